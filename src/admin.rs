@@ -0,0 +1,314 @@
+//! Administrative commands that operate on the configured storage.
+//!
+//! These are used by the `sekursranko` binary and do not require a running
+//! server. They honor the same configuration as the server (e.g. encryption
+//! and log privacy).
+
+use std::{
+    future::Future,
+    io::{Read, Write},
+    time::SystemTime,
+};
+
+use anyhow::{bail, Context};
+use log::{info, warn};
+
+use crate::{
+    archive,
+    config::ServerConfig,
+    events::{BackupEvent, EventKind, Source},
+    handlers::backup_id_valid,
+    replication, retention,
+    safe_backup::SafeKeys,
+    state::State,
+};
+
+pub use crate::{
+    archive::{ExportStats, ImportStats},
+    replication::ResyncStats,
+    retention::SweepStats,
+    safe_backup::BackupSummary,
+    storage::BackupVersion,
+};
+
+/// Run a command that emits events, and deliver its webhook events before
+/// returning.
+async fn with_webhooks<T>(state: &State, command: impl Future<Output = T>) -> T {
+    let workers = state
+        .webhooks
+        .as_ref()
+        .map(|webhooks| webhooks.spawn(state.metrics.clone()));
+    let result = command.await;
+    if let (Some(webhooks), Some(workers)) = (&state.webhooks, workers) {
+        webhooks.flush(workers).await;
+    }
+    result
+}
+
+/// Push all local backups to the specified replication peer.
+pub async fn resync(config: &ServerConfig, peer: &str) -> anyhow::Result<ResyncStats> {
+    let replication = match config.replication {
+        Some(ref replication) => replication,
+        None => bail!("Replication is not configured"),
+    };
+    let state = State::new(config.clone())?;
+    let storage = &state.storage;
+    replication::resync(replication, storage, &state.log_privacy, peer).await
+}
+
+/// Statistics of a re-encryption run.
+#[derive(Debug, Default)]
+pub struct ReencryptStats {
+    pub reencrypted: usize,
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+/// Re-encrypt all backups with the active key.
+///
+/// Backups that are not encrypted yet (because they were stored before
+/// encryption was enabled) are encrypted as well.
+pub async fn reencrypt(config: &ServerConfig) -> anyhow::Result<ReencryptStats> {
+    if config.encryption.is_none() {
+        bail!("Encryption is not configured");
+    }
+    let state = State::new(config.clone())?;
+    let storage = &state.storage;
+    let mut stats = ReencryptStats::default();
+    for backup_id in storage.backup_ids()? {
+        match storage.reencrypt(&backup_id).await {
+            Ok(true) => {
+                info!("Re-encrypted backup {}", state.log_privacy.id(&backup_id));
+                stats.reencrypted += 1;
+            }
+            Ok(false) => stats.skipped += 1,
+            Err(e) => {
+                warn!(
+                    "Could not re-encrypt backup {}: {:#}",
+                    state.log_privacy.id(&backup_id),
+                    e
+                );
+                stats.failed += 1;
+            }
+        }
+    }
+    Ok(stats)
+}
+
+fn require_valid_id(backup_id: &str) -> anyhow::Result<()> {
+    if !backup_id_valid(backup_id) {
+        bail!("Invalid backup ID: {}", backup_id);
+    }
+    Ok(())
+}
+
+/// List the retained prior versions of a backup, newest first.
+pub async fn list_versions(
+    config: &ServerConfig,
+    backup_id: &str,
+) -> anyhow::Result<Vec<BackupVersion>> {
+    require_valid_id(backup_id)?;
+    let state = State::new(config.clone())?;
+    let storage = &state.storage;
+    Ok(storage.versions(backup_id).await?)
+}
+
+/// Return the (decrypted) contents of a prior version of a backup.
+pub async fn download_version(
+    config: &ServerConfig,
+    backup_id: &str,
+    version: &str,
+) -> anyhow::Result<Vec<u8>> {
+    require_valid_id(backup_id)?;
+    let state = State::new(config.clone())?;
+    let storage = &state.storage;
+    storage
+        .read_version(backup_id, version)
+        .await
+        .with_context(|| format!("Could not read version {} of backup {}", version, backup_id))
+}
+
+/// Make a prior version the current version of a backup.
+///
+/// The replaced version is retained as a prior version. The change is
+/// queued for replication like an upload.
+pub async fn promote_version(
+    config: &ServerConfig,
+    backup_id: &str,
+    version: &str,
+) -> anyhow::Result<()> {
+    require_valid_id(backup_id)?;
+    let state = State::new(config.clone())?;
+    state.storage.promote_version(backup_id, version).await?;
+    let size = state.storage.size(backup_id).await.ok();
+    let event = BackupEvent::new(EventKind::Updated, Source::Admin, backup_id, size);
+    with_webhooks(&state, state.emit(&event)).await;
+    Ok(())
+}
+
+/// Restore the most recently deleted copy of a backup from the trash.
+///
+/// The restored backup is queued for replication like an upload.
+pub async fn restore(config: &ServerConfig, backup_id: &str) -> anyhow::Result<()> {
+    require_valid_id(backup_id)?;
+    let state = State::new(config.clone())?;
+    state.storage.restore(backup_id).await?;
+    let size = state.storage.size(backup_id).await.ok();
+    let event = BackupEvent::new(EventKind::Created, Source::Admin, backup_id, size);
+    with_webhooks(&state, state.emit(&event)).await;
+    Ok(())
+}
+
+/// A backup with its owner.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupOwner {
+    pub backup_id: String,
+    /// The owner (or `None` if the backup is not owned by any user)
+    pub owner: Option<String>,
+    /// The (decrypted) size in bytes
+    pub size: u64,
+    /// The time of the last upload
+    pub stored_at: SystemTime,
+    /// The time the backup expires (or `None` if backups never expire)
+    pub expires_at: Option<SystemTime>,
+}
+
+/// List the backups with their owners.
+///
+/// If a user is specified, only the backups owned by this user are listed.
+pub async fn list_owners(
+    config: &ServerConfig,
+    user: Option<&str>,
+) -> anyhow::Result<Vec<BackupOwner>> {
+    let state = State::new(config.clone())?;
+    let storage = &state.storage;
+    let mut backups = vec![];
+    let mut backup_ids = storage.backup_ids()?;
+    backup_ids.sort();
+    for backup_id in backup_ids {
+        let owner = storage.owner(&backup_id).await?;
+        if user.is_some() && owner.as_deref() != user {
+            continue;
+        }
+        let size = match storage.size(&backup_id).await {
+            Ok(size) => size,
+            // Deleted in the meantime
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let stored_at = storage.stored_at(&backup_id).await?;
+        backups.push(BackupOwner {
+            backup_id,
+            owner,
+            size,
+            stored_at,
+            expires_at: retention::expires_at(stored_at, config.retention_days),
+        });
+    }
+    Ok(backups)
+}
+
+/// Assign a backup to a user (or remove its owner if `user` is `None`).
+///
+/// The user must exist in the htpasswd file. Quotas are not checked.
+pub async fn assign_owner(
+    config: &ServerConfig,
+    backup_id: &str,
+    user: Option<&str>,
+) -> anyhow::Result<()> {
+    require_valid_id(backup_id)?;
+    let state = State::new(config.clone())?;
+    let auth = match state.auth {
+        Some(ref auth) => auth,
+        None => bail!("Authentication is not configured"),
+    };
+    if let Some(user) = user {
+        if !auth.has_user(user) {
+            bail!("Unknown user: {}", user);
+        }
+    }
+    if !state.storage.path(backup_id).is_file() {
+        bail!("Backup {} does not exist", backup_id);
+    }
+    state.storage.set_owner(backup_id, user).await?;
+    Ok(())
+}
+
+/// A backup located and decrypted with the user's Safe credentials.
+#[derive(Debug, Clone)]
+pub struct Inspection {
+    pub backup_id: String,
+    /// The size of the backup as uploaded by the client
+    pub size: u64,
+    pub stored_at: SystemTime,
+    /// The time the backup expires (or `None` if backups never expire)
+    pub expires_at: Option<SystemTime>,
+    pub summary: BackupSummary,
+}
+
+/// Derive the backup ID from a Threema ID and Safe password, then locate,
+/// decrypt and summarize the backup.
+///
+/// Everything happens locally; the password and the decrypted contents never
+/// leave this process.
+pub async fn inspect(
+    config: &ServerConfig,
+    identity: &str,
+    password: &str,
+) -> anyhow::Result<Inspection> {
+    let state = State::new(config.clone())?;
+    let (identity, password) = (identity.to_string(), password.to_string());
+    let keys = tokio::task::spawn_blocking(move || SafeKeys::derive(&identity, &password))
+        .await
+        .context("Key derivation panicked")??;
+    let backup_id = keys.backup_id.clone();
+    let storage = &state.storage;
+    let data = match storage.read(&backup_id).await {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            bail!("Backup {} does not exist", backup_id)
+        }
+        Err(e) => return Err(e).context(format!("Could not read backup {}", backup_id)),
+    };
+    let stored_at = storage.stored_at(&backup_id).await?;
+    let json = keys
+        .decrypt(&data)
+        .with_context(|| format!("Backup {} is invalid", backup_id))?;
+    let summary = BackupSummary::from_json(&json)
+        .with_context(|| format!("Backup {} is invalid", backup_id))?;
+    Ok(Inspection {
+        backup_id,
+        size: data.len() as u64,
+        stored_at,
+        expires_at: retention::expires_at(stored_at, config.retention_days),
+        summary,
+    })
+}
+
+/// Delete expired backups, prune prior versions and purge the trash.
+pub async fn sweep(config: &ServerConfig) -> anyhow::Result<SweepStats> {
+    let state = State::new(config.clone())?;
+    Ok(with_webhooks(&state, retention::sweep(&state)).await?)
+}
+
+/// Write all current backups (with their metadata) to an archive.
+///
+/// Backups are decrypted, so the archive must be protected accordingly.
+pub async fn export<W: Write>(config: &ServerConfig, writer: W) -> anyhow::Result<ExportStats> {
+    let state = State::new(config.clone())?;
+    archive::export(&state.storage, writer).await
+}
+
+/// Restore the backups from an archive created by `export`.
+///
+/// Checksums are verified and the time each backup was stored is preserved.
+/// Existing backups are skipped unless `overwrite` is set. Imported backups
+/// are queued for replication like uploads.
+pub async fn import<R: Read>(
+    config: &ServerConfig,
+    reader: R,
+    overwrite: bool,
+) -> anyhow::Result<ImportStats> {
+    let state = State::new(config.clone())?;
+    with_webhooks(&state, archive::import(&state, reader, overwrite)).await
+}
@@ -0,0 +1,397 @@
+//! Export and import of all backups as a portable archive.
+//!
+//! The archive is a tar file. The first entry `sekursranko-export.json`
+//! describes the archive format. It is followed by two entries per backup:
+//! `backups/<backup_id>.json` with the metadata of the backup (size, SHA-256
+//! checksum and the time it was stored) and `backups/<backup_id>` with its
+//! (decrypted) contents.
+//!
+//! Backups are stored in plaintext in the archive, so that they can be
+//! imported into a storage with a different encryption key. Only current
+//! backups are exported, not prior versions or deleted backups.
+
+use std::{
+    io::{self, Read, Write},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context};
+use log::{debug, warn};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    events::{BackupEvent, EventKind, Source},
+    handlers::backup_id_valid,
+    state::State,
+    storage::{unix_millis, Storage},
+};
+
+/// The path of the archive description inside the archive.
+const MANIFEST_PATH: &str = "sekursranko-export.json";
+
+/// The directory inside the archive that contains the backups.
+const BACKUPS_DIR: &str = "backups/";
+
+/// The current archive format.
+const FORMAT: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    /// The archive format
+    format: u32,
+    /// The version of the exporting server
+    version: String,
+    /// The time of the export (in milliseconds since the Unix epoch)
+    exported_at: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BackupMetadata {
+    backup_id: String,
+    /// The (decrypted) size in bytes
+    size: u64,
+    /// The hex encoded SHA-256 checksum of the (decrypted) contents
+    sha256: String,
+    /// The time the backup was stored (in milliseconds since the Unix epoch)
+    stored_at: u64,
+    /// The user owning the backup (if authentication is enabled)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    owner: Option<String>,
+}
+
+/// Statistics of an export.
+#[derive(Debug, Default)]
+pub struct ExportStats {
+    pub exported: usize,
+}
+
+/// Statistics of an import.
+#[derive(Debug, Default)]
+pub struct ImportStats {
+    pub imported: usize,
+    /// Backups that already existed (and were not overwritten)
+    pub skipped: usize,
+    /// Backups that were invalid or could not be stored
+    pub failed: usize,
+}
+
+fn append_file<W: Write>(
+    builder: &mut tar::Builder<W>,
+    path: &str,
+    data: &[u8],
+    mtime: SystemTime,
+) -> io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o600);
+    header.set_mtime(unix_millis(mtime) / 1000);
+    header.set_entry_type(tar::EntryType::Regular);
+    builder.append_data(&mut header, path, data)
+}
+
+/// Write all backups to an archive.
+pub async fn export<W: Write>(storage: &Storage, writer: W) -> anyhow::Result<ExportStats> {
+    let mut builder = tar::Builder::new(writer);
+    let mut stats = ExportStats::default();
+
+    let now = SystemTime::now();
+    let manifest = Manifest {
+        format: FORMAT,
+        version: crate::VERSION.to_string(),
+        exported_at: unix_millis(now),
+    };
+    append_file(
+        &mut builder,
+        MANIFEST_PATH,
+        &serde_json::to_vec(&manifest)?,
+        now,
+    )?;
+
+    for backup_id in storage.backup_ids()? {
+        let (stored_at, data) = match storage.stored_at(&backup_id).await {
+            Ok(stored_at) => (stored_at, storage.read(&backup_id).await),
+            Err(e) => (UNIX_EPOCH, Err(e)),
+        };
+        let data = match data {
+            Ok(data) => data,
+            // Deleted during the export
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!(
+                        "Could not read backup {}",
+                        storage.log_privacy().id(&backup_id)
+                    )
+                })
+            }
+        };
+        let metadata = BackupMetadata {
+            backup_id: backup_id.clone(),
+            size: data.len() as u64,
+            sha256: hex::encode(Sha256::digest(&data)),
+            stored_at: unix_millis(stored_at),
+            owner: storage.owner(&backup_id).await?,
+        };
+        let path = format!("{}{}", BACKUPS_DIR, backup_id);
+        append_file(
+            &mut builder,
+            &format!("{}.json", path),
+            &serde_json::to_vec(&metadata)?,
+            stored_at,
+        )?;
+        append_file(&mut builder, &path, &data, stored_at)?;
+        debug!("Exported backup {}", storage.log_privacy().id(&backup_id));
+        stats.exported += 1;
+    }
+
+    builder.into_inner()?.flush()?;
+    Ok(stats)
+}
+
+/// Restore all backups from an archive.
+///
+/// Existing backups are only replaced if `overwrite` is set. Backups with
+/// an invalid size or checksum are not imported. The time each backup was
+/// stored is preserved, so that it expires at the same time as before.
+pub async fn import<R: Read>(
+    state: &State,
+    reader: R,
+    overwrite: bool,
+) -> anyhow::Result<ImportStats> {
+    let storage = &state.storage;
+    let mut archive = tar::Archive::new(reader);
+    let mut entries = archive.entries()?;
+    let mut stats = ImportStats::default();
+
+    // Check the archive format
+    let mut manifest = match entries.next() {
+        Some(entry) => entry?,
+        None => bail!("The archive is empty"),
+    };
+    if manifest.path_bytes().as_ref() != MANIFEST_PATH.as_bytes() {
+        bail!("Not a backup archive (missing {})", MANIFEST_PATH);
+    }
+    let manifest: Manifest = serde_json::from_reader(&mut manifest)
+        .with_context(|| format!("Invalid {}", MANIFEST_PATH))?;
+    if manifest.format != FORMAT {
+        bail!("Unsupported archive format: {}", manifest.format);
+    }
+
+    let mut pending: Option<BackupMetadata> = None;
+    for entry in entries {
+        let mut entry = entry?;
+        let path = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+        let name = match path.strip_prefix(BACKUPS_DIR) {
+            Some(name) => name,
+            None => bail!("Unexpected archive entry: {}", path),
+        };
+
+        if let Some(backup_id) = name.strip_suffix(".json") {
+            if let Some(previous) = pending.take() {
+                warn!(
+                    "Missing contents of backup {}",
+                    state.log_privacy.id(&previous.backup_id)
+                );
+                stats.failed += 1;
+            }
+            let parsed: BackupMetadata = serde_json::from_reader(&mut entry)
+                .with_context(|| format!("Invalid archive entry {}", path))?;
+            if parsed.backup_id != backup_id || !backup_id_valid(backup_id) {
+                bail!("Invalid archive entry {}", path);
+            }
+            pending = Some(parsed);
+            continue;
+        }
+
+        let metadata = match pending.take() {
+            Some(metadata) if metadata.backup_id == name => metadata,
+            _ => bail!("Missing metadata for archive entry {}", path),
+        };
+        let backup_id = metadata.backup_id.as_str();
+        if entry.header().size()? != metadata.size || metadata.size > state.config.max_backup_bytes
+        {
+            warn!("Invalid size of backup {}", state.log_privacy.id(backup_id));
+            stats.failed += 1;
+            continue;
+        }
+        let mut data = Vec::with_capacity(metadata.size as usize);
+        entry.read_to_end(&mut data)?;
+        if hex::encode(Sha256::digest(&data)) != metadata.sha256 {
+            warn!(
+                "Checksum mismatch for backup {}",
+                state.log_privacy.id(backup_id)
+            );
+            stats.failed += 1;
+            continue;
+        }
+
+        let exists = storage.path(backup_id).exists();
+        if exists && !overwrite {
+            debug!(
+                "Skipping existing backup {}",
+                state.log_privacy.id(backup_id)
+            );
+            stats.skipped += 1;
+            continue;
+        }
+        let stored_at = UNIX_EPOCH + Duration::from_millis(metadata.stored_at);
+        let result = match storage.write_data(backup_id, &data).await {
+            Ok(updated) => {
+                async {
+                    storage.set_stored_at(backup_id, stored_at).await?;
+                    storage
+                        .set_owner(backup_id, metadata.owner.as_deref())
+                        .await?;
+                    Ok(updated)
+                }
+                .await
+            }
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(updated) => {
+                let kind = if updated {
+                    EventKind::Updated
+                } else {
+                    EventKind::Created
+                };
+                state
+                    .emit(&BackupEvent::new(
+                        kind,
+                        Source::Admin,
+                        backup_id,
+                        Some(metadata.size),
+                    ))
+                    .await;
+                debug!("Imported backup {}", state.log_privacy.id(backup_id));
+                stats.imported += 1;
+            }
+            Err(e) => {
+                warn!(
+                    "Could not import backup {}: {:#}",
+                    state.log_privacy.id(backup_id),
+                    e
+                );
+                stats.failed += 1;
+            }
+        }
+    }
+    if let Some(previous) = pending {
+        warn!(
+            "Missing contents of backup {}",
+            state.log_privacy.id(&previous.backup_id)
+        );
+        stats.failed += 1;
+    }
+
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::ServerConfig;
+
+    const BACKUP_ID: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+    const OTHER_ID: &str = "fedcba9876543210fedcba9876543210fedcba9876543210fedcba9876543210";
+
+    fn state(dir: &tempfile::TempDir) -> State {
+        State::new(ServerConfig {
+            backup_dir: dir.path().to_path_buf(),
+            max_backup_bytes: 10_000,
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn export_import() {
+        let source_dir = tempfile::tempdir().unwrap();
+        let source = state(&source_dir);
+        source
+            .storage
+            .write_data(BACKUP_ID, b"first")
+            .await
+            .unwrap();
+        source
+            .storage
+            .write_data(OTHER_ID, b"second")
+            .await
+            .unwrap();
+        let stored_at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        source
+            .storage
+            .set_stored_at(BACKUP_ID, stored_at)
+            .await
+            .unwrap();
+        source
+            .storage
+            .set_owner(BACKUP_ID, Some("alice"))
+            .await
+            .unwrap();
+
+        let mut archive = Vec::new();
+        let stats = export(&source.storage, &mut archive).await.unwrap();
+        assert_eq!(stats.exported, 2);
+
+        let target_dir = tempfile::tempdir().unwrap();
+        let target = state(&target_dir);
+        target.storage.write_data(OTHER_ID, b"newer").await.unwrap();
+        let stats = import(&target, &archive[..], false).await.unwrap();
+        assert_eq!(stats.imported, 1);
+        assert_eq!(stats.skipped, 1);
+        assert_eq!(stats.failed, 0);
+        assert_eq!(target.storage.read(BACKUP_ID).await.unwrap(), b"first");
+        assert_eq!(target.storage.read(OTHER_ID).await.unwrap(), b"newer");
+        assert_eq!(
+            target.storage.stored_at(BACKUP_ID).await.unwrap(),
+            stored_at
+        );
+        assert_eq!(
+            target.storage.owner(BACKUP_ID).await.unwrap().as_deref(),
+            Some("alice")
+        );
+
+        let stats = import(&target, &archive[..], true).await.unwrap();
+        assert_eq!(stats.imported, 2);
+        assert_eq!(target.storage.read(OTHER_ID).await.unwrap(), b"second");
+    }
+
+    #[tokio::test]
+    async fn import_corrupted() {
+        let source_dir = tempfile::tempdir().unwrap();
+        let source = state(&source_dir);
+        source
+            .storage
+            .write_data(BACKUP_ID, b"contents")
+            .await
+            .unwrap();
+        let mut archive = Vec::new();
+        export(&source.storage, &mut archive).await.unwrap();
+
+        // Flip a byte of the backup contents
+        let pos = archive
+            .windows(8)
+            .position(|window| window == b"contents")
+            .unwrap();
+        archive[pos] = b'C';
+
+        let target_dir = tempfile::tempdir().unwrap();
+        let target = state(&target_dir);
+        let stats = import(&target, &archive[..], false).await.unwrap();
+        assert_eq!(stats.imported, 0);
+        assert_eq!(stats.failed, 1);
+        assert!(!target.storage.path(BACKUP_ID).exists());
+    }
+
+    #[tokio::test]
+    async fn import_invalid_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut builder = tar::Builder::new(Vec::new());
+        append_file(&mut builder, "other.txt", b"hello", SystemTime::now()).unwrap();
+        let archive = builder.into_inner().unwrap();
+        assert!(import(&state(&dir), &archive[..], false).await.is_err());
+    }
+}
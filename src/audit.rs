@@ -0,0 +1,267 @@
+//! Structured audit log of backup lifecycle events.
+//!
+//! Every event is appended to the audit log as one JSON object per line,
+//! e.g.
+//!
+//! ```text
+//! {"timestamp":"2025-04-01T12:00:00.000Z","event":"created","backup_id":"0123…","size":1024,"source":"api"}
+//! ```
+//!
+//! If a hash key is configured, the backup ID is replaced by its keyed hash
+//! (`backup_id_hash`). Request bodies are never logged.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use log::warn;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{events::BackupEvent, privacy::IdHasher};
+
+/// The audit log configuration.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct AuditLogConfig {
+    /// Path to the audit log file
+    pub path: PathBuf,
+    /// Path to a file containing the key for hashing backup IDs. If not set,
+    /// raw backup IDs are logged.
+    pub hash_key_file: Option<PathBuf>,
+    /// Rotate the log once it exceeds this size in bytes (default: no
+    /// rotation)
+    pub max_bytes: Option<u64>,
+    /// The number of rotated files to keep (default: 5)
+    pub max_files: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    timestamp: String,
+    event: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    backup_id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    backup_id_hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    source: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<&'static str>,
+}
+
+/// An append-only audit log with size based rotation.
+#[derive(Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+    hasher: Option<IdHasher>,
+    max_bytes: Option<u64>,
+    max_files: u32,
+    file: Mutex<Option<File>>,
+}
+
+impl AuditLog {
+    pub fn new(config: &AuditLogConfig) -> anyhow::Result<Self> {
+        let hasher = match config.hash_key_file {
+            Some(ref key_file) => Some(IdHasher::load(key_file)?),
+            None => None,
+        };
+        Ok(Self {
+            path: config.path.clone(),
+            hasher,
+            max_bytes: config.max_bytes,
+            max_files: config.max_files.unwrap_or(5),
+            file: Mutex::new(None),
+        })
+    }
+
+    /// Append an event to the audit log.
+    ///
+    /// The file is written on the blocking thread pool, so that the runtime
+    /// isn't blocked by the file system or by other writers.
+    pub async fn record(self: &Arc<Self>, event: &BackupEvent) {
+        let (backup_id, backup_id_hash) = match self.hasher {
+            Some(ref hasher) => (None, Some(hasher.hash(&event.backup_id))),
+            None => (Some(event.backup_id.as_str()), None),
+        };
+        let entry = Entry {
+            timestamp: humantime::format_rfc3339_millis(event.timestamp).to_string(),
+            event: event.kind.to_string(),
+            backup_id,
+            backup_id_hash,
+            size: event.size,
+            source: event.source.to_string(),
+            reason: event.reason,
+        };
+        let mut line = serde_json::to_string(&entry).expect("Could not serialize audit entry");
+        line.push('\n');
+        let log = self.clone();
+        let res = tokio::task::spawn_blocking(move || log.append(line.as_bytes()))
+            .await
+            .unwrap_or_else(|e| Err(io::Error::other(e)));
+        if let Err(e) = res {
+            warn!("Could not write to audit log {:?}: {}", self.path, e);
+        }
+    }
+
+    fn append(&self, line: &[u8]) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(max_bytes) = self.max_bytes {
+            let len = match *file {
+                Some(ref f) => f.metadata()?.len(),
+                None => fs::metadata(&self.path).map_or(0, |m| m.len()),
+            };
+            if len > 0 && len + line.len() as u64 > max_bytes {
+                *file = None;
+                rotate(&self.path, self.max_files)?;
+            }
+        }
+        if file.is_none() {
+            *file = Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .mode(0o600)
+                    .open(&self.path)?,
+            );
+        }
+        file.as_mut().expect("Audit log not open").write_all(line)
+    }
+}
+
+fn rotated_path(path: &Path, index: u32) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+/// Rename `log` to `log.1`, `log.1` to `log.2` etc., removing the oldest file.
+fn rotate(path: &Path, max_files: u32) -> io::Result<()> {
+    if max_files == 0 {
+        return fs::remove_file(path);
+    }
+    for index in (1..max_files).rev() {
+        let from = rotated_path(path, index);
+        if from.exists() {
+            fs::rename(&from, rotated_path(path, index + 1))?;
+        }
+    }
+    fs::rename(path, rotated_path(path, 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::events::{EventKind, Source};
+
+    const BACKUP_ID: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    fn read_lines(path: &Path) -> Vec<serde_json::Value> {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn record_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let log = Arc::new(
+            AuditLog::new(&AuditLogConfig {
+                path: path.clone(),
+                hash_key_file: None,
+                max_bytes: None,
+                max_files: None,
+            })
+            .unwrap(),
+        );
+        log.record(&BackupEvent::new(
+            EventKind::Created,
+            Source::Api,
+            BACKUP_ID,
+            Some(42),
+        ))
+        .await;
+        log.record(
+            &BackupEvent::new(EventKind::Rejected, Source::Api, BACKUP_ID, None)
+                .with_reason("backup_too_large"),
+        )
+        .await;
+        let lines = read_lines(&path);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["event"], "created");
+        assert_eq!(lines[0]["backup_id"], BACKUP_ID);
+        assert_eq!(lines[0]["size"], 42);
+        assert_eq!(lines[0]["source"], "api");
+        assert!(lines[0]["timestamp"].as_str().unwrap().ends_with('Z'));
+        assert_eq!(lines[1]["event"], "rejected");
+        assert_eq!(lines[1]["reason"], "backup_too_large");
+        assert!(lines[1].get("size").is_none());
+    }
+
+    #[tokio::test]
+    async fn hash_backup_ids() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let key_file = dir.path().join("key");
+        fs::write(&key_file, "a very secret hash key\n").unwrap();
+        let log = Arc::new(
+            AuditLog::new(&AuditLogConfig {
+                path: path.clone(),
+                hash_key_file: Some(key_file),
+                max_bytes: None,
+                max_files: None,
+            })
+            .unwrap(),
+        );
+        log.record(&BackupEvent::new(
+            EventKind::Deleted,
+            Source::Api,
+            BACKUP_ID,
+            None,
+        ))
+        .await;
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(!contents.contains(BACKUP_ID));
+        let lines = read_lines(&path);
+        assert!(lines[0].get("backup_id").is_none());
+        assert_eq!(
+            lines[0]["backup_id_hash"],
+            IdHasher::new(b"a very secret hash key").hash(BACKUP_ID)
+        );
+    }
+
+    #[tokio::test]
+    async fn rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let log = Arc::new(
+            AuditLog::new(&AuditLogConfig {
+                path: path.clone(),
+                hash_key_file: None,
+                max_bytes: Some(200),
+                max_files: Some(2),
+            })
+            .unwrap(),
+        );
+        for _ in 0..10 {
+            log.record(&BackupEvent::new(
+                EventKind::Updated,
+                Source::Api,
+                BACKUP_ID,
+                Some(1),
+            ))
+            .await;
+        }
+        assert_eq!(read_lines(&path).len(), 1);
+        assert_eq!(read_lines(&rotated_path(&path, 1)).len(), 1);
+        assert_eq!(read_lines(&rotated_path(&path, 2)).len(), 1);
+        assert!(!rotated_path(&path, 3).exists());
+    }
+}
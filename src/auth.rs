@@ -0,0 +1,371 @@
+//! HTTP Basic authentication for the Safe API.
+//!
+//! Users are read from an htpasswd-style file with one `user:hash` line per
+//! user. Only bcrypt (`$2y$…`, e.g. from `htpasswd -B`) and argon2
+//! (`$argon2id$…`) hashes are accepted. The file is reloaded when it changes.
+//!
+//! Backups are owned by the user that created them. Other users cannot see,
+//! replace or delete them, and every user has a quota of backups and bytes.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, Context};
+use argon2::{password_hash::PasswordHash, Argon2, PasswordVerifier};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hyper::{header, http::HeaderValue, Body, Request};
+use log::{info, warn};
+use serde_derive::Deserialize;
+use tokio::task::JoinHandle;
+
+/// The Basic authentication configuration.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct AuthConfig {
+    /// Path to the htpasswd file with bcrypt or argon2 hashes
+    pub htpasswd_file: PathBuf,
+    /// The realm sent to clients (default: "Threema Safe")
+    pub realm: Option<String>,
+    /// The interval between checks for changes of the htpasswd file in
+    /// seconds (default: 30)
+    pub reload_interval_secs: Option<u64>,
+    /// The quota of every user
+    #[serde(default)]
+    pub quota: QuotaConfig,
+    /// Quotas of individual users (overriding `quota`)
+    #[serde(default)]
+    pub user_quotas: HashMap<String, QuotaConfig>,
+}
+
+impl AuthConfig {
+    /// Return the quota of a user.
+    pub fn quota_for(&self, user: &str) -> QuotaConfig {
+        self.quota.for_user(&self.user_quotas, user)
+    }
+}
+
+/// The storage quota of a user.
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+pub struct QuotaConfig {
+    /// The maximum number of backups (unlimited if not set)
+    pub max_backups: Option<u64>,
+    /// The maximum total size of all backups in bytes (unlimited if not set)
+    pub max_bytes: Option<u64>,
+}
+
+impl QuotaConfig {
+    /// Return the quota of a user, with the limits of `user_quotas`
+    /// overriding this default quota.
+    pub fn for_user(&self, user_quotas: &HashMap<String, QuotaConfig>, user: &str) -> Self {
+        match user_quotas.get(user) {
+            Some(quota) => QuotaConfig {
+                max_backups: quota.max_backups.or(self.max_backups),
+                max_bytes: quota.max_bytes.or(self.max_bytes),
+            },
+            None => self.clone(),
+        }
+    }
+
+    /// Return whether the usage is within the quota.
+    pub fn allows(&self, backups: u64, bytes: u64) -> bool {
+        self.max_backups.map_or(true, |max| backups <= max)
+            && self.max_bytes.map_or(true, |max| bytes <= max)
+    }
+}
+
+/// A password hash from the htpasswd file.
+#[derive(Debug, Clone, PartialEq)]
+enum Hash {
+    Bcrypt(String),
+    Argon2(String),
+}
+
+impl Hash {
+    fn parse(hash: &str) -> anyhow::Result<Self> {
+        if ["$2a$", "$2b$", "$2x$", "$2y$"]
+            .iter()
+            .any(|prefix| hash.starts_with(prefix))
+        {
+            Ok(Hash::Bcrypt(hash.to_string()))
+        } else if hash.starts_with("$argon2") {
+            PasswordHash::new(hash).map_err(|e| anyhow::anyhow!("Invalid argon2 hash: {}", e))?;
+            Ok(Hash::Argon2(hash.to_string()))
+        } else {
+            bail!("Unsupported hash (only bcrypt and argon2 are supported)")
+        }
+    }
+
+    /// Verify a password. The hashes are compared in constant time.
+    fn verify(&self, password: &str) -> bool {
+        match self {
+            Hash::Bcrypt(hash) => bcrypt::verify(password, hash).unwrap_or(false),
+            Hash::Argon2(hash) => PasswordHash::new(hash).is_ok_and(|hash| {
+                Argon2::default()
+                    .verify_password(password.as_bytes(), &hash)
+                    .is_ok()
+            }),
+        }
+    }
+}
+
+/// The users of an htpasswd file.
+#[derive(Debug, Default)]
+struct Users {
+    hashes: HashMap<String, Hash>,
+    /// The modification time and size of the file when it was read
+    version: Option<(SystemTime, u64)>,
+}
+
+impl Users {
+    fn load(path: &Path) -> anyhow::Result<Self> {
+        let version = file_version(path)?;
+        let contents =
+            fs::read_to_string(path).with_context(|| format!("Could not read {:?}", path))?;
+        let mut hashes = HashMap::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (user, hash) = line
+                .split_once(':')
+                .with_context(|| format!("Invalid entry in {:?}, line {}", path, number + 1))?;
+            let hash = Hash::parse(hash)
+                .with_context(|| format!("Invalid hash of user {} in {:?}", user, path))?;
+            if hashes.insert(user.to_string(), hash).is_some() {
+                bail!("Duplicate user {} in {:?}", user, path);
+            }
+        }
+        Ok(Self {
+            hashes,
+            version: Some(version),
+        })
+    }
+}
+
+fn file_version(path: &Path) -> anyhow::Result<(SystemTime, u64)> {
+    let metadata = fs::metadata(path).with_context(|| format!("Could not read {:?}", path))?;
+    Ok((metadata.modified()?, metadata.len()))
+}
+
+/// Checks the credentials of requests.
+#[derive(Debug)]
+pub struct Authenticator {
+    config: AuthConfig,
+    challenge: HeaderValue,
+    users: RwLock<Arc<Users>>,
+}
+
+impl Authenticator {
+    pub fn new(config: &AuthConfig) -> anyhow::Result<Self> {
+        let realm = config.realm.as_deref().unwrap_or("Threema Safe");
+        if realm.contains(|c: char| c == '"' || c == '\\' || c.is_control()) {
+            bail!("Invalid auth realm: {:?}", realm);
+        }
+        let challenge =
+            HeaderValue::from_str(&format!("Basic realm=\"{}\", charset=\"UTF-8\"", realm))
+                .context("Invalid auth realm")?;
+        Ok(Self {
+            config: config.clone(),
+            challenge,
+            users: RwLock::new(Arc::new(Users::load(&config.htpasswd_file)?)),
+        })
+    }
+
+    /// The `WWW-Authenticate` header value sent with 401 responses.
+    pub fn challenge(&self) -> HeaderValue {
+        self.challenge.clone()
+    }
+
+    /// Return whether the user exists.
+    pub fn has_user(&self, user: &str) -> bool {
+        self.users().hashes.contains_key(user)
+    }
+
+    fn users(&self) -> Arc<Users> {
+        self.users.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Reload the htpasswd file if it has changed.
+    ///
+    /// If the file cannot be read, the previous users are kept.
+    pub fn reload(&self) -> anyhow::Result<bool> {
+        let path = &self.config.htpasswd_file;
+        if self.users().version == Some(file_version(path)?) {
+            return Ok(false);
+        }
+        let users = Users::load(path)?;
+        info!("Reloaded {} users from {:?}", users.hashes.len(), path);
+        *self.users.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(users);
+        Ok(true)
+    }
+
+    /// Return the user if the request has valid credentials.
+    ///
+    /// Password hashing is slow by design, so this runs on the blocking
+    /// thread pool.
+    pub async fn authenticate(&self, req: &Request<Body>) -> Option<String> {
+        let (user, password) = credentials(req)?;
+        let users = self.users();
+        tokio::task::spawn_blocking(move || {
+            match users.hashes.get(&user) {
+                Some(hash) if hash.verify(&password) => Some(user),
+                Some(_) => None,
+                None => {
+                    // Take as long as for a known user
+                    if let Some(hash) = users.hashes.values().next() {
+                        hash.verify(&password);
+                    }
+                    None
+                }
+            }
+        })
+        .await
+        .unwrap_or(None)
+    }
+
+    /// Check for changes of the htpasswd file periodically.
+    pub fn spawn(self: &Arc<Self>) -> JoinHandle<()> {
+        let authenticator = self.clone();
+        let interval = Duration::from_secs(self.config.reload_interval_secs.unwrap_or(30).max(1));
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let authenticator = authenticator.clone();
+                match tokio::task::spawn_blocking(move || authenticator.reload()).await {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) => warn!("Could not reload users: {:#}", e),
+                    Err(e) => warn!("Could not reload users: {}", e),
+                }
+            }
+        })
+    }
+}
+
+/// Return the user name and password of the `Authorization` header.
+fn credentials(req: &Request<Body>) -> Option<(String, String)> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, encoded) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(STANDARD.decode(encoded.trim()).ok()?).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The password "secret" (with low costs)
+    const BCRYPT: &str = "$2b$04$okK9S1/hF7QJO1ReoRi4puM/bvcq882iNaLpVZk5hzl9GYT/w.HgW";
+    const ARGON2: &str =
+        "$argon2id$v=19$m=16,t=2,p=1$c2FsdHNhbHQ$865AUeURr6n8HtD+KV9954Buy8pBRcZagkPkr9xKlao";
+
+    fn request(authorization: Option<&str>) -> Request<Body> {
+        let mut builder = Request::get("/config");
+        if let Some(authorization) = authorization {
+            builder = builder.header(header::AUTHORIZATION, authorization);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    fn basic(user: &str, password: &str) -> String {
+        format!(
+            "Basic {}",
+            STANDARD.encode(format!("{}:{}", user, password))
+        )
+    }
+
+    #[test]
+    fn parse_credentials() {
+        assert_eq!(
+            credentials(&request(Some(&basic("alice", "pass:word")))),
+            Some(("alice".into(), "pass:word".into()))
+        );
+        assert_eq!(credentials(&request(Some("Bearer abc"))), None);
+        assert_eq!(credentials(&request(Some("Basic !!!"))), None);
+        assert_eq!(credentials(&request(None)), None);
+    }
+
+    #[test]
+    fn user_quotas() {
+        let config: AuthConfig = toml::from_str(
+            r#"
+            htpasswd_file = "/etc/htpasswd"
+            quota = { max_backups = 2, max_bytes = 1000 }
+            user_quotas = { alice = { max_bytes = 5000 } }
+            "#,
+        )
+        .unwrap();
+        let quota = config.quota_for("alice");
+        assert_eq!(quota.max_backups, Some(2));
+        assert_eq!(quota.max_bytes, Some(5000));
+        assert!(quota.allows(2, 5000));
+        assert!(!quota.allows(3, 10));
+        assert!(!config.quota_for("bob").allows(1, 1001));
+        assert!(QuotaConfig::default().allows(u64::MAX, u64::MAX));
+    }
+
+    #[test]
+    fn parse_hashes() {
+        assert!(Hash::parse(BCRYPT).is_ok());
+        assert!(Hash::parse(ARGON2).is_ok());
+        assert!(Hash::parse("$apr1$salt$hash").is_err());
+        assert!(Hash::parse("{SHA}abc").is_err());
+        assert!(Hash::parse("plaintext").is_err());
+    }
+
+    #[tokio::test]
+    async fn authenticate_and_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("htpasswd");
+        fs::write(
+            &path,
+            format!("# users\nalice:{}\nbob:{}\n", BCRYPT, ARGON2),
+        )
+        .unwrap();
+        let auth = Authenticator::new(&AuthConfig {
+            htpasswd_file: path.clone(),
+            realm: None,
+            reload_interval_secs: None,
+            quota: QuotaConfig::default(),
+            user_quotas: HashMap::new(),
+        })
+        .unwrap();
+        assert_eq!(
+            auth.challenge(),
+            "Basic realm=\"Threema Safe\", charset=\"UTF-8\""
+        );
+
+        let auth = &auth;
+        let login = |user: &str, password: &str| {
+            let req = request(Some(&basic(user, password)));
+            async move { auth.authenticate(&req).await }
+        };
+        assert_eq!(login("alice", "secret").await.as_deref(), Some("alice"));
+        assert_eq!(login("bob", "secret").await.as_deref(), Some("bob"));
+        assert_eq!(login("alice", "wrong").await, None);
+        assert_eq!(login("carol", "secret").await, None);
+        assert_eq!(auth.authenticate(&request(None)).await, None);
+        assert!(auth.has_user("alice"));
+
+        // Unchanged
+        assert!(!auth.reload().unwrap());
+
+        // Invalid files are not applied
+        fs::write(&path, "alice:plaintext\n").unwrap();
+        assert!(auth.reload().is_err());
+        assert_eq!(login("alice", "secret").await.as_deref(), Some("alice"));
+
+        fs::write(&path, format!("carol:{}\n", BCRYPT)).unwrap();
+        assert!(auth.reload().unwrap());
+        assert_eq!(login("alice", "secret").await, None);
+        assert_eq!(login("carol", "secret").await.as_deref(), Some("carol"));
+    }
+}
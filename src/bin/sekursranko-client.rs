@@ -0,0 +1,135 @@
+use std::{
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+use clap::{self, Parser, Subcommand};
+
+use sekursranko::client::Client;
+
+/// The environment variable with the password for Basic authentication.
+const PASSWORD_ENV: &str = "SEKURSRANKO_PASSWORD";
+
+/// A command line client for Threema Safe servers.
+#[derive(Parser, Debug)]
+#[command(author, version)]
+struct Cli {
+    /// The base URL of the server (e.g. "https://safe.example.com")
+    #[arg(short, long)]
+    url: String,
+
+    /// The user for Basic authentication (the password is read from the
+    /// SEKURSRANKO_PASSWORD environment variable)
+    #[arg(long)]
+    user: Option<String>,
+
+    /// The user agent (must be accepted by the server's policy)
+    #[arg(long)]
+    user_agent: Option<String>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print the server limits
+    Config,
+    /// Print the size, upload and expiry time of a backup
+    Head {
+        /// The backup ID
+        backup_id: String,
+    },
+    /// Download a backup
+    Get {
+        /// The backup ID
+        backup_id: String,
+        /// The output file (default: stdout)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Upload a backup
+    Put {
+        /// The backup ID
+        backup_id: String,
+        /// The input file ("-" for stdin)
+        input: PathBuf,
+    },
+    /// Delete a backup
+    Delete {
+        /// The backup ID
+        backup_id: String,
+    },
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let cli = Cli::parse();
+    if let Err(e) = run(cli).await {
+        eprintln!("Error: {:#}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run(cli: Cli) -> anyhow::Result<()> {
+    let mut client = Client::new(&cli.url)?;
+    if let Some(ref user_agent) = cli.user_agent {
+        client = client.with_user_agent(user_agent);
+    }
+    if let Some(ref user) = cli.user {
+        let password = std::env::var(PASSWORD_ENV)
+            .with_context(|| format!("{} must be set when using --user", PASSWORD_ENV))?;
+        client = client.with_basic_auth(user, &password);
+    }
+
+    match cli.command {
+        Command::Config => {
+            let config = client.config().await?;
+            println!("Max backup bytes: {}", config.max_backup_bytes);
+            println!("Retention days: {}", config.retention_days);
+        }
+        Command::Head { backup_id } => {
+            let info = client.head_backup(&backup_id).await?;
+            println!("Size: {} bytes", info.size);
+            if let Some(stored_at) = info.stored_at {
+                println!("Stored at: {}", httpdate::fmt_http_date(stored_at));
+            }
+            match info.expires_at {
+                Some(expires_at) => println!("Expires at: {}", httpdate::fmt_http_date(expires_at)),
+                None => println!("Expires at: never"),
+            }
+        }
+        Command::Get { backup_id, output } => {
+            let data = client.get_backup(&backup_id).await?;
+            match output {
+                Some(path) => std::fs::write(&path, data)
+                    .with_context(|| format!("Could not write {:?}", path))?,
+                None => std::io::stdout().write_all(&data)?,
+            }
+        }
+        Command::Put { backup_id, input } => {
+            let data = if input == Path::new("-") {
+                let mut data = vec![];
+                std::io::stdin().read_to_end(&mut data)?;
+                data
+            } else {
+                std::fs::read(&input).with_context(|| format!("Could not read {:?}", input))?
+            };
+            if data.is_empty() {
+                bail!("The backup is empty");
+            }
+            let updated = client.put_backup(&backup_id, data).await?;
+            println!(
+                "{} backup {}",
+                if updated { "Updated" } else { "Created" },
+                backup_id
+            );
+        }
+        Command::Delete { backup_id } => {
+            client.delete_backup(&backup_id).await?;
+            println!("Deleted backup {}", backup_id);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,237 @@
+//! A client for the Threema Safe API.
+//!
+//! The client sends the headers required by the server (a Threema user
+//! agent and the expected `Accept` and `Content-Type` headers) and turns
+//! error responses back into [`ApiError`]s.
+
+use std::{
+    fmt,
+    time::{Duration, SystemTime},
+};
+
+use reqwest::{header, RequestBuilder, Response, StatusCode};
+use serde_derive::Deserialize;
+
+use crate::{
+    config::ServerConfigPublic,
+    errors::ApiError,
+    handlers::{backup_id_valid, BACKUP_MEDIA_TYPE, CONFIG_MEDIA_TYPE, EXPIRES_HEADER},
+};
+
+/// Errors returned by the client.
+#[derive(Debug)]
+pub enum ClientError {
+    /// The server rejected the request (or the backup ID is invalid).
+    Api(ApiError),
+    /// The server responded with a status that does not correspond to an
+    /// API error (e.g. from a reverse proxy).
+    UnexpectedStatus(StatusCode),
+    /// The request could not be sent or the response could not be read.
+    Http(reqwest::Error),
+    /// The response body could not be parsed.
+    InvalidResponse(serde_json::Error),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::Api(e) => write!(f, "{} ({})", e.detail(), e.code()),
+            ClientError::UnexpectedStatus(status) => {
+                write!(f, "Server responded with status {}", status)
+            }
+            // The details are available as the error source
+            ClientError::Http(_) => write!(f, "Request failed"),
+            ClientError::InvalidResponse(_) => write!(f, "Invalid response"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientError::Http(e) => Some(e),
+            ClientError::InvalidResponse(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(e: reqwest::Error) -> Self {
+        // The URL contains the backup ID, which must not end up in the logs
+        ClientError::Http(e.without_url())
+    }
+}
+
+/// The JSON body of error responses.
+#[derive(Deserialize)]
+struct ErrorBody {
+    code: String,
+}
+
+/// The metadata of a stored backup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupInfo {
+    /// The size in bytes
+    pub size: u64,
+    /// The time of the last upload (if provided by the server)
+    pub stored_at: Option<SystemTime>,
+    /// The time the backup expires (or `None` if it never expires)
+    pub expires_at: Option<SystemTime>,
+}
+
+/// A client for a Threema Safe server.
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    base_url: String,
+    user_agent: String,
+    credentials: Option<(String, String)>,
+}
+
+impl Client {
+    /// Create a client for the server at `base_url` (e.g.
+    /// "https://safe.example.com").
+    pub fn new(base_url: &str) -> Result<Self, ClientError> {
+        Self::with_timeout(base_url, Duration::from_secs(30))
+    }
+
+    /// Create a client with a custom request timeout.
+    pub fn with_timeout(base_url: &str, timeout: Duration) -> Result<Self, ClientError> {
+        let http = reqwest::Client::builder().timeout(timeout).build()?;
+        Ok(Self {
+            http,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            user_agent: format!(
+                "Threema (compatible; sekursranko/{}; client)",
+                crate::VERSION
+            ),
+            credentials: None,
+        })
+    }
+
+    /// Use a different user agent (must be accepted by the server's policy).
+    pub fn with_user_agent(mut self, user_agent: &str) -> Self {
+        self.user_agent = user_agent.to_string();
+        self
+    }
+
+    /// Authenticate with HTTP Basic authentication.
+    pub fn with_basic_auth(mut self, user: &str, password: &str) -> Self {
+        self.credentials = Some((user.to_string(), password.to_string()));
+        self
+    }
+
+    /// Fetch the server limits.
+    pub async fn config(&self) -> Result<ServerConfigPublic, ClientError> {
+        let response = self
+            .request(reqwest::Method::GET, "/config")
+            .header(header::ACCEPT, CONFIG_MEDIA_TYPE)
+            .send()
+            .await?;
+        let body = check_status(response).await?.bytes().await?;
+        serde_json::from_slice(&body).map_err(ClientError::InvalidResponse)
+    }
+
+    /// Return the size, upload and expiry time of a backup.
+    pub async fn head_backup(&self, backup_id: &str) -> Result<BackupInfo, ClientError> {
+        let response = self
+            .backup_request(reqwest::Method::HEAD, backup_id)?
+            .header(header::ACCEPT, BACKUP_MEDIA_TYPE)
+            .send()
+            .await?;
+        // Responses to HEAD requests have no body, so errors can only be
+        // identified by their status
+        match response.status() {
+            StatusCode::OK => {
+                let headers = response.headers();
+                let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+                let size = header(header::CONTENT_LENGTH.as_str())
+                    .and_then(|v| v.parse().ok())
+                    .ok_or(ClientError::UnexpectedStatus(StatusCode::OK))?;
+                let time = |name| header(name).and_then(|v| httpdate::parse_http_date(v).ok());
+                Ok(BackupInfo {
+                    size,
+                    stored_at: time(header::LAST_MODIFIED.as_str()),
+                    expires_at: time(EXPIRES_HEADER),
+                })
+            }
+            StatusCode::NOT_FOUND => Err(ClientError::Api(ApiError::NotFound)),
+            StatusCode::UNAUTHORIZED => Err(ClientError::Api(ApiError::Unauthorized)),
+            status => Err(ClientError::UnexpectedStatus(status)),
+        }
+    }
+
+    /// Download a backup.
+    pub async fn get_backup(&self, backup_id: &str) -> Result<Vec<u8>, ClientError> {
+        let response = self
+            .backup_request(reqwest::Method::GET, backup_id)?
+            .header(header::ACCEPT, BACKUP_MEDIA_TYPE)
+            .send()
+            .await?;
+        Ok(check_status(response).await?.bytes().await?.to_vec())
+    }
+
+    /// Upload a backup.
+    ///
+    /// Return whether an existing backup was updated.
+    pub async fn put_backup(&self, backup_id: &str, data: Vec<u8>) -> Result<bool, ClientError> {
+        let response = self
+            .backup_request(reqwest::Method::PUT, backup_id)?
+            .header(header::CONTENT_TYPE, BACKUP_MEDIA_TYPE)
+            .body(data)
+            .send()
+            .await?;
+        let response = check_status(response).await?;
+        Ok(response.status() == StatusCode::NO_CONTENT)
+    }
+
+    /// Delete a backup.
+    pub async fn delete_backup(&self, backup_id: &str) -> Result<(), ClientError> {
+        let response = self
+            .backup_request(reqwest::Method::DELETE, backup_id)?
+            .send()
+            .await?;
+        check_status(response).await?;
+        Ok(())
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> RequestBuilder {
+        let mut request = self
+            .http
+            .request(method, format!("{}{}", self.base_url, path))
+            .header(header::USER_AGENT, &self.user_agent);
+        if let Some((ref user, ref password)) = self.credentials {
+            request = request.basic_auth(user, Some(password));
+        }
+        request
+    }
+
+    fn backup_request(
+        &self,
+        method: reqwest::Method,
+        backup_id: &str,
+    ) -> Result<RequestBuilder, ClientError> {
+        if !backup_id_valid(backup_id) {
+            return Err(ClientError::Api(ApiError::InvalidBackupId));
+        }
+        Ok(self.request(method, &format!("/backups/{}", backup_id)))
+    }
+}
+
+/// Turn error responses into errors.
+async fn check_status(response: Response) -> Result<Response, ClientError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body = response.bytes().await?;
+    let error = serde_json::from_slice::<ErrorBody>(&body)
+        .ok()
+        .and_then(|body| ApiError::from_code(&body.code))
+        .filter(|error| error.status() == status);
+    Err(match error {
+        Some(error) => ClientError::Api(error),
+        None => ClientError::UnexpectedStatus(status),
+    })
+}
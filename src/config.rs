@@ -1,3 +1,5 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::convert::From;
 use std::fmt;
 use std::fs::File;
@@ -6,8 +8,22 @@ use std::path::{Path, PathBuf};
 
 use serde_derive::{Deserialize, Serialize};
 
+use crate::{
+    audit::AuditLogConfig,
+    auth::{AuthConfig, QuotaConfig},
+    cors::CorsConfig,
+    encryption::EncryptionConfig,
+    migration::MigrationConfig,
+    privacy::LogPrivacyConfig,
+    replication::ReplicationConfig,
+    routing::RouteGroup,
+    tenants::TenantConfig,
+    user_agent::UserAgentPolicy,
+    webhooks::WebhookConfig,
+};
+
 /// The server configuration.
-#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
 pub struct ServerConfig {
     /// The max file size for backups (e.g. 65536)
     pub max_backup_bytes: u64,
@@ -15,13 +31,126 @@ pub struct ServerConfig {
     pub retention_days: u32,
     /// The path to the directory where backups will be stored
     pub backup_dir: PathBuf,
-    /// The listening address for the server (e.g. "127.0.0.1:3000")
+    /// The number of prior versions retained per backup (default: 0)
+    #[serde(default)]
+    pub retained_versions: u32,
+    /// The number of days deleted backups are kept in the trash. If not set,
+    /// deletions are permanent.
+    pub delete_grace_days: Option<u32>,
+    /// The interval between retention sweeps in seconds. If not set, expired
+    /// backups are only removed by the `sweep` command.
+    pub sweep_interval_secs: Option<u64>,
+    /// Reject uploads that are not structurally valid Threema Safe backups
+    /// (default: false)
+    #[serde(default)]
+    pub validate_backups: bool,
+    /// The listening address for the default listener
+    ///
+    /// See `ListenerConfig::listen_on` for the supported formats.
+    pub listen_on: Option<String>,
+    /// The file mode of the default listener's Unix domain socket
+    pub unix_socket_mode: Option<u32>,
+    /// The owner of the default listener's Unix domain socket
+    pub unix_socket_owner: Option<String>,
+    /// Whether to allow access from a web browser on the default listener
+    pub allow_browser: Option<bool>,
+    /// The CORS settings of the default listener
+    pub cors: Option<CorsConfig>,
+    /// Additional listeners with their own settings
+    #[serde(default)]
+    pub listeners: Vec<ListenerConfig>,
+    /// The user agent policy
+    #[serde(default)]
+    pub user_agent: UserAgentPolicy,
+    /// Require HTTP Basic authentication for the Safe API
+    pub auth: Option<AuthConfig>,
+    /// Replication to peer servers
+    pub replication: Option<ReplicationConfig>,
+    /// Read-through migration from a legacy server
+    pub migration: Option<MigrationConfig>,
+    /// Encryption of backups at rest
+    pub encryption: Option<EncryptionConfig>,
+    /// The audit log of backup lifecycle events
+    pub audit_log: Option<AuditLogConfig>,
+    /// Hide backup IDs and client addresses in logs
+    pub log_privacy: Option<LogPrivacyConfig>,
+    /// Webhook notifications of backup lifecycle events
+    pub webhooks: Option<WebhookConfig>,
+    /// Tenants with their own backup directory and settings
+    #[serde(default)]
+    pub tenants: Vec<TenantConfig>,
+}
+
+/// The configuration of a single listener.
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+pub struct ListenerConfig {
+    /// The listening address
+    ///
+    /// This can be a TCP socket address (e.g. "127.0.0.1:3000"), a Unix
+    /// domain socket path (e.g. "unix:/run/sekursranko.sock") or "systemd"
+    /// for systemd socket activation.
     pub listen_on: String,
+    /// The file mode of the Unix domain socket (e.g. 0o660)
+    pub unix_socket_mode: Option<u32>,
+    /// The owner of the Unix domain socket (e.g. "sekursranko:www-data")
+    pub unix_socket_owner: Option<String>,
     /// Whether to allow access from a web browser
     ///
-    /// This will disable the user-agent check and set a CORS header on the
-    /// response.
+    /// This will disable the user-agent check and enable CORS (allowing any
+    /// origin, unless configured otherwise in `cors`).
     pub allow_browser: Option<bool>,
+    /// CORS settings. If not set, CORS is only enabled if `allow_browser`
+    /// is set.
+    pub cors: Option<CorsConfig>,
+    /// TLS settings. If not set, plain HTTP is served.
+    pub tls: Option<TlsConfig>,
+    /// The route groups exposed on this listener (default: "info" and "safe")
+    pub routes: Option<Vec<RouteGroup>>,
+}
+
+/// The TLS configuration of a listener.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct TlsConfig {
+    /// Path to the PEM encoded certificate chain
+    pub cert_file: PathBuf,
+    /// Path to the PEM encoded private key
+    pub key_file: PathBuf,
+    /// Path to the PEM encoded CA certificates for client authentication.
+    /// If set, clients must present a certificate issued by one of them.
+    pub client_ca_file: Option<PathBuf>,
+    /// Path to the PEM encoded revocation lists for client certificates
+    pub client_crl_file: Option<PathBuf>,
+    /// The field of the client certificate used as user identity (for
+    /// ownership and quotas, instead of Basic authentication)
+    pub client_identity: Option<ClientIdentity>,
+    /// The quota of every user identified by a client certificate
+    #[serde(default)]
+    pub quota: QuotaConfig,
+    /// Quotas of individual users identified by a client certificate
+    /// (overriding `quota`)
+    #[serde(default)]
+    pub user_quotas: HashMap<String, QuotaConfig>,
+}
+
+impl TlsConfig {
+    /// Return the quota of a user identified by a client certificate.
+    pub fn quota_for(&self, user: &str) -> QuotaConfig {
+        self.quota.for_user(&self.user_quotas, user)
+    }
+}
+
+/// A field of a client certificate that identifies the user.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientIdentity {
+    /// The common name (CN) of the subject
+    CommonName,
+    /// The first email address in the subject alternative names
+    SanEmail,
+    /// The first DNS name in the subject alternative names
+    SanDns,
+    /// The first URI in the subject alternative names
+    SanUri,
 }
 
 impl ServerConfig {
@@ -42,6 +171,80 @@ impl ServerConfig {
         // Deserialize
         toml::from_str(&contents).map_err(|e| format!("Could not deserialize config file: {}", e))
     }
+
+    /// Return the config of the specified tenant.
+    ///
+    /// Settings that the tenant does not override are inherited. Replication
+    /// and migration are not supported for tenants.
+    pub fn tenant_config(&self, tenant: &TenantConfig) -> ServerConfig {
+        ServerConfig {
+            backup_dir: tenant.backup_dir.clone(),
+            max_backup_bytes: tenant.max_backup_bytes.unwrap_or(self.max_backup_bytes),
+            retention_days: tenant.retention_days.unwrap_or(self.retention_days),
+            user_agent: tenant
+                .user_agent
+                .clone()
+                .unwrap_or_else(|| self.user_agent.clone()),
+            auth: tenant.auth.clone().or_else(|| self.auth.clone()),
+            replication: None,
+            migration: None,
+            tenants: vec![],
+            ..self.clone()
+        }
+    }
+
+    /// Return the config of the tenant with the specified name.
+    pub fn for_tenant(&self, name: &str) -> Result<ServerConfig, String> {
+        self.tenants
+            .iter()
+            .find(|tenant| tenant.name == name)
+            .map(|tenant| self.tenant_config(tenant))
+            .ok_or_else(|| format!("Unknown tenant: {}", name))
+    }
+
+    /// Return all configured listeners.
+    ///
+    /// The top-level `listen_on` setting (if present) defines the first
+    /// listener, followed by the entries in `listeners`.
+    pub fn listeners(&self) -> Vec<ListenerConfig> {
+        let default_listener = self.listen_on.as_ref().map(|_| self.default_listener());
+        default_listener
+            .into_iter()
+            .chain(self.listeners.iter().cloned())
+            .collect()
+    }
+
+    /// Return the listener defined by the top-level settings.
+    pub fn default_listener(&self) -> ListenerConfig {
+        ListenerConfig {
+            listen_on: self.listen_on.clone().unwrap_or_default(),
+            unix_socket_mode: self.unix_socket_mode,
+            unix_socket_owner: self.unix_socket_owner.clone(),
+            allow_browser: self.allow_browser,
+            cors: self.cors.clone(),
+            tls: None,
+            routes: None,
+        }
+    }
+}
+
+impl ListenerConfig {
+    /// Return whether the specified route group is exposed on this listener.
+    pub fn exposes(&self, group: RouteGroup) -> bool {
+        match self.routes {
+            Some(ref routes) => routes.contains(&group),
+            None => RouteGroup::DEFAULT.contains(&group),
+        }
+    }
+
+    /// Return the effective CORS settings, or `None` if CORS is disabled.
+    pub fn cors(&self) -> Option<Cow<'_, CorsConfig>> {
+        match (&self.cors, self.allow_browser) {
+            (Some(cors), _) => Some(Cow::Borrowed(cors)),
+            (None, Some(true)) => Some(Cow::Owned(CorsConfig::default())),
+            (None, _) => None,
+        }
+    }
 }
 
 impl fmt::Display for ServerConfig {
@@ -49,12 +252,113 @@ impl fmt::Display for ServerConfig {
         writeln!(f, "- Max backup bytes: {}", self.max_backup_bytes)?;
         writeln!(f, "- Retention days: {}", self.retention_days)?;
         writeln!(f, "- Backup directory: {:?}", self.backup_dir)?;
-        writeln!(f, "- Listening address: {}", self.listen_on)?;
+        writeln!(f, "- Retained versions: {}", self.retained_versions)?;
+        if let Some(grace) = self.delete_grace_days {
+            writeln!(f, "- Delete grace period: {} days", grace)?;
+        }
+        if let Some(interval) = self.sweep_interval_secs {
+            writeln!(f, "- Retention sweep interval: {}s", interval)?;
+        }
+        if self.validate_backups {
+            writeln!(f, "- Backup validation: enabled")?;
+        }
+        if let Some(ref encryption) = self.encryption {
+            writeln!(
+                f,
+                "- Encryption key: {} ({:?})",
+                encryption.key_id, encryption.key_file
+            )?;
+        }
+        writeln!(f, "- User agent policy:")?;
+        for pattern in &self.user_agent.allow {
+            writeln!(f, "  - Allow: {}", pattern)?;
+        }
+        for pattern in &self.user_agent.deny {
+            writeln!(f, "  - Deny: {}", pattern)?;
+        }
+        for min in &self.user_agent.min_versions {
+            writeln!(f, "  - Min version: {} ({})", min.version, min.pattern)?;
+        }
+        if let Some(ref auth) = self.auth {
+            writeln!(f, "- Basic auth users: {:?}", auth.htpasswd_file)?;
+        }
+        if let Some(ref audit_log) = self.audit_log {
+            writeln!(f, "- Audit log: {:?}", audit_log.path)?;
+            writeln!(
+                f,
+                "  - Hashed backup IDs: {}",
+                audit_log.hash_key_file.is_some()
+            )?;
+        }
+        writeln!(f, "- Log privacy: {}", self.log_privacy.is_some())?;
+        if let Some(ref replication) = self.replication {
+            writeln!(f, "- Replication queue: {:?}", replication.queue_dir)?;
+            for peer in &replication.peers {
+                writeln!(f, "  - Peer {}: {}", peer.name, peer.url)?;
+            }
+        }
+        if let Some(ref webhooks) = self.webhooks {
+            for endpoint in &webhooks.endpoints {
+                writeln!(f, "- Webhook {}: {}", endpoint.name, endpoint.url)?;
+            }
+        }
+        for tenant in &self.tenants {
+            writeln!(f, "- Tenant {}: {:?}", tenant.name, tenant.backup_dir)?;
+            for host in &tenant.hosts {
+                writeln!(f, "  - Host: {}", host)?;
+            }
+            if let Some(ref prefix) = tenant.path_prefix {
+                writeln!(f, "  - Path prefix: {}", prefix)?;
+            }
+            if let Some(ref auth) = tenant.auth {
+                writeln!(f, "  - Basic auth users: {:?}", auth.htpasswd_file)?;
+            }
+        }
+        if let Some(ref migration) = self.migration {
+            writeln!(
+                f,
+                "- Migration upstream: {} (enabled: {})",
+                migration.upstream_url,
+                migration.enabled()
+            )?;
+        }
+        for listener in self.listeners() {
+            write!(f, "{}", listener)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ListenerConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "- Listener: {}", self.listen_on)?;
+        if let Some(mode) = self.unix_socket_mode {
+            writeln!(f, "  - Unix socket mode: {:o}", mode)?;
+        }
+        if let Some(ref owner) = self.unix_socket_owner {
+            writeln!(f, "  - Unix socket owner: {}", owner)?;
+        }
         writeln!(
             f,
-            "- Allow browser access: {}",
+            "  - Allow browser access: {}",
             self.allow_browser.unwrap_or(false)
         )?;
+        if let Some(cors) = self.cors() {
+            writeln!(
+                f,
+                "  - CORS allowed origins: {}",
+                cors.allowed_origins.join(", ")
+            )?;
+        }
+        if let Some(ref tls) = self.tls {
+            writeln!(f, "  - TLS certificate: {:?}", tls.cert_file)?;
+            if let Some(ref client_ca_file) = tls.client_ca_file {
+                writeln!(f, "  - TLS client CA: {:?}", client_ca_file)?;
+            }
+        }
+        if let Some(ref routes) = self.routes {
+            writeln!(f, "  - Routes: {:?}", routes)?;
+        }
         Ok(())
     }
 }
@@ -62,7 +366,7 @@ impl fmt::Display for ServerConfig {
 /// The public part of the server configuration.
 ///
 /// This can be queried over the API.
-#[derive(Debug, Copy, Clone, Serialize)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ServerConfigPublic {
     /// The max file size for backups (e.g. 65536)
@@ -126,9 +430,89 @@ mod tests {
                 max_backup_bytes: 10_000,
                 retention_days: 100,
                 backup_dir: PathBuf::from("backups"),
-                listen_on: "127.0.0.1:3000".to_string(),
+                listen_on: Some("127.0.0.1:3000".to_string()),
                 allow_browser: Some(true),
+                ..Default::default()
             }
         );
     }
+
+    #[test]
+    fn read_config_file_unix_socket() {
+        let mut tempfile = NamedTempFile::new().unwrap();
+        let file = tempfile.as_file_mut();
+        file.write_all(b"max_backup_bytes = 10000\n").unwrap();
+        file.write_all(b"retention_days = 100\n").unwrap();
+        file.write_all(b"backup_dir = \"backups\"\n").unwrap();
+        file.write_all(b"listen_on = \"unix:/run/sekursranko.sock\"\n")
+            .unwrap();
+        file.write_all(b"unix_socket_mode = 0o660\n").unwrap();
+        file.write_all(b"unix_socket_owner = \"sekursranko:www-data\"\n")
+            .unwrap();
+        let config = ServerConfig::from_file(tempfile.path()).unwrap();
+        assert_eq!(
+            config.listen_on.as_deref(),
+            Some("unix:/run/sekursranko.sock")
+        );
+        assert_eq!(config.unix_socket_mode, Some(0o660));
+        assert_eq!(
+            config.unix_socket_owner.as_deref(),
+            Some("sekursranko:www-data")
+        );
+    }
+
+    #[test]
+    fn read_config_file_listeners() {
+        let mut tempfile = NamedTempFile::new().unwrap();
+        let file = tempfile.as_file_mut();
+        file.write_all(b"max_backup_bytes = 10000\n").unwrap();
+        file.write_all(b"retention_days = 100\n").unwrap();
+        file.write_all(b"backup_dir = \"backups\"\n").unwrap();
+        file.write_all(b"listen_on = \"127.0.0.1:3000\"\n").unwrap();
+        file.write_all(b"[[listeners]]\n").unwrap();
+        file.write_all(b"listen_on = \"[::]:3443\"\n").unwrap();
+        file.write_all(b"routes = [\"safe\"]\n").unwrap();
+        file.write_all(b"tls = { cert_file = \"cert.pem\", key_file = \"key.pem\" }\n")
+            .unwrap();
+        file.write_all(b"[[listeners]]\n").unwrap();
+        file.write_all(b"listen_on = \"127.0.0.1:3001\"\n").unwrap();
+        file.write_all(b"allow_browser = true\n").unwrap();
+        file.write_all(b"[listeners.cors]\n").unwrap();
+        file.write_all(b"allowed_origins = [\"https://safe.example.com\"]\n")
+            .unwrap();
+        file.write_all(b"max_age = 3600\n").unwrap();
+        let config = ServerConfig::from_file(tempfile.path()).unwrap();
+        let listeners = config.listeners();
+        assert_eq!(listeners.len(), 3);
+
+        assert_eq!(listeners[0].listen_on, "127.0.0.1:3000");
+        assert!(listeners[0].exposes(RouteGroup::Info));
+        assert!(listeners[0].exposes(RouteGroup::Safe));
+        assert!(!listeners[0].exposes(RouteGroup::Metrics));
+
+        assert_eq!(listeners[1].listen_on, "[::]:3443");
+        assert!(!listeners[1].exposes(RouteGroup::Info));
+        assert!(listeners[1].exposes(RouteGroup::Safe));
+        assert_eq!(
+            listeners[1].tls,
+            Some(TlsConfig {
+                cert_file: PathBuf::from("cert.pem"),
+                key_file: PathBuf::from("key.pem"),
+                client_ca_file: None,
+                client_crl_file: None,
+                client_identity: None,
+                quota: Default::default(),
+                user_quotas: Default::default(),
+            })
+        );
+
+        assert_eq!(listeners[1].cors(), None);
+
+        assert_eq!(listeners[2].allow_browser, Some(true));
+        assert_eq!(listeners[2].tls, None);
+        let cors = listeners[2].cors().unwrap();
+        assert_eq!(cors.allowed_origins, vec!["https://safe.example.com"]);
+        assert_eq!(cors.allowed_methods, vec!["GET", "HEAD", "PUT", "DELETE"]);
+        assert_eq!(cors.max_age, Some(3600));
+    }
 }
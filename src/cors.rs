@@ -0,0 +1,283 @@
+use hyper::{
+    header::{self, HeaderMap, HeaderValue},
+    Body, Method, Request, Response, StatusCode,
+};
+use log::debug;
+use serde_derive::Deserialize;
+
+use crate::{
+    errors::{ApiError, ApiResult},
+    handlers::EXPIRES_HEADER,
+};
+
+/// The CORS configuration of a listener.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct CorsConfig {
+    /// Allowed origins (e.g. "https://safe.example.com"), or "*" for any
+    #[serde(default = "default_allowed_origins")]
+    pub allowed_origins: Vec<String>,
+    /// Allowed request methods
+    #[serde(default = "default_allowed_methods")]
+    pub allowed_methods: Vec<String>,
+    /// Allowed request headers
+    #[serde(default = "default_allowed_headers")]
+    pub allowed_headers: Vec<String>,
+    /// How long (in seconds) the result of a preflight request may be cached
+    pub max_age: Option<u32>,
+}
+
+fn default_allowed_origins() -> Vec<String> {
+    vec!["*".into()]
+}
+
+fn default_allowed_methods() -> Vec<String> {
+    ["GET", "HEAD", "PUT", "DELETE"]
+        .iter()
+        .map(|m| m.to_string())
+        .collect()
+}
+
+fn default_allowed_headers() -> Vec<String> {
+    // Authorization is needed for Basic authentication
+    ["Accept", "Authorization", "Content-Type"]
+        .iter()
+        .map(|h| h.to_string())
+        .collect()
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: default_allowed_origins(),
+            allowed_methods: default_allowed_methods(),
+            allowed_headers: default_allowed_headers(),
+            max_age: None,
+        }
+    }
+}
+
+impl CorsConfig {
+    fn allows_any_origin(&self) -> bool {
+        self.allowed_origins.iter().any(|o| o == "*")
+    }
+
+    /// Return the `Access-Control-Allow-Origin` value for the request
+    /// origin, or `None` if the origin is not allowed.
+    fn allow_origin(&self, origin: Option<&HeaderValue>) -> Option<HeaderValue> {
+        if self.allows_any_origin() {
+            return Some(HeaderValue::from_static("*"));
+        }
+        let origin = origin?;
+        let origin_str = origin.to_str().ok()?;
+        if self.allowed_origins.iter().any(|o| o == origin_str) {
+            Some(origin.clone())
+        } else {
+            None
+        }
+    }
+
+    /// Add the CORS headers to a response.
+    ///
+    /// `origin` is the `Origin` header of the request.
+    pub fn apply(&self, origin: Option<&HeaderValue>, response: &mut Response<Body>) {
+        let headers = response.headers_mut();
+        if let Some(allow_origin) = self.allow_origin(origin) {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+        }
+        if !self.allows_any_origin() {
+            headers.append(header::VARY, HeaderValue::from_static("Origin"));
+        }
+        if headers.contains_key(EXPIRES_HEADER) {
+            headers.insert(
+                header::ACCESS_CONTROL_EXPOSE_HEADERS,
+                HeaderValue::from_static(EXPIRES_HEADER),
+            );
+        }
+    }
+
+    /// Handle a CORS preflight request.
+    pub fn preflight(&self, req: &Request<Body>) -> ApiResult {
+        let request_headers = req.headers();
+        let origin = request_headers.get(header::ORIGIN);
+        if origin.is_none() || self.allow_origin(origin).is_none() {
+            debug!("CORS preflight with disallowed origin: {:?}", origin);
+            return Err(ApiError::CorsForbidden);
+        }
+        if !self.allows_method(request_headers) {
+            debug!("CORS preflight with disallowed method");
+            return Err(ApiError::CorsForbidden);
+        }
+        if !self.allows_headers(request_headers) {
+            debug!("CORS preflight with disallowed headers");
+            return Err(ApiError::CorsForbidden);
+        }
+
+        let mut builder = Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .header(
+                header::ACCESS_CONTROL_ALLOW_METHODS,
+                self.allowed_methods.join(", "),
+            )
+            .header(
+                header::ACCESS_CONTROL_ALLOW_HEADERS,
+                self.allowed_headers.join(", "),
+            );
+        if let Some(max_age) = self.max_age {
+            builder = builder.header(header::ACCESS_CONTROL_MAX_AGE, max_age);
+        }
+        let mut response = builder
+            .body(Body::empty())
+            .expect("Could not create response");
+        self.apply(origin, &mut response);
+        Ok(response)
+    }
+
+    fn allows_method(&self, request_headers: &HeaderMap) -> bool {
+        let method = match request_headers
+            .get(header::ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<Method>().ok())
+        {
+            Some(method) => method,
+            None => return false,
+        };
+        self.allowed_methods
+            .iter()
+            .any(|m| m.eq_ignore_ascii_case(method.as_str()))
+    }
+
+    fn allows_headers(&self, request_headers: &HeaderMap) -> bool {
+        let requested = match request_headers
+            .get(header::ACCESS_CONTROL_REQUEST_HEADERS)
+            .map(|v| v.to_str())
+        {
+            Some(Ok(requested)) => requested,
+            Some(Err(_)) => return false,
+            None => return true,
+        };
+        requested
+            .split(',')
+            .map(str::trim)
+            .filter(|h| !h.is_empty())
+            .all(|h| {
+                self.allowed_headers
+                    .iter()
+                    .any(|a| a.eq_ignore_ascii_case(h))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn preflight_request(origin: &str, method: &str, headers: Option<&str>) -> Request<Body> {
+        let mut builder = Request::builder()
+            .method(Method::OPTIONS)
+            .uri("/config")
+            .header(header::ORIGIN, origin)
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, method);
+        if let Some(headers) = headers {
+            builder = builder.header(header::ACCESS_CONTROL_REQUEST_HEADERS, headers);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    fn restricted() -> CorsConfig {
+        CorsConfig {
+            allowed_origins: vec!["https://safe.example.com".into()],
+            max_age: Some(600),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn preflight_any_origin() {
+        let cors = CorsConfig::default();
+        let res = cors
+            .preflight(&preflight_request(
+                "https://example.org",
+                "PUT",
+                Some("content-type, authorization"),
+            ))
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let headers = res.headers();
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_METHODS],
+            "GET, HEAD, PUT, DELETE"
+        );
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_HEADERS],
+            "Accept, Authorization, Content-Type"
+        );
+        assert!(headers.get(header::ACCESS_CONTROL_MAX_AGE).is_none());
+        assert!(headers.get(header::VARY).is_none());
+    }
+
+    #[test]
+    fn preflight_echo_origin() {
+        let res = restricted()
+            .preflight(&preflight_request(
+                "https://safe.example.com",
+                "DELETE",
+                None,
+            ))
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let headers = res.headers();
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://safe.example.com"
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_MAX_AGE], "600");
+        assert_eq!(headers[header::VARY], "Origin");
+    }
+
+    #[test]
+    fn preflight_forbidden() {
+        let cors = restricted();
+        let requests = vec![
+            preflight_request("https://evil.example.com", "PUT", None),
+            preflight_request("https://safe.example.com", "POST", None),
+            preflight_request("https://safe.example.com", "PUT", Some("x-custom")),
+        ];
+        for req in requests {
+            assert_eq!(cors.preflight(&req).unwrap_err(), ApiError::CorsForbidden);
+        }
+    }
+
+    #[test]
+    fn apply_disallowed_origin() {
+        let mut res = Response::new(Body::empty());
+        let origin = HeaderValue::from_static("https://evil.example.com");
+        restricted().apply(Some(&origin), &mut res);
+        assert!(res
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .is_none());
+        assert_eq!(res.headers()[header::VARY], "Origin");
+    }
+
+    #[test]
+    fn apply_expose_expiry() {
+        let mut res = Response::new(Body::empty());
+        let origin = HeaderValue::from_static("https://safe.example.com");
+        restricted().apply(Some(&origin), &mut res);
+        assert!(res
+            .headers()
+            .get(header::ACCESS_CONTROL_EXPOSE_HEADERS)
+            .is_none());
+
+        res.headers_mut().insert(
+            EXPIRES_HEADER,
+            HeaderValue::from_static("Fri, 12 Mar 2021 12:26:40 GMT"),
+        );
+        restricted().apply(Some(&origin), &mut res);
+        assert_eq!(
+            res.headers()[header::ACCESS_CONTROL_EXPOSE_HEADERS],
+            EXPIRES_HEADER
+        );
+    }
+}
@@ -0,0 +1,262 @@
+//! Optional server-side encryption of backups at rest.
+//!
+//! Encrypted backups are stored in the following format:
+//!
+//! ```text
+//! magic "SKRE" | version (1 byte) | key ID length (1 byte) | key ID | nonce (12 bytes) | ciphertext
+//! ```
+//!
+//! The ciphertext is produced by ChaCha20-Poly1305. The header and the
+//! backup ID are authenticated as associated data, so an encrypted file
+//! cannot be moved to another backup ID unnoticed.
+
+use std::{collections::HashMap, fmt, fs, path::PathBuf};
+
+use anyhow::{anyhow, bail, Context};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use rand::RngCore;
+use serde_derive::Deserialize;
+
+const MAGIC: &[u8] = b"SKRE";
+const FORMAT_VERSION: u8 = 1;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+
+/// The encryption configuration.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct EncryptionConfig {
+    /// Path to the key file
+    ///
+    /// Every line contains a key ID and a hex encoded 256 bit key, separated
+    /// by whitespace. Empty lines and lines starting with `#` are ignored.
+    pub key_file: PathBuf,
+    /// The ID of the key used for encrypting new backups
+    pub key_id: String,
+}
+
+/// The server keys, indexed by key ID.
+pub struct Keyring {
+    ciphers: HashMap<String, ChaCha20Poly1305>,
+    active: String,
+}
+
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Keyring")
+            .field("key_ids", &self.ciphers.keys().collect::<Vec<_>>())
+            .field("active", &self.active)
+            .finish()
+    }
+}
+
+impl Keyring {
+    /// Load the keys from the configured key file.
+    pub fn load(config: &EncryptionConfig) -> anyhow::Result<Self> {
+        let contents = fs::read_to_string(&config.key_file)
+            .with_context(|| format!("Could not read key file {:?}", config.key_file))?;
+        Self::parse(&contents, &config.key_id)
+    }
+
+    fn parse(contents: &str, active: &str) -> anyhow::Result<Self> {
+        let mut ciphers = HashMap::new();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.split_whitespace();
+            let (key_id, key_hex) = match (parts.next(), parts.next(), parts.next()) {
+                (Some(key_id), Some(key_hex), None) => (key_id, key_hex),
+                _ => bail!("Invalid key file entry on line {}", i + 1),
+            };
+            if key_id.len() > u8::MAX as usize {
+                bail!("Key ID on line {} is too long", i + 1);
+            }
+            let key = hex::decode(key_hex)
+                .ok()
+                .filter(|key| key.len() == KEY_LEN)
+                .ok_or_else(|| {
+                    anyhow!("Invalid key on line {} (expected 64 hex characters)", i + 1)
+                })?;
+            let cipher = ChaCha20Poly1305::new(Key::from_slice(&key));
+            if ciphers.insert(key_id.to_string(), cipher).is_some() {
+                bail!("Duplicate key ID: {}", key_id);
+            }
+        }
+        if !ciphers.contains_key(active) {
+            bail!("Key file does not contain the key {}", active);
+        }
+        Ok(Self {
+            ciphers,
+            active: active.to_string(),
+        })
+    }
+
+    /// The ID of the key used for encrypting new backups.
+    pub fn active_key_id(&self) -> &str {
+        &self.active
+    }
+
+    /// Encrypt a backup with the active key.
+    pub fn encrypt(&self, backup_id: &str, plaintext: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut nonce = [0; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let mut data = Vec::with_capacity(
+            MAGIC.len() + 2 + self.active.len() + NONCE_LEN + plaintext.len() + TAG_LEN,
+        );
+        data.extend_from_slice(MAGIC);
+        data.push(FORMAT_VERSION);
+        data.push(self.active.len() as u8);
+        data.extend_from_slice(self.active.as_bytes());
+        data.extend_from_slice(&nonce);
+        let aad = [&data[..], backup_id.as_bytes()].concat();
+        let ciphertext = self.ciphers[&self.active]
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| anyhow!("Could not encrypt backup"))?;
+        data.extend_from_slice(&ciphertext);
+        Ok(data)
+    }
+
+    /// Decrypt a stored backup.
+    ///
+    /// Backups that were stored before encryption was enabled are returned
+    /// unchanged.
+    pub fn decrypt(&self, backup_id: &str, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let header = match Header::parse(data)? {
+            Some(header) => header,
+            None => return Ok(data.to_vec()),
+        };
+        let cipher = self
+            .ciphers
+            .get(header.key_id)
+            .ok_or_else(|| anyhow!("Unknown key ID: {}", header.key_id))?;
+        let nonce_start = header.len - NONCE_LEN;
+        let aad = [&data[..header.len], backup_id.as_bytes()].concat();
+        cipher
+            .decrypt(
+                Nonce::from_slice(&data[nonce_start..header.len]),
+                Payload {
+                    msg: &data[header.len..],
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| anyhow!("Could not decrypt backup (corrupted or wrong key)"))
+    }
+}
+
+/// The header of an encrypted backup.
+#[derive(Debug, PartialEq)]
+pub struct Header<'a> {
+    /// The ID of the key the backup was encrypted with
+    pub key_id: &'a str,
+    /// The length of the header, including the nonce
+    pub len: usize,
+}
+
+impl<'a> Header<'a> {
+    /// The number of bytes needed to determine the header length.
+    pub const PREFIX_LEN: usize = 6;
+
+    /// The number of bytes an encrypted backup with the specified header
+    /// length is larger than the plaintext.
+    pub fn overhead_for(header_len: usize) -> usize {
+        header_len + TAG_LEN
+    }
+
+    /// Parse the header of a stored backup.
+    ///
+    /// Return `None` if the backup is not encrypted.
+    pub fn parse(data: &'a [u8]) -> anyhow::Result<Option<Self>> {
+        if !data.starts_with(MAGIC) {
+            return Ok(None);
+        }
+        if data.len() < Self::PREFIX_LEN {
+            bail!("Truncated encryption header");
+        }
+        if data[4] != FORMAT_VERSION {
+            bail!("Unsupported encryption format version: {}", data[4]);
+        }
+        let key_id_end = Self::PREFIX_LEN + data[5] as usize;
+        let len = key_id_end + NONCE_LEN;
+        if data.len() < len + TAG_LEN {
+            bail!("Truncated encrypted backup");
+        }
+        let key_id =
+            std::str::from_utf8(&data[Self::PREFIX_LEN..key_id_end]).context("Invalid key ID")?;
+        Ok(Some(Self { key_id, len }))
+    }
+
+    /// Return the header length from the first `PREFIX_LEN` bytes of a
+    /// stored backup, or `None` if the backup is not encrypted.
+    pub fn len_from_prefix(prefix: &[u8]) -> Option<usize> {
+        if prefix.len() < Self::PREFIX_LEN || !prefix.starts_with(MAGIC) {
+            return None;
+        }
+        Some(Self::PREFIX_LEN + prefix[5] as usize + NONCE_LEN)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BACKUP_ID: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+    const KEYS: &str = "# Keys\n\
+        2024 0000000000000000000000000000000000000000000000000000000000000001\n\
+        \n\
+        2025 0000000000000000000000000000000000000000000000000000000000000002\n";
+
+    #[test]
+    fn parse_key_file() {
+        let keyring = Keyring::parse(KEYS, "2025").unwrap();
+        assert_eq!(keyring.active_key_id(), "2025");
+        assert_eq!(keyring.ciphers.len(), 2);
+
+        assert!(Keyring::parse(KEYS, "2026").is_err());
+        assert!(Keyring::parse("2025 abcd\n", "2025").is_err());
+        assert!(Keyring::parse("2025\n", "2025").is_err());
+        assert!(Keyring::parse(&format!("{}{}", KEYS, KEYS), "2025").is_err());
+    }
+
+    #[test]
+    fn roundtrip() {
+        let keyring = Keyring::parse(KEYS, "2025").unwrap();
+        let data = keyring.encrypt(BACKUP_ID, b"sekurkopio").unwrap();
+        let header = Header::parse(&data).unwrap().unwrap();
+        assert_eq!(header.key_id, "2025");
+        assert_eq!(data.len() - Header::overhead_for(header.len), 10);
+        assert_eq!(Header::len_from_prefix(&data[..6]), Some(header.len));
+        assert_eq!(keyring.decrypt(BACKUP_ID, &data).unwrap(), b"sekurkopio");
+
+        // Old keys can still be used for decrypting
+        let rotated = Keyring::parse(KEYS, "2024").unwrap();
+        assert_eq!(rotated.decrypt(BACKUP_ID, &data).unwrap(), b"sekurkopio");
+    }
+
+    #[test]
+    fn decrypt_tampered() {
+        let keyring = Keyring::parse(KEYS, "2025").unwrap();
+        let mut data = keyring.encrypt(BACKUP_ID, b"sekurkopio").unwrap();
+        let other_id = BACKUP_ID.replace('0', "f");
+        assert!(keyring.decrypt(&other_id, &data).is_err());
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        assert!(keyring.decrypt(BACKUP_ID, &data).is_err());
+    }
+
+    #[test]
+    fn decrypt_plaintext() {
+        let keyring = Keyring::parse(KEYS, "2025").unwrap();
+        assert_eq!(keyring.decrypt(BACKUP_ID, b"legacy").unwrap(), b"legacy");
+    }
+}
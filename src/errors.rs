@@ -0,0 +1,188 @@
+use std::fmt;
+
+use hyper::{header, Body, Response, StatusCode};
+use serde_derive::Serialize;
+
+/// Errors returned by the API.
+///
+/// Every error is rendered as a JSON object with a stable, machine-readable
+/// `code` and a human-readable `detail`, e.g.
+/// `{"code":"invalid_backup_id","detail":"Invalid backup ID"}`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiError {
+    InvalidAcceptHeader,
+    InvalidContentTypeHeader,
+    InvalidContentLengthHeader,
+    InvalidUserAgent,
+    InvalidBackupId,
+    /// The upload is not a structurally valid Threema Safe backup.
+    InvalidBackup,
+    /// Missing or invalid credentials (the `WWW-Authenticate` header is
+    /// added by the handler).
+    Unauthorized,
+    BackupTooLarge,
+    /// The user's backup count or size quota would be exceeded.
+    QuotaExceeded,
+    CorsForbidden,
+    NotFound,
+    /// The value is the `Allow` header of the route.
+    MethodNotAllowed(&'static str),
+    InternalServerError,
+    /// The migration upstream server could not be reached.
+    UpstreamUnavailable,
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    code: &'static str,
+    detail: &'static str,
+}
+
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::InvalidAcceptHeader
+            | ApiError::InvalidContentTypeHeader
+            | ApiError::InvalidContentLengthHeader
+            | ApiError::InvalidUserAgent
+            | ApiError::InvalidBackupId
+            | ApiError::InvalidBackup => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ApiError::BackupTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::QuotaExceeded => StatusCode::FORBIDDEN,
+            ApiError::CorsForbidden => StatusCode::FORBIDDEN,
+            ApiError::NotFound => StatusCode::NOT_FOUND,
+            ApiError::MethodNotAllowed(_) => StatusCode::METHOD_NOT_ALLOWED,
+            ApiError::InternalServerError => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::UpstreamUnavailable => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    /// The machine-readable error code.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::InvalidAcceptHeader => "invalid_accept_header",
+            ApiError::InvalidContentTypeHeader => "invalid_content_type_header",
+            ApiError::InvalidContentLengthHeader => "invalid_content_length_header",
+            ApiError::InvalidUserAgent => "invalid_user_agent",
+            ApiError::InvalidBackupId => "invalid_backup_id",
+            ApiError::InvalidBackup => "invalid_backup",
+            ApiError::Unauthorized => "unauthorized",
+            ApiError::BackupTooLarge => "backup_too_large",
+            ApiError::QuotaExceeded => "quota_exceeded",
+            ApiError::CorsForbidden => "cors_forbidden",
+            ApiError::NotFound => "not_found",
+            ApiError::MethodNotAllowed(_) => "method_not_allowed",
+            ApiError::InternalServerError => "internal_server_error",
+            ApiError::UpstreamUnavailable => "upstream_unavailable",
+        }
+    }
+
+    /// The human-readable error description.
+    pub fn detail(&self) -> &'static str {
+        match self {
+            ApiError::InvalidAcceptHeader => "Invalid accept header",
+            ApiError::InvalidContentTypeHeader => "Invalid content-type header",
+            ApiError::InvalidContentLengthHeader => "Invalid or missing content-length header",
+            ApiError::InvalidUserAgent => "Invalid user agent",
+            ApiError::InvalidBackupId => "Invalid backup ID",
+            ApiError::InvalidBackup => "Not a valid Threema Safe backup",
+            ApiError::Unauthorized => "Authentication required",
+            ApiError::BackupTooLarge => "Backup is too large",
+            ApiError::QuotaExceeded => "Backup quota exceeded",
+            ApiError::CorsForbidden => "CORS request not allowed",
+            ApiError::NotFound => "Not found",
+            ApiError::MethodNotAllowed(_) => "Method not allowed",
+            ApiError::InternalServerError => "Internal server error",
+            ApiError::UpstreamUnavailable => "Upstream server unavailable",
+        }
+    }
+
+    /// Look up an error by its machine-readable code (e.g. in a client).
+    ///
+    /// `method_not_allowed` is not supported, because the `Allow` header is
+    /// not part of the code.
+    pub fn from_code(code: &str) -> Option<Self> {
+        [
+            ApiError::InvalidAcceptHeader,
+            ApiError::InvalidContentTypeHeader,
+            ApiError::InvalidContentLengthHeader,
+            ApiError::InvalidUserAgent,
+            ApiError::InvalidBackupId,
+            ApiError::InvalidBackup,
+            ApiError::Unauthorized,
+            ApiError::BackupTooLarge,
+            ApiError::QuotaExceeded,
+            ApiError::CorsForbidden,
+            ApiError::NotFound,
+            ApiError::InternalServerError,
+            ApiError::UpstreamUnavailable,
+        ]
+        .iter()
+        .copied()
+        .find(|error| error.code() == code)
+    }
+
+    /// Render the error as a JSON response.
+    pub fn into_response(self) -> Response<Body> {
+        let body = serde_json::to_string(&ErrorBody {
+            code: self.code(),
+            detail: self.detail(),
+        })
+        .expect("Could not serialize error");
+        let mut builder = Response::builder()
+            .status(self.status())
+            .header(header::CONTENT_TYPE, "application/json");
+        if let ApiError::MethodNotAllowed(allow) = self {
+            builder = builder.header(header::ALLOW, allow);
+        }
+        builder
+            .body(Body::from(body))
+            .expect("Could not create response")
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.detail())
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+/// The result type of the request handlers.
+pub type ApiResult = Result<Response<Body>, ApiError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn render_error() {
+        let res = ApiError::InvalidBackupId.into_response();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "application/json");
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(
+            body,
+            "{\"code\":\"invalid_backup_id\",\"detail\":\"Invalid backup ID\"}"
+        );
+    }
+
+    #[test]
+    fn error_from_code() {
+        assert_eq!(
+            ApiError::from_code("quota_exceeded"),
+            Some(ApiError::QuotaExceeded)
+        );
+        assert_eq!(ApiError::from_code("method_not_allowed"), None);
+        assert_eq!(ApiError::from_code("unknown"), None);
+    }
+
+    #[test]
+    fn render_method_not_allowed() {
+        let res = ApiError::MethodNotAllowed("GET, HEAD").into_response();
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(res.headers()[header::ALLOW], "GET, HEAD");
+    }
+}
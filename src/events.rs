@@ -0,0 +1,89 @@
+use std::{fmt, time::SystemTime};
+
+/// The kind of a backup lifecycle event.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EventKind {
+    Created,
+    Updated,
+    Deleted,
+    /// The backup was deleted by the retention sweep
+    Expired,
+    /// A request to change the backup was rejected
+    Rejected,
+}
+
+impl EventKind {
+    /// Return whether the stored backup was changed.
+    pub fn is_change(self) -> bool {
+        self != EventKind::Rejected
+    }
+}
+
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EventKind::Created => write!(f, "created"),
+            EventKind::Updated => write!(f, "updated"),
+            EventKind::Deleted => write!(f, "deleted"),
+            EventKind::Expired => write!(f, "expired"),
+            EventKind::Rejected => write!(f, "rejected"),
+        }
+    }
+}
+
+/// The origin of an event.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Source {
+    /// A request to the Threema Safe API
+    Api,
+    /// The retention sweep
+    Sweep,
+    /// An administrative command
+    Admin,
+    /// Read-through migration from the upstream server
+    Migration,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Api => write!(f, "api"),
+            Source::Sweep => write!(f, "sweep"),
+            Source::Admin => write!(f, "admin"),
+            Source::Migration => write!(f, "migration"),
+        }
+    }
+}
+
+/// A backup lifecycle event.
+///
+/// Changes are emitted after they have been persisted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupEvent {
+    pub kind: EventKind,
+    pub source: Source,
+    pub backup_id: String,
+    /// The size of the backup in bytes (if known)
+    pub size: Option<u64>,
+    /// The reason for a rejection (the API error code)
+    pub reason: Option<&'static str>,
+    pub timestamp: SystemTime,
+}
+
+impl BackupEvent {
+    pub fn new(kind: EventKind, source: Source, backup_id: &str, size: Option<u64>) -> Self {
+        Self {
+            kind,
+            source,
+            backup_id: backup_id.to_string(),
+            size,
+            reason: None,
+            timestamp: SystemTime::now(),
+        }
+    }
+
+    pub fn with_reason(mut self, reason: &'static str) -> Self {
+        self.reason = Some(reason);
+        self
+    }
+}
@@ -1,17 +1,30 @@
-use std::{io::Error as IoError, os::unix::fs::PermissionsExt, path::Path};
-
-use anyhow::{bail, Context};
-use futures::StreamExt;
 use hyper::{header, Body, Method, Request, Response, StatusCode};
-use log::{debug, error, info, trace, warn};
-use rand::Rng;
-use tokio::{fs, io::AsyncWriteExt};
+use log::{debug, error, info, warn};
 
 use crate::{
-    config::{ServerConfig, ServerConfigPublic},
-    routing::{Route, Router},
+    auth::{Authenticator, QuotaConfig},
+    config::{ListenerConfig, ServerConfig, ServerConfigPublic},
+    errors::{ApiError, ApiResult},
+    events::{BackupEvent, EventKind, Source},
+    metrics::Metrics,
+    migration::Migrator,
+    openapi, retention,
+    routing::{Operation, Route, Router},
+    state::State,
+    tenants,
+    validation::{self, MIN_BACKUP_BYTES},
 };
 
+/// The media type of the server config (`/config`).
+pub(crate) const CONFIG_MEDIA_TYPE: &str = "application/json";
+
+/// The media type of backups (`/backups/:backupId`).
+pub(crate) const BACKUP_MEDIA_TYPE: &str = "application/octet-stream";
+
+/// The header with the time a backup expires (an HTTP date, like
+/// `Last-Modified`). It is omitted if backups never expire.
+pub(crate) const EXPIRES_HEADER: &str = "X-Sekursranko-Expires";
+
 macro_rules! require_accept_starts_with {
     ($req:expr, $accept:expr) => {
         match $req
@@ -22,7 +35,7 @@ macro_rules! require_accept_starts_with {
             Some(accept) if accept.starts_with($accept) => {}
             _ => {
                 warn!("Received request without valid accept header");
-                return response_400_bad_request("{\"detail\": \"Invalid accept header\"}");
+                return Err(ApiError::InvalidAcceptHeader);
             }
         }
     };
@@ -37,7 +50,7 @@ macro_rules! require_accept_is {
             != Some($accept)
         {
             warn!("Received request without valid accept header");
-            return response_400_bad_request("{\"detail\": \"Invalid accept header\"}");
+            return Err(ApiError::InvalidAcceptHeader);
         }
     };
 }
@@ -51,386 +64,674 @@ macro_rules! require_content_type_is {
             != Some($accept)
         {
             warn!("Received request without valid content-type header");
-            return response_400_bad_request("{\"detail\": \"Invalid content-type header\"}");
+            return Err(ApiError::InvalidContentTypeHeader);
         }
     };
 }
 
 /// Main handler.
+///
+/// The client identity is the user verified by the client certificate of
+/// the connection (if the listener maps certificates to users).
 pub async fn handler(
     req: Request<Body>,
     router: &Router,
-    config: &ServerConfig,
+    state: &State,
+    listener: &ListenerConfig,
+    client_identity: Option<&str>,
 ) -> Result<Response<Body>, hyper::Error> {
-    // Verify headers
-    if !config.allow_browser.unwrap_or(false) {
-        match req
-            .headers()
-            .get(header::USER_AGENT)
-            .and_then(|v| v.to_str().ok())
-        {
-            Some(uagent) if uagent.contains("Threema") => {}
-            _ => {
-                warn!("Received request without valid user agent");
-                return Ok(Response::builder()
-                    .status(StatusCode::BAD_REQUEST)
-                    .body(Body::empty())
-                    .expect("Could not create response"));
-            }
-        }
+    // Select the tenant
+    let (state, path) = tenants::select(&state.tenants, state, &req);
+    let path = path.to_string();
+
+    let route_match = router
+        .recognize(&path)
+        .ok()
+        .filter(|route_match| listener.exposes(route_match.handler().group()));
+
+    let cors = listener.cors();
+    let operation = route_match
+        .as_ref()
+        .and_then(|route_match| Operation::new(**route_match.handler(), req.method()))
+        .filter(|operation| !operation.is_preflight() || cors.is_some());
+    let origin = req.headers().get(header::ORIGIN).cloned();
+
+    // Handle CORS preflight requests for the Safe API
+    if let (Some(cors), Some(true)) = (&cors, operation.map(Operation::is_preflight)) {
+        return Ok(cors.preflight(&req).unwrap_or_else(|e| e.into_response()));
     }
 
-    let mut response = if let Ok(route_match) = router.recognize(req.uri().path()) {
-        match route_match.handler() {
-            Route::Index => {
-                if req.method() == Method::GET {
-                    handle_index()
-                } else {
-                    response_405_method_not_allowed()
-                }
-            }
-            Route::Config => {
-                if req.method() == Method::GET {
-                    handle_config(&req, config)
-                } else {
-                    response_405_method_not_allowed()
-                }
+    // Verify headers (metrics and the API description are meant for scrapers
+    // and tooling, not for Threema clients)
+    let is_tooling = route_match
+        .as_ref()
+        .is_some_and(|m| matches!(m.handler(), Route::Metrics | Route::OpenApi));
+    let result = if !listener.allow_browser.unwrap_or(false) && !is_tooling {
+        check_user_agent(&req, state)
+    } else {
+        Ok(())
+    };
+
+    // Check the credentials for the Safe API (unless the client certificate
+    // identifies the user)
+    let is_safe_api = route_match
+        .as_ref()
+        .is_some_and(|m| matches!(m.handler(), Route::Config | Route::Backup));
+    let (result, user) = match (result, client_identity, &state.auth) {
+        (Ok(()), Some(identity), _) if is_safe_api => (Ok(()), Some(identity.to_string())),
+        (Ok(()), None, Some(auth)) if is_safe_api => {
+            match check_credentials(&req, state, auth).await {
+                Ok(user) => (Ok(()), Some(user)),
+                Err(e) => (Err(e), None),
             }
-            Route::Backup => match *req.method() {
-                Method::GET | Method::HEAD => {
-                    handle_get_backup(
-                        &req,
-                        config,
-                        route_match
-                            .params()
-                            .find("backupId")
-                            .expect("Missing backupId param"),
-                    )
-                    .await
-                }
-                Method::PUT => {
-                    handle_put_backup(
-                        req,
-                        config,
-                        route_match
-                            .params()
-                            .find("backupId")
-                            .expect("Missing backupId param"),
-                    )
-                    .await
-                }
-                Method::DELETE => {
-                    handle_delete_backup(
-                        config,
-                        route_match
-                            .params()
-                            .find("backupId")
-                            .expect("Missing backupId param"),
-                    )
-                    .await
-                }
-                _ => response_405_method_not_allowed(),
-            },
         }
-    } else {
-        response_404_not_found()
+        (result, _, _) => (result, None),
     };
 
-    if config.allow_browser.unwrap_or(false) {
-        let headers = response.headers_mut();
-        headers.insert(
-            header::ACCESS_CONTROL_ALLOW_ORIGIN,
-            header::HeaderValue::from_static("*"),
-        );
+    // Users identified by a client certificate get the quotas of the
+    // listener, all others those of `[auth]`
+    let quota = match (&user, client_identity) {
+        (Some(user), Some(_)) => listener.tls.as_ref().map(|tls| tls.quota_for(user)),
+        (Some(user), None) => state.config.auth.as_ref().map(|auth| auth.quota_for(user)),
+        (None, _) => None,
+    }
+    .unwrap_or_default();
+
+    let backup_id = route_match
+        .as_ref()
+        .and_then(|m| m.params().find("backupId"));
+    let is_change = matches!(*req.method(), Method::PUT | Method::DELETE);
+    let declared_size = content_length(&req);
+    let result = match (result, &route_match) {
+        (Err(e), _) => Err(e),
+        (Ok(()), Some(route_match)) => {
+            dispatch(
+                req,
+                **route_match.handler(),
+                backup_id,
+                state,
+                user.as_deref(),
+                &quota,
+                cors.is_some(),
+            )
+            .await
+        }
+        (Ok(()), None) => Err(ApiError::NotFound),
+    };
+
+    // Record rejected changes to backups
+    if let (Err(e), Some(backup_id), true) = (&result, backup_id, is_change) {
+        if e.status().is_client_error() && *e != ApiError::NotFound {
+            state
+                .emit(
+                    &BackupEvent::new(EventKind::Rejected, Source::Api, backup_id, declared_size)
+                        .with_reason(e.code()),
+                )
+                .await;
+        }
+    }
+
+    let mut response = result.unwrap_or_else(|e| e.into_response());
+    if response.status() == StatusCode::UNAUTHORIZED {
+        if let Some(ref auth) = state.auth {
+            response
+                .headers_mut()
+                .insert(header::WWW_AUTHENTICATE, auth.challenge());
+        }
+    }
+
+    if let Some(cors) = cors {
+        cors.apply(origin.as_ref(), &mut response);
     }
 
     Ok(response)
 }
 
-fn handle_index() -> Response<Body> {
-    Response::builder()
+/// Return the value of the Content-Length header.
+fn content_length(req: &Request<Body>) -> Option<u64> {
+    req.headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+}
+
+fn check_user_agent(req: &Request<Body>, state: &State) -> Result<(), ApiError> {
+    let user_agent = req
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|v| v.to_str().ok());
+    state
+        .config
+        .user_agent
+        .check(user_agent)
+        .map_err(|rejection| {
+            warn!("Received request with invalid user agent: {}", rejection);
+            Metrics::inc(&state.metrics.user_agent_rejected);
+            ApiError::InvalidUserAgent
+        })
+}
+
+async fn check_credentials(
+    req: &Request<Body>,
+    state: &State,
+    auth: &Authenticator,
+) -> Result<String, ApiError> {
+    if let Some(user) = auth.authenticate(req).await {
+        return Ok(user);
+    }
+    debug!("Received request with missing or invalid credentials");
+    Metrics::inc(&state.metrics.auth_failed);
+    Err(ApiError::Unauthorized)
+}
+
+/// Dispatch a request to the handler of the matched route.
+async fn dispatch(
+    req: Request<Body>,
+    route: Route,
+    backup_id: Option<&str>,
+    state: &State,
+    user: Option<&str>,
+    quota: &QuotaConfig,
+    cors: bool,
+) -> ApiResult {
+    let config = &state.config;
+    let method_not_allowed = || Err(ApiError::MethodNotAllowed(route.allow(cors)));
+    match route {
+        Route::Index => {
+            if req.method() == Method::GET {
+                handle_index()
+            } else {
+                method_not_allowed()
+            }
+        }
+        Route::Config => {
+            if req.method() == Method::GET {
+                handle_config(&req, config)
+            } else {
+                method_not_allowed()
+            }
+        }
+        Route::Backup => {
+            let backup_id = backup_id.expect("Missing backupId param");
+            match *req.method() {
+                Method::GET | Method::HEAD => handle_get_backup(&req, state, backup_id, user).await,
+                Method::PUT => handle_put_backup(req, state, backup_id, user, quota).await,
+                Method::DELETE => handle_delete_backup(state, backup_id, user).await,
+                _ => method_not_allowed(),
+            }
+        }
+        Route::Metrics => {
+            if req.method() == Method::GET {
+                handle_metrics(state).await
+            } else {
+                method_not_allowed()
+            }
+        }
+        Route::OpenApi => {
+            if req.method() == Method::GET {
+                handle_openapi(config)
+            } else {
+                method_not_allowed()
+            }
+        }
+    }
+}
+
+fn handle_index() -> ApiResult {
+    Ok(Response::builder()
         .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
         .body(Body::from(format!("{} {}", crate::NAME, crate::VERSION)))
-        .expect("Could not create response")
+        .expect("Could not create response"))
+}
+
+async fn handle_metrics(state: &State) -> ApiResult {
+    let mut metrics = state.metrics.render();
+    if let Some(ref replicator) = state.replicator {
+        replicator.render_metrics(&mut metrics).await;
+    }
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(Body::from(metrics))
+        .expect("Could not create response"))
 }
 
-fn handle_config(req: &Request<Body>, config: &ServerConfig) -> Response<Body> {
-    require_accept_starts_with!(req, "application/json");
+fn handle_openapi(config: &ServerConfig) -> ApiResult {
+    let document = serde_json::to_string(&openapi::document(config)).map_err(|e| {
+        error!("Could not serialize OpenAPI document: {}", e);
+        ApiError::InternalServerError
+    })?;
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(document))
+        .expect("Could not create response"))
+}
+
+fn handle_config(req: &Request<Body>, config: &ServerConfig) -> ApiResult {
+    require_accept_starts_with!(req, CONFIG_MEDIA_TYPE);
     let config_string = match serde_json::to_string(&ServerConfigPublic::from(config)) {
         Ok(s) => s,
         Err(e) => {
             error!("Could not serialize server config: {}", e);
-            return response_500_internal_server_error();
+            return Err(ApiError::InternalServerError);
         }
     };
-    Response::builder()
+    Ok(Response::builder()
         .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, CONFIG_MEDIA_TYPE)
         .body(Body::from(config_string))
-        .expect("Could not create response")
+        .expect("Could not create response"))
 }
 
 /// Return whether this backup id is valid.
 ///
 /// A backup id must be a 64 character lowercase hex string.
-fn backup_id_valid(backup_id: &str) -> bool {
+pub(crate) fn backup_id_valid(backup_id: &str) -> bool {
     backup_id.len() == 64
         && backup_id
             .chars()
             .all(|c| c.is_ascii_hexdigit() && (c.is_ascii_digit() || c.is_lowercase()))
 }
 
+/// Return the owner of a backup, or 404 if it is owned by another user.
+///
+/// Backups without an owner (e.g. stored before authentication was enabled)
+/// are accessible to all users.
+async fn check_owner(
+    state: &State,
+    backup_id: &str,
+    user: Option<&str>,
+) -> Result<Option<String>, ApiError> {
+    let user = match user {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+    match state.storage.owner(backup_id).await {
+        Ok(Some(owner)) if owner != user => {
+            debug!(
+                "Backup {} is owned by another user than {}",
+                state.log_privacy.id(backup_id),
+                user
+            );
+            Err(ApiError::NotFound)
+        }
+        Ok(owner) => Ok(owner),
+        Err(e) => {
+            error!(
+                "Could not read owner of backup {}: {}",
+                state.log_privacy.id(backup_id),
+                e
+            );
+            Err(ApiError::InternalServerError)
+        }
+    }
+}
+
+/// Make a user the owner of a backup.
+async fn claim_backup(state: &State, backup_id: &str, user: &str) -> Result<(), ApiError> {
+    state
+        .storage
+        .set_owner(backup_id, Some(user))
+        .await
+        .map_err(|e| {
+            error!(
+                "Could not set owner of backup {}: {}",
+                state.log_privacy.id(backup_id),
+                e
+            );
+            ApiError::InternalServerError
+        })
+}
+
+/// Check that storing a backup keeps the user within their quota.
+async fn check_quota(
+    state: &State,
+    user: &str,
+    quota: &QuotaConfig,
+    backup_id: &str,
+    size: u64,
+) -> Result<(), ApiError> {
+    if *quota == QuotaConfig::default() {
+        return Ok(());
+    }
+    let internal_error = |e: std::io::Error| {
+        error!("Could not determine the usage of {}: {}", user, e);
+        ApiError::InternalServerError
+    };
+    let (mut backups, mut bytes) = (1, size);
+    for (owned_id, owner) in state.storage.owners().await.map_err(internal_error)? {
+        if owner != user || owned_id == backup_id {
+            continue;
+        }
+        backups += 1;
+        bytes += match state.storage.size(&owned_id).await {
+            Ok(size) => size,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(internal_error(e)),
+        };
+    }
+    if !quota.allows(backups, bytes) {
+        warn!(
+            "Upload by {} exceeds the quota ({} backups, {} bytes)",
+            user, backups, bytes
+        );
+        return Err(ApiError::QuotaExceeded);
+    }
+    Ok(())
+}
+
 async fn handle_get_backup(
     req: &Request<Body>,
-    config: &ServerConfig,
+    state: &State,
     backup_id: &str,
-) -> Response<Body> {
+    user: Option<&str>,
+) -> ApiResult {
     // Validate headers
-    require_accept_is!(req, "application/octet-stream");
+    require_accept_is!(req, BACKUP_MEDIA_TYPE);
 
     // Validate params
     if !backup_id_valid(backup_id) {
         warn!(
             "Download of backup with invalid id was requested: {}",
-            backup_id
+            state.log_privacy.id(backup_id)
         );
-        return response_404_not_found();
+        return Err(ApiError::NotFound);
     }
 
     let is_head_request = req.method() == Method::HEAD;
+    check_owner(state, backup_id, user).await?;
 
-    let backup_path = config.backup_dir.join(backup_id);
+    let backup_path = state.storage.path(backup_id);
+    if let Some(ref migrator) = state.migrator {
+        if backup_path.exists() {
+            Metrics::inc(&state.metrics.migration_hits);
+        } else {
+            Metrics::inc(&state.metrics.migration_misses);
+            migrate_backup(state, migrator, backup_id).await?;
+            if let (Some(user), true) = (user, backup_path.exists()) {
+                claim_backup(state, backup_id, user).await?;
+            }
+        }
+    }
     if backup_path.exists() && backup_path.is_file() {
-        let body: Body = if is_head_request {
-            Body::empty()
+        let (body, length): (Body, u64) = if is_head_request {
+            let size = state.storage.size(backup_id).await.map_err(|e| {
+                error!("Could not read file metadata: {}", e);
+                ApiError::InternalServerError
+            })?;
+            (Body::empty(), size)
         } else {
-            let bytes = match fs::read(backup_path).await {
-                Ok(bytes) => bytes,
-                Err(e) => {
-                    error!("Could not read file: {}", e);
-                    return response_500_internal_server_error();
-                }
-            };
-            bytes.into()
+            let bytes = state.storage.read(backup_id).await.map_err(|e| {
+                error!("Could not read file: {}", e);
+                ApiError::InternalServerError
+            })?;
+            let length = bytes.len() as u64;
+            (bytes.into(), length)
         };
-        Response::builder()
+        let stored_at = state.storage.stored_at(backup_id).await.map_err(|e| {
+            error!("Could not read file metadata: {}", e);
+            ApiError::InternalServerError
+        })?;
+        let mut builder = Response::builder()
             .status(StatusCode::OK)
-            .body(body)
-            .expect("Could not create response")
+            .header(header::CONTENT_TYPE, BACKUP_MEDIA_TYPE)
+            .header(header::CONTENT_LENGTH, length)
+            .header(header::LAST_MODIFIED, httpdate::fmt_http_date(stored_at));
+        if let Some(expires_at) = retention::expires_at(stored_at, state.config.retention_days) {
+            builder = builder.header(EXPIRES_HEADER, httpdate::fmt_http_date(expires_at));
+        }
+        Ok(builder.body(body).expect("Could not create response"))
     } else {
-        response_404_not_found()
+        Err(ApiError::NotFound)
     }
 }
 
-// Create a file with permissions set to 0600.
-async fn create_file(path: &Path) -> Result<fs::File, IoError> {
-    let file = fs::File::create(path).await?;
-    let mut perms = file.metadata().await?.permissions();
-    perms.set_mode(0o600);
-    file.set_permissions(perms).await?;
-    Ok(file)
+/// Fetch a backup that does not exist locally from the migration upstream.
+async fn migrate_backup(
+    state: &State,
+    migrator: &Migrator,
+    backup_id: &str,
+) -> Result<(), ApiError> {
+    let max_bytes = state.config.max_backup_bytes;
+    match migrator.migrate(&state.storage, backup_id, max_bytes).await {
+        Ok(Some(size)) => {
+            info!(
+                "Migrated backup {} from upstream",
+                state.log_privacy.id(backup_id)
+            );
+            Metrics::inc(&state.metrics.migration_migrated);
+            state
+                .emit(&BackupEvent::new(
+                    EventKind::Created,
+                    Source::Migration,
+                    backup_id,
+                    Some(size),
+                ))
+                .await;
+            Ok(())
+        }
+        Ok(None) => Ok(()),
+        Err(e) => {
+            warn!(
+                "Could not migrate backup {} from upstream: {:#}",
+                state.log_privacy.id(backup_id),
+                e
+            );
+            Err(ApiError::UpstreamUnavailable)
+        }
+    }
 }
 
 async fn handle_put_backup(
     req: Request<Body>,
-    config: &ServerConfig,
+    state: &State,
     backup_id: &str,
-) -> Response<Body> {
+    user: Option<&str>,
+    quota: &QuotaConfig,
+) -> ApiResult {
+    let config = &state.config;
+
     // Validate headers
-    require_content_type_is!(req, "application/octet-stream");
+    require_content_type_is!(req, BACKUP_MEDIA_TYPE);
 
     // Validate params
     if !backup_id_valid(backup_id) {
         warn!(
             "Upload of backup with invalid id was requested: {}",
-            backup_id
+            state.log_privacy.id(backup_id)
         );
-        return response_400_bad_request("{\"detail\": \"Invalid backup ID\"}");
+        return Err(ApiError::InvalidBackupId);
     }
+    let owner = check_owner(state, backup_id, user).await?;
 
     // Validate backup path
-    let backup_path = config.backup_dir.join(backup_id);
+    let backup_path = state.storage.path(backup_id);
     if backup_path.exists() && !backup_path.is_file() {
         warn!(
-            "Tried to upload to a backup path that exists but is not a file: {:?}",
-            backup_path
+            "Tried to upload to a backup path that exists but is not a file: {}",
+            state.log_privacy.id(backup_id)
         );
-        return response_500_internal_server_error();
+        return Err(ApiError::InternalServerError);
     }
 
     // Get Content-Length header
     // We can trust that the actual body size will not be larger than the
     // declared content length, because hyper will actually stop consuming data
     // after the declared number of bytes have been processed.
-    let content_length: Option<u64> = req
-        .headers()
-        .get(header::CONTENT_LENGTH)
-        .and_then(|v| v.to_str().ok())
-        .and_then(|v| v.parse().ok());
+    let content_length = content_length(&req);
     if let Some(length) = content_length {
         if length > config.max_backup_bytes {
             warn!(
                 "Upload request is too large ({} > {})",
                 length, config.max_backup_bytes
             );
-            return Response::builder()
-                .status(StatusCode::PAYLOAD_TOO_LARGE)
-                .body(Body::from("{\"detail\": \"Backup is too large\"}"))
-                .expect("Could not create response");
+            return Err(ApiError::BackupTooLarge);
         }
     } else {
         warn!(
             "Upload request has invalid content-length header: \"{:?}\"",
             req.headers().get(header::CONTENT_LENGTH)
         );
-        return response_400_bad_request(
-            "{\"detail\": \"Invalid or missing content-length header\"}",
-        );
+        return Err(ApiError::InvalidContentLengthHeader);
+    };
+    if let (Some(user), Some(length)) = (user, content_length) {
+        check_quota(state, user, quota, backup_id, length).await?;
+    }
+
+    // Validate the backup structure while writing it (uploads that are too
+    // short are rejected without reading them)
+    let body = if config.validate_backups {
+        if content_length.is_some_and(|length| length < MIN_BACKUP_BYTES) {
+            warn!(
+                "Upload of backup {} is too short",
+                state.log_privacy.id(backup_id)
+            );
+            return Err(ApiError::InvalidBackup);
+        }
+        validation::validate_body(req.into_body())
+    } else {
+        req.into_body()
     };
 
     // Write backup
-    match write_backup(req.into_body(), backup_id, &backup_path).await {
+    match state.storage.write(backup_id, body).await {
         Ok(updated) => {
+            if let (Some(user), None) = (user, owner) {
+                claim_backup(state, backup_id, user).await?;
+            }
             info!(
                 "{} backup {}",
                 if updated { "Updated" } else { "Created" },
-                backup_id
+                state.log_privacy.id(backup_id)
             );
-            Response::builder()
+            let kind = if updated {
+                EventKind::Updated
+            } else {
+                EventKind::Created
+            };
+            state
+                .emit(&BackupEvent::new(
+                    kind,
+                    Source::Api,
+                    backup_id,
+                    content_length,
+                ))
+                .await;
+            Ok(Response::builder()
                 .status(if updated {
                     StatusCode::NO_CONTENT
                 } else {
                     StatusCode::CREATED
                 })
                 .body(Body::empty())
-                .expect("Could not create response")
+                .expect("Could not create response"))
         }
         Err(e) => {
+            if let Some(reason) = validation::invalid_backup(&e) {
+                warn!(
+                    "Rejected upload of backup {}: {}",
+                    state.log_privacy.id(backup_id),
+                    reason
+                );
+                return Err(ApiError::InvalidBackup);
+            }
             error!("Could not write backup: {}", e);
-            response_500_internal_server_error()
+            Err(ApiError::InternalServerError)
         }
     }
 }
 
-/// Store the backup to the file system.
-///
-/// Return true if an existing backup was updated, or false if a new backup was created.
-async fn write_backup(mut body: Body, backup_id: &str, backup_path: &Path) -> anyhow::Result<bool> {
-    // The incoming stream will be written to a temporary file. This is done to prevent
-    // incomplete backups from being persisted.
-    let random_ext: String = {
-        let mut rng = rand::thread_rng();
-        std::iter::repeat(())
-            .map(|_| rng.sample(rand::distributions::Alphanumeric))
-            .map(char::from)
-            .take(10)
-            .collect()
-    };
-    let backup_path_dl = backup_path.with_extension(random_ext);
-    trace!("Writing temporary upload to {:?}", backup_path_dl);
-    if backup_path_dl.exists() {
-        bail!(
-            "Random upload path \"{:?}\" already exists!",
-            backup_path_dl
-        );
-    }
-
-    // Create the empty download file to ensure correct permissions before
-    // writing the data
-    let mut backup_file_dl = create_file(&backup_path_dl)
-        .await
-        .context("Could not create temporary file")?;
-
-    // Write data to temporary file
-    while let Some(chunk_or_error) = body.next().await {
-        let chunk = chunk_or_error.context("Could not read body chunk")?;
-        backup_file_dl
-            .write_all(&chunk)
-            .await
-            .context("Could not write chunk to temporary file")?
-    }
-    trace!("Wrote temp backup for {}", backup_id);
-
-    // Move temporary file to final location
-    let updated = backup_path.exists() && backup_path.is_file();
-    fs::rename(&backup_path_dl, &backup_path)
-        .await
-        .context("Could not move temporary backup to final location")?;
-    trace!("Renamed: {:?} -> {:?}", backup_path_dl, backup_path);
-
-    Ok(updated)
-}
-
-async fn handle_delete_backup(config: &ServerConfig, backup_id: &str) -> Response<Body> {
+async fn handle_delete_backup(state: &State, backup_id: &str, user: Option<&str>) -> ApiResult {
     // Validate params
     if !backup_id_valid(backup_id) {
         warn!(
             "Deletion of backup with invalid id was requested: {}",
-            backup_id
+            state.log_privacy.id(backup_id)
         );
-        return response_400_bad_request("{\"detail\": \"Invalid backup ID\"}");
+        return Err(ApiError::InvalidBackupId);
     }
+    check_owner(state, backup_id, user).await?;
 
-    let backup_path = config.backup_dir.join(backup_id);
+    let backup_path = state.storage.path(backup_id);
+
+    // Delete the backup upstream first, so that it is never migrated again
+    let mut deleted_upstream = false;
+    if let Some(ref migrator) = state.migrator {
+        deleted_upstream = migrator.delete(backup_id).await.map_err(|e| {
+            warn!(
+                "Could not delete backup {} on upstream: {:#}",
+                state.log_privacy.id(backup_id),
+                e
+            );
+            ApiError::UpstreamUnavailable
+        })?;
+    }
 
     // Ensure backup exists
+    if !backup_path.exists() && deleted_upstream {
+        info!(
+            "Deleted backup {} on upstream",
+            state.log_privacy.id(backup_id)
+        );
+        state
+            .emit(&BackupEvent::new(
+                EventKind::Deleted,
+                Source::Migration,
+                backup_id,
+                None,
+            ))
+            .await;
+        return Ok(Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())
+            .expect("Could not create response"));
+    }
     if !backup_path.exists() {
         debug!(
-            "Tried to delete a backup path that does not exist: {:?}",
-            backup_path
+            "Tried to delete a backup that does not exist: {}",
+            state.log_privacy.id(backup_id)
         );
-        return response_404_not_found();
+        return Err(ApiError::NotFound);
     }
 
     // Ensure backup is a file
     if !backup_path.is_file() {
         warn!(
-            "Tried to delete a backup path that exists but is not a file: {:?}",
-            backup_path
+            "Tried to delete a backup path that exists but is not a file: {}",
+            state.log_privacy.id(backup_id)
         );
-        return response_500_internal_server_error();
+        return Err(ApiError::InternalServerError);
     }
 
     // Delete file
-    match fs::remove_file(&backup_path).await {
-        Ok(_) => Response::builder()
-            .status(StatusCode::NO_CONTENT)
-            .body(Body::empty())
-            .expect("Could not create response"),
+    match state.storage.delete(backup_id).await {
+        Ok(_) => {
+            state
+                .emit(&BackupEvent::new(
+                    EventKind::Deleted,
+                    Source::Api,
+                    backup_id,
+                    None,
+                ))
+                .await;
+            Ok(Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .body(Body::empty())
+                .expect("Could not create response"))
+        }
         Err(e) => {
-            error!("Could not delete backup at {:?}: {}", &backup_path, e);
-            response_500_internal_server_error()
+            error!(
+                "Could not delete backup {}: {}",
+                state.log_privacy.id(backup_id),
+                e
+            );
+            Err(ApiError::InternalServerError)
         }
     }
 }
 
-fn response_400_bad_request(body: &'static str) -> Response<Body> {
-    Response::builder()
-        .status(StatusCode::BAD_REQUEST)
-        .body(Body::from(body))
-        .expect("Could not create response")
-}
-
-fn response_404_not_found() -> Response<Body> {
-    Response::builder()
-        .status(StatusCode::NOT_FOUND)
-        .body(Body::empty())
-        .expect("Could not create response")
-}
-
-fn response_405_method_not_allowed() -> Response<Body> {
-    Response::builder()
-        .status(StatusCode::METHOD_NOT_ALLOWED)
-        .body(Body::empty())
-        .expect("Could not create response")
-}
-
-fn response_500_internal_server_error() -> Response<Body> {
-    Response::builder()
-        .status(StatusCode::INTERNAL_SERVER_ERROR)
-        .body(Body::from("{\"detail\": \"Internal server error\"}"))
-        .expect("Could not create response")
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
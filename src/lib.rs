@@ -1,14 +1,61 @@
 #![deny(clippy::all)]
 
+pub mod admin;
+mod archive;
+mod audit;
+mod auth;
+pub mod client;
 mod config;
+mod cors;
+mod encryption;
+mod errors;
+mod events;
 mod handlers;
+mod listen;
+mod metrics;
+mod migration;
+pub mod openapi;
+mod privacy;
+mod replication;
+mod retention;
 mod routing;
+mod safe_backup;
+mod server;
 mod service;
+mod state;
+mod storage;
+mod tenants;
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(feature = "tower")]
+mod tower;
+mod user_agent;
+mod validation;
+mod webhooks;
 
 pub use crate::{
-    config::{ServerConfig, ServerConfigPublic},
+    audit::AuditLogConfig,
+    auth::{AuthConfig, QuotaConfig},
+    config::{ClientIdentity, ListenerConfig, ServerConfig, ServerConfigPublic, TlsConfig},
+    cors::CorsConfig,
+    encryption::EncryptionConfig,
+    errors::ApiError,
+    listen::{serve, ListenAddr},
+    migration::MigrationConfig,
+    privacy::LogPrivacyConfig,
+    replication::{PeerConfig, ReplicationConfig},
+    routing::RouteGroup,
+    server::{ServerBuilder, ServerHandle, ShutdownTrigger},
     service::{BackupService, MakeBackupService},
+    state::BackgroundTasks,
+    storage::Storage,
+    tenants::TenantConfig,
+    user_agent::{MinVersion, Pattern, UserAgentPolicy, Version},
+    webhooks::{WebhookConfig, WebhookEndpoint},
 };
 
+#[cfg(feature = "tower")]
+pub use crate::tower::SafeApi;
+
 pub static NAME: &str = "Sekurŝranko";
 pub static VERSION: &str = env!("CARGO_PKG_VERSION");
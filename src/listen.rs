@@ -0,0 +1,461 @@
+use std::{
+    env,
+    ffi::CString,
+    fmt, fs,
+    future::Future,
+    net::SocketAddr,
+    os::unix::{
+        fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+        io::{FromRawFd, RawFd},
+    },
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, bail, Context};
+use futures::future;
+use hyper::{server::accept, Server};
+use log::{debug, info};
+use tokio::net::UnixListener;
+
+use crate::{config::ListenerConfig, service::MakeBackupService};
+
+/// The first file descriptor passed in by systemd (`SD_LISTEN_FDS_START`).
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// A parsed listening address.
+///
+/// Supported formats:
+///
+/// - `127.0.0.1:3000` or `[::1]:3000`: A TCP socket address
+/// - `unix:/run/sekursranko.sock`: A Unix domain socket path
+/// - `systemd` or `systemd:1`: A socket passed in via systemd socket
+///   activation (`LISTEN_FDS`), optionally selected by index
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+    Systemd(usize),
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err("Unix socket path must not be empty".to_string());
+            }
+            Ok(ListenAddr::Unix(PathBuf::from(path)))
+        } else if s == "systemd" {
+            Ok(ListenAddr::Systemd(0))
+        } else if let Some(index) = s.strip_prefix("systemd:") {
+            index
+                .parse()
+                .map(ListenAddr::Systemd)
+                .map_err(|e| format!("Invalid systemd socket index: {}", e))
+        } else {
+            s.parse()
+                .map(ListenAddr::Tcp)
+                .map_err(|e| format!("Invalid socket address: {}", e))
+        }
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+            ListenAddr::Systemd(index) => write!(f, "systemd:{}", index),
+        }
+    }
+}
+
+/// A bound listening socket.
+#[derive(Debug)]
+pub(crate) enum BoundListener {
+    Tcp(std::net::TcpListener),
+    Unix(UnixListener),
+}
+
+impl BoundListener {
+    /// Return the local address of a TCP socket.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            BoundListener::Tcp(tcp_listener) => tcp_listener.local_addr().ok(),
+            BoundListener::Unix(_) => None,
+        }
+    }
+}
+
+/// Bind the socket of the specified listener.
+///
+/// This must be called from within a tokio runtime.
+pub(crate) fn bind(addr: &ListenAddr, listener: &ListenerConfig) -> anyhow::Result<BoundListener> {
+    let bound = match addr {
+        ListenAddr::Tcp(addr) => {
+            let tcp_listener = std::net::TcpListener::bind(addr)
+                .with_context(|| format!("Could not bind to {}", addr))?;
+            tcp_listener.set_nonblocking(true)?;
+            info!("Listening on {}", tcp_listener.local_addr()?);
+            BoundListener::Tcp(tcp_listener)
+        }
+        ListenAddr::Unix(path) => {
+            if listener.tls.is_some() {
+                bail!("TLS is not supported on Unix domain sockets");
+            }
+            let unix_listener = bind_unix(path, listener)?;
+            info!("Listening on unix:{}", path.display());
+            BoundListener::Unix(unix_listener)
+        }
+        ListenAddr::Systemd(index) => match systemd_listener(*index)? {
+            SystemdListener::Tcp(tcp_listener) => {
+                info!("Listening on systemd socket {} ({:?})", index, tcp_listener);
+                BoundListener::Tcp(tcp_listener)
+            }
+            SystemdListener::Unix(unix_listener) => {
+                if listener.tls.is_some() {
+                    bail!("TLS is not supported on Unix domain sockets");
+                }
+                info!(
+                    "Listening on systemd socket {} ({:?})",
+                    index, unix_listener
+                );
+                BoundListener::Unix(UnixListener::from_std(unix_listener)?)
+            }
+        },
+    };
+    Ok(bound)
+}
+
+/// Serve the backup service on the specified listener until an error occurs.
+pub async fn serve(
+    addr: &ListenAddr,
+    listener: &ListenerConfig,
+    service: MakeBackupService,
+) -> anyhow::Result<()> {
+    let bound = bind(addr, listener)?;
+    serve_bound(bound, listener, service, future::pending()).await
+}
+
+/// Serve the backup service on a bound socket until an error occurs or the
+/// `shutdown` future completes.
+///
+/// On shutdown, no new connections are accepted and open connections are
+/// closed once their current request is answered.
+pub(crate) async fn serve_bound(
+    bound: BoundListener,
+    listener: &ListenerConfig,
+    service: MakeBackupService,
+    shutdown: impl Future<Output = ()>,
+) -> anyhow::Result<()> {
+    match bound {
+        BoundListener::Tcp(tcp_listener) => {
+            serve_tcp(tcp_listener, listener, service, shutdown).await
+        }
+        BoundListener::Unix(unix_listener) => {
+            Ok(serve_unix(unix_listener, service, shutdown).await?)
+        }
+    }
+}
+
+async fn serve_tcp(
+    tcp_listener: std::net::TcpListener,
+    listener: &ListenerConfig,
+    service: MakeBackupService,
+    shutdown: impl Future<Output = ()>,
+) -> anyhow::Result<()> {
+    match listener.tls {
+        #[cfg(feature = "tls")]
+        Some(ref tls_config) => {
+            let acceptor = crate::tls::make_acceptor(tls_config)?;
+            let tcp_listener = tokio::net::TcpListener::from_std(tcp_listener)?;
+            // The accept loop stops on shutdown (or if the server fails)
+            let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+            let shutdown = async move {
+                shutdown.await;
+                let _ = stop_tx.send(());
+            };
+            let incoming = accept::from_stream(crate::tls::incoming(
+                tcp_listener,
+                acceptor,
+                tls_config.client_identity,
+                service.log_privacy().clone(),
+                async {
+                    let _ = stop_rx.await;
+                },
+            ));
+            let make_service =
+                hyper::service::make_service_fn(move |conn: &crate::tls::TlsConnection| {
+                    let service = service.connection_service(conn.client_identity.clone());
+                    async move { Ok::<_, hyper::Error>(service) }
+                });
+            Server::builder(incoming)
+                .serve(make_service)
+                .with_graceful_shutdown(shutdown)
+                .await?;
+        }
+        #[cfg(not(feature = "tls"))]
+        Some(_) => bail!("TLS support is not enabled (compile with the \"tls\" feature)"),
+        None => {
+            Server::from_tcp(tcp_listener)?
+                .serve(service)
+                .with_graceful_shutdown(shutdown)
+                .await?
+        }
+    }
+    Ok(())
+}
+
+async fn serve_unix(
+    listener: UnixListener,
+    service: MakeBackupService,
+    shutdown: impl Future<Output = ()>,
+) -> hyper::Result<()> {
+    let incoming = accept::poll_fn(move |cx| {
+        listener
+            .poll_accept(cx)
+            .map(|res| Some(res.map(|(stream, _)| stream)))
+    });
+    Server::builder(incoming)
+        .serve(service)
+        .with_graceful_shutdown(shutdown)
+        .await
+}
+
+/// Bind a Unix domain socket and apply the configured mode and owner.
+///
+/// The socket is bound in a private directory next to `path` and moved into
+/// place once its mode and owner are set, so that it is never reachable with
+/// the default mode. A stale socket file at the same path is removed before
+/// binding.
+fn bind_unix(path: &Path, config: &ListenerConfig) -> anyhow::Result<UnixListener> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            bail!("Path {:?} exists but is not a socket", path);
+        }
+        debug!("Removing stale socket at {:?}", path);
+        fs::remove_file(path).context("Could not remove stale socket")?;
+    }
+
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("Invalid unix socket path {:?}", path))?;
+    let private_dir = path.with_file_name(format!(".sekursranko-{}", std::process::id()));
+    if private_dir.is_dir() {
+        debug!("Removing stale directory at {:?}", private_dir);
+        fs::remove_dir_all(&private_dir).context("Could not remove stale socket directory")?;
+    }
+    fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&private_dir)
+        .context("Could not create socket directory")?;
+    let private_path = private_dir.join(file_name);
+    let result = bind_unix_private(&private_path, path, config);
+
+    // Nothing is left behind once the socket was moved or if binding failed
+    let _ = fs::remove_file(&private_path);
+    let _ = fs::remove_dir(&private_dir);
+    result
+}
+
+/// Bind a Unix domain socket at `private_path`, apply the configured mode and
+/// owner and move it to `path`.
+fn bind_unix_private(
+    private_path: &Path,
+    path: &Path,
+    config: &ListenerConfig,
+) -> anyhow::Result<UnixListener> {
+    let listener = UnixListener::bind(private_path).context("Could not bind unix socket")?;
+
+    if let Some(mode) = config.unix_socket_mode {
+        fs::set_permissions(private_path, fs::Permissions::from_mode(mode))
+            .context("Could not set unix socket mode")?;
+    }
+    if let Some(ref owner) = config.unix_socket_owner {
+        let (uid, gid) = parse_owner(owner)?;
+        std::os::unix::fs::chown(private_path, uid, gid)
+            .context("Could not set unix socket owner")?;
+    }
+
+    fs::rename(private_path, path).context("Could not move unix socket into place")?;
+    Ok(listener)
+}
+
+/// Parse an owner specification in the form `user`, `user:group` or `:group`.
+///
+/// Both user and group may be specified by name or by numeric id.
+fn parse_owner(owner: &str) -> anyhow::Result<(Option<u32>, Option<u32>)> {
+    let (user, group) = match owner.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (owner, None),
+    };
+    let uid = match user {
+        "" => None,
+        user => Some(lookup_uid(user)?),
+    };
+    let gid = match group {
+        None | Some("") => None,
+        Some(group) => Some(lookup_gid(group)?),
+    };
+    Ok((uid, gid))
+}
+
+fn lookup_uid(user: &str) -> anyhow::Result<u32> {
+    if let Ok(uid) = user.parse() {
+        return Ok(uid);
+    }
+    let name = CString::new(user)?;
+    // Safety: `getpwnam` is called with a valid nul-terminated string. The
+    // returned pointer is only dereferenced if non-null, before any other
+    // call that could overwrite the static buffer.
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    if passwd.is_null() {
+        bail!("Unknown user: {}", user);
+    }
+    Ok(unsafe { (*passwd).pw_uid })
+}
+
+fn lookup_gid(group: &str) -> anyhow::Result<u32> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let name = CString::new(group)?;
+    // Safety: See `lookup_uid`.
+    let grp = unsafe { libc::getgrnam(name.as_ptr()) };
+    if grp.is_null() {
+        bail!("Unknown group: {}", group);
+    }
+    Ok(unsafe { (*grp).gr_gid })
+}
+
+enum SystemdListener {
+    Tcp(std::net::TcpListener),
+    Unix(std::os::unix::net::UnixListener),
+}
+
+/// Take over a socket passed in via systemd socket activation.
+///
+/// See `sd_listen_fds(3)` for the protocol.
+fn systemd_listener(index: usize) -> anyhow::Result<SystemdListener> {
+    let pid: u32 = env::var("LISTEN_PID")
+        .context("LISTEN_PID is not set, not started via socket activation?")?
+        .parse()
+        .context("Invalid LISTEN_PID")?;
+    if pid != std::process::id() {
+        bail!("LISTEN_PID does not match our PID");
+    }
+    let fds: usize = env::var("LISTEN_FDS")
+        .context("LISTEN_FDS is not set")?
+        .parse()
+        .context("Invalid LISTEN_FDS")?;
+    if index >= fds {
+        bail!(
+            "Systemd socket index {} out of range ({} sockets passed)",
+            index,
+            fds
+        );
+    }
+    let fd = SD_LISTEN_FDS_START + index as RawFd;
+
+    // Determine the socket family
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    // Safety: `storage` is large enough for any socket address and `len`
+    // is initialized with its size.
+    let res =
+        unsafe { libc::getsockname(fd, &mut storage as *mut _ as *mut libc::sockaddr, &mut len) };
+    if res != 0 {
+        return Err(anyhow!(std::io::Error::last_os_error()))
+            .context(format!("File descriptor {} is not a socket", fd));
+    }
+
+    // Safety: systemd passes ownership of the listening sockets to us, and
+    // each descriptor is only taken over once.
+    let listener = match i32::from(storage.ss_family) {
+        libc::AF_UNIX => {
+            SystemdListener::Unix(unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) })
+        }
+        libc::AF_INET | libc::AF_INET6 => {
+            SystemdListener::Tcp(unsafe { std::net::TcpListener::from_raw_fd(fd) })
+        }
+        family => bail!("Unsupported socket family {} on fd {}", family, fd),
+    };
+    match listener {
+        SystemdListener::Tcp(ref l) => l.set_nonblocking(true)?,
+        SystemdListener::Unix(ref l) => l.set_nonblocking(true)?,
+    }
+    Ok(listener)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_listen_addr_tcp() {
+        assert_eq!(
+            "127.0.0.1:3000".parse(),
+            Ok(ListenAddr::Tcp(([127, 0, 0, 1], 3000).into()))
+        );
+        assert!("[::1]:3000".parse::<ListenAddr>().is_ok());
+        assert!("localhost".parse::<ListenAddr>().is_err());
+    }
+
+    #[test]
+    fn parse_listen_addr_unix() {
+        assert_eq!(
+            "unix:/run/sekursranko.sock".parse(),
+            Ok(ListenAddr::Unix(PathBuf::from("/run/sekursranko.sock")))
+        );
+        assert!("unix:".parse::<ListenAddr>().is_err());
+    }
+
+    #[test]
+    fn parse_listen_addr_systemd() {
+        assert_eq!("systemd".parse(), Ok(ListenAddr::Systemd(0)));
+        assert_eq!("systemd:2".parse(), Ok(ListenAddr::Systemd(2)));
+        assert!("systemd:x".parse::<ListenAddr>().is_err());
+    }
+
+    #[test]
+    fn listen_addr_display_roundtrip() {
+        for s in &["127.0.0.1:3000", "unix:/tmp/a.sock", "systemd:1"] {
+            assert_eq!(s.parse::<ListenAddr>().unwrap().to_string(), *s);
+        }
+    }
+
+    #[test]
+    fn parse_owner_numeric() {
+        assert_eq!(parse_owner("1000").unwrap(), (Some(1000), None));
+        assert_eq!(parse_owner("1000:33").unwrap(), (Some(1000), Some(33)));
+        assert_eq!(parse_owner(":33").unwrap(), (None, Some(33)));
+        assert_eq!(parse_owner("root:0").unwrap(), (Some(0), Some(0)));
+        assert!(parse_owner("no-such-user-sekursranko").is_err());
+    }
+
+    #[tokio::test]
+    async fn bind_unix_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sekursranko.sock");
+
+        // The socket gets the configured mode
+        let config = ListenerConfig {
+            unix_socket_mode: Some(0o600),
+            ..Default::default()
+        };
+        let listener = bind_unix(&path, &config).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        drop(listener);
+
+        // Nothing is left behind if the owner can't be set
+        let config = ListenerConfig {
+            unix_socket_owner: Some("no-such-user-sekursranko".into()),
+            ..Default::default()
+        };
+        assert!(bind_unix(&path, &config).is_err());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
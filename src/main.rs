@@ -1,10 +1,18 @@
-use std::path::PathBuf;
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use clap::{self, Parser};
-use hyper::Server;
+use anyhow::Context;
+use clap::{self, Parser, Subcommand};
 use log::error;
 
-use sekursranko::{MakeBackupService, ServerConfig};
+use sekursranko::{ServerBuilder, ServerConfig};
+
+/// The environment variable with the Safe password for `inspect`.
+const SAFE_PASSWORD_ENV: &str = "SEKURSRANKO_SAFE_PASSWORD";
 
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
@@ -12,6 +20,113 @@ struct Cli {
     /// Path to the config file
     #[arg(short, long)]
     config: PathBuf,
+
+    /// Run admin commands against the backups of this tenant
+    #[arg(short, long, global = true)]
+    tenant: Option<String>,
+
+    /// The command to run (default: run the server)
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Push all local backups to a replication peer
+    Resync {
+        /// The name of the peer
+        peer: String,
+    },
+    /// Re-encrypt all backups with the configured key
+    Reencrypt,
+    /// Manage the retained prior versions of a backup
+    Versions {
+        #[command(subcommand)]
+        command: VersionsCommand,
+    },
+    /// Restore a deleted backup from the trash
+    Restore {
+        /// The backup ID
+        backup_id: String,
+    },
+    /// Delete expired backups, prune prior versions and purge the trash
+    Sweep,
+    /// Manage the owners of backups (if authentication is enabled)
+    Owners {
+        #[command(subcommand)]
+        command: OwnersCommand,
+    },
+    /// Write all backups with their metadata to a tar archive
+    Export {
+        /// The output file ("-" for stdout)
+        output: PathBuf,
+    },
+    /// Restore backups from an archive created by `export`
+    Import {
+        /// The input file ("-" for stdin)
+        input: PathBuf,
+        /// Replace existing backups
+        #[arg(long)]
+        overwrite: bool,
+    },
+    /// Print the OpenAPI description of the Safe API as JSON
+    Openapi,
+    /// Derive the backup ID from a Threema ID and Safe password, then
+    /// decrypt the backup and print a summary of its contents
+    ///
+    /// The password is read from SEKURSRANKO_SAFE_PASSWORD or prompted for.
+    Inspect {
+        /// The Threema ID
+        identity: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum VersionsCommand {
+    /// List the prior versions of a backup
+    List {
+        /// The backup ID
+        backup_id: String,
+    },
+    /// Write a prior version of a backup to a file (or stdout)
+    Download {
+        /// The backup ID
+        backup_id: String,
+        /// The version ID (see `versions list`)
+        version: String,
+        /// The output file
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Make a prior version the current version of a backup
+    Promote {
+        /// The backup ID
+        backup_id: String,
+        /// The version ID (see `versions list`)
+        version: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum OwnersCommand {
+    /// List the backups with their owners, sizes, upload and expiry times
+    List {
+        /// Only list the backups of this user
+        #[arg(short, long)]
+        user: Option<String>,
+    },
+    /// Assign a backup to another user
+    Assign {
+        /// The backup ID
+        backup_id: String,
+        /// The new owner
+        user: String,
+    },
+    /// Remove the owner of a backup, making it accessible to all users
+    Clear {
+        /// The backup ID
+        backup_id: String,
+    },
 }
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 2)]
@@ -26,23 +141,269 @@ async fn main() {
         eprintln!("Could not load config file: {}", e);
         ::std::process::exit(1);
     });
-    let addr: ::std::net::SocketAddr = config.listen_on.parse().unwrap_or_else(|e| {
-        eprintln!("Invalid listening address: {}", e);
+    let config = match cli.tenant {
+        Some(ref tenant) if cli.command.is_some() => config
+            .for_tenant(tenant)
+            .unwrap_or_else(|e| exit_with_error(anyhow::anyhow!(e))),
+        Some(_) => exit_with_error(anyhow::anyhow!("--tenant requires a command")),
+        None => config,
+    };
+
+    match cli.command {
+        None => run_server(config).await,
+        Some(Command::Resync { peer }) => {
+            let stats = sekursranko::admin::resync(&config, &peer)
+                .await
+                .unwrap_or_else(|e| exit_with_error(e));
+            println!("Pushed {} backups ({} failed)", stats.pushed, stats.failed);
+            if stats.failed > 0 {
+                std::process::exit(1);
+            }
+        }
+        Some(Command::Reencrypt) => {
+            let stats = sekursranko::admin::reencrypt(&config)
+                .await
+                .unwrap_or_else(|e| exit_with_error(e));
+            println!(
+                "Re-encrypted {} backups ({} already up to date, {} failed)",
+                stats.reencrypted, stats.skipped, stats.failed
+            );
+            if stats.failed > 0 {
+                std::process::exit(1);
+            }
+        }
+        Some(Command::Versions { command }) => run_versions_command(&config, command)
+            .await
+            .unwrap_or_else(|e| exit_with_error(e)),
+        Some(Command::Restore { backup_id }) => {
+            sekursranko::admin::restore(&config, &backup_id)
+                .await
+                .unwrap_or_else(|e| exit_with_error(e));
+            println!("Restored backup {}", backup_id);
+        }
+        Some(Command::Sweep) => {
+            let stats = sekursranko::admin::sweep(&config)
+                .await
+                .unwrap_or_else(|e| exit_with_error(e));
+            println!(
+                "Deleted {} expired backups and {} prior versions, purged {} backups from the trash",
+                stats.expired, stats.pruned_versions, stats.purged_trash
+            );
+        }
+        Some(Command::Owners { command }) => run_owners_command(&config, command)
+            .await
+            .unwrap_or_else(|e| exit_with_error(e)),
+        Some(Command::Export { output }) => {
+            let stats = export(&config, &output)
+                .await
+                .unwrap_or_else(|e| exit_with_error(e));
+            eprintln!("Exported {} backups", stats.exported);
+        }
+        Some(Command::Import { input, overwrite }) => {
+            let stats = import(&config, &input, overwrite)
+                .await
+                .unwrap_or_else(|e| exit_with_error(e));
+            println!(
+                "Imported {} backups ({} skipped, {} failed)",
+                stats.imported, stats.skipped, stats.failed
+            );
+            if stats.failed > 0 {
+                std::process::exit(1);
+            }
+        }
+        Some(Command::Inspect { identity }) => inspect(&config, &identity)
+            .await
+            .unwrap_or_else(|e| exit_with_error(e)),
+        Some(Command::Openapi) => {
+            let document = sekursranko::openapi::document(&config);
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&document).expect("Could not serialize document")
+            );
+        }
+    }
+}
+
+async fn export(
+    config: &ServerConfig,
+    output: &Path,
+) -> anyhow::Result<sekursranko::admin::ExportStats> {
+    if output == Path::new("-") {
+        return sekursranko::admin::export(config, std::io::stdout().lock()).await;
+    }
+    let file = File::create(output).with_context(|| format!("Could not create {:?}", output))?;
+    sekursranko::admin::export(config, BufWriter::new(file)).await
+}
+
+async fn import(
+    config: &ServerConfig,
+    input: &Path,
+    overwrite: bool,
+) -> anyhow::Result<sekursranko::admin::ImportStats> {
+    if input == Path::new("-") {
+        return sekursranko::admin::import(config, std::io::stdin().lock(), overwrite).await;
+    }
+    let file = File::open(input).with_context(|| format!("Could not open {:?}", input))?;
+    sekursranko::admin::import(config, BufReader::new(file), overwrite).await
+}
+
+async fn inspect(config: &ServerConfig, identity: &str) -> anyhow::Result<()> {
+    let password = match std::env::var(SAFE_PASSWORD_ENV) {
+        Ok(password) => password,
+        Err(_) => read_password("Safe password: ")?,
+    };
+    let inspection = sekursranko::admin::inspect(config, identity, &password).await?;
+    let summary = &inspection.summary;
+    let or_unknown = |value: Option<&str>| value.unwrap_or("-").to_string();
+    println!("Backup ID: {}", inspection.backup_id);
+    println!("Stored at: {} (Unix time)", unix_time(inspection.stored_at));
+    println!("Expires at: {}", expiry(inspection.expires_at));
+    println!("Size: {} bytes", inspection.size);
+    println!(
+        "Format version: {}",
+        or_unknown(summary.version.map(|v| v.to_string()).as_deref())
+    );
+    println!("Device: {}", or_unknown(summary.device.as_deref()));
+    println!("Nickname: {}", or_unknown(summary.nickname.as_deref()));
+    println!(
+        "Private key: {}",
+        if summary.has_private_key {
+            "present"
+        } else {
+            "missing"
+        }
+    );
+    println!("Contacts: {}", summary.contacts);
+    println!("Groups: {}", summary.groups);
+    println!("Distribution lists: {}", summary.distribution_lists);
+    println!("Settings: {}", summary.settings);
+    Ok(())
+}
+
+/// Read a password from stdin, without echoing it if stdin is a terminal.
+fn read_password(prompt: &str) -> anyhow::Result<String> {
+    eprint!("{}", prompt);
+    let fd = libc::STDIN_FILENO;
+    let mut original: libc::termios = unsafe { std::mem::zeroed() };
+    let is_terminal = unsafe { libc::isatty(fd) == 1 && libc::tcgetattr(fd, &mut original) == 0 };
+    if is_terminal {
+        let mut silent = original;
+        silent.c_lflag &= !libc::ECHO;
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &silent) };
+    }
+    let mut password = String::new();
+    let result = std::io::stdin().read_line(&mut password);
+    if is_terminal {
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &original) };
+        eprintln!();
+    }
+    result.context("Could not read password")?;
+    Ok(password.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+async fn run_versions_command(
+    config: &ServerConfig,
+    command: VersionsCommand,
+) -> anyhow::Result<()> {
+    match command {
+        VersionsCommand::List { backup_id } => {
+            let versions = sekursranko::admin::list_versions(config, &backup_id).await?;
+            if versions.is_empty() {
+                println!("No prior versions");
+            }
+            for version in versions {
+                println!(
+                    "{}\tstored at {} (Unix time)\t{} bytes",
+                    version.id,
+                    unix_time(version.stored_at),
+                    version.size
+                );
+            }
+        }
+        VersionsCommand::Download {
+            backup_id,
+            version,
+            output,
+        } => {
+            let data = sekursranko::admin::download_version(config, &backup_id, &version).await?;
+            match output {
+                Some(path) => std::fs::write(path, data)?,
+                None => std::io::stdout().write_all(&data)?,
+            }
+        }
+        VersionsCommand::Promote { backup_id, version } => {
+            sekursranko::admin::promote_version(config, &backup_id, &version).await?;
+            println!("Promoted version {} of backup {}", version, backup_id);
+        }
+    }
+    Ok(())
+}
+
+async fn run_owners_command(config: &ServerConfig, command: OwnersCommand) -> anyhow::Result<()> {
+    match command {
+        OwnersCommand::List { user } => {
+            let backups = sekursranko::admin::list_owners(config, user.as_deref()).await?;
+            if backups.is_empty() {
+                println!("No backups");
+            }
+            for backup in backups {
+                println!(
+                    "{}\t{}\t{} bytes\tstored at {} (Unix time)\texpires at {}",
+                    backup.backup_id,
+                    backup.owner.as_deref().unwrap_or("-"),
+                    backup.size,
+                    unix_time(backup.stored_at),
+                    expiry(backup.expires_at)
+                );
+            }
+        }
+        OwnersCommand::Assign { backup_id, user } => {
+            sekursranko::admin::assign_owner(config, &backup_id, Some(&user)).await?;
+            println!("Assigned backup {} to {}", backup_id, user);
+        }
+        OwnersCommand::Clear { backup_id } => {
+            sekursranko::admin::assign_owner(config, &backup_id, None).await?;
+            println!("Removed the owner of backup {}", backup_id);
+        }
+    }
+    Ok(())
+}
+
+/// Return the seconds since the Unix epoch.
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// Format an expiry time (`None` if backups never expire).
+fn expiry(expires_at: Option<SystemTime>) -> String {
+    match expires_at {
+        Some(time) => format!("{} (Unix time)", unix_time(time)),
+        None => "never".to_string(),
+    }
+}
+
+fn exit_with_error(e: anyhow::Error) -> ! {
+    eprintln!("Error: {:#}", e);
+    std::process::exit(1);
+}
+
+async fn run_server(config: ServerConfig) {
+    if config.listeners().is_empty() {
+        eprintln!("No listeners configured");
         ::std::process::exit(1);
-    });
+    }
     println!(
         "Starting {} server with the following configuration:\n\n{}",
         sekursranko::NAME,
         &config
     );
 
-    // Create server
-    let service = MakeBackupService::new(config);
-    let server = Server::bind(&addr).serve(service);
-
-    // Serve
+    // Serve all listeners, sharing the same config and state
+    let server = ServerBuilder::new(config)
+        .start()
+        .unwrap_or_else(|e| exit_with_error(e));
     if let Err(e) = server.await {
-        error!("Server error: {}", e);
+        error!("Server error: {:#}", e);
         std::process::exit(1);
     };
 }
@@ -0,0 +1,115 @@
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Counters shared between all listeners.
+///
+/// The counters can be queried in the Prometheus text format on the
+/// `/metrics` route.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Requests rejected by the user agent policy
+    pub user_agent_rejected: AtomicU64,
+    /// Requests with missing or invalid credentials
+    pub auth_failed: AtomicU64,
+    /// Changes successfully pushed to replication peers
+    pub replication_pushed: AtomicU64,
+    /// Failed attempts to push changes to replication peers
+    pub replication_failed: AtomicU64,
+    /// Backup downloads served locally while migration is enabled
+    pub migration_hits: AtomicU64,
+    /// Backup downloads not found locally while migration is enabled
+    pub migration_misses: AtomicU64,
+    /// Backups fetched from the migration upstream
+    pub migration_migrated: AtomicU64,
+    /// Webhook events delivered
+    pub webhook_delivered: AtomicU64,
+    /// Webhook events not delivered after all attempts
+    pub webhook_failed: AtomicU64,
+    /// Webhook events dropped because the queue was full
+    pub webhook_dropped: AtomicU64,
+}
+
+impl Metrics {
+    /// Increment a counter by one.
+    pub fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Render all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut counter = |name: &str, help: &str, value: &AtomicU64| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+        };
+        counter(
+            "sekursranko_user_agent_rejected_total",
+            "Requests rejected by the user agent policy.",
+            &self.user_agent_rejected,
+        );
+        counter(
+            "sekursranko_auth_failed_total",
+            "Requests with missing or invalid credentials.",
+            &self.auth_failed,
+        );
+        counter(
+            "sekursranko_replication_pushed_total",
+            "Changes pushed to replication peers.",
+            &self.replication_pushed,
+        );
+        counter(
+            "sekursranko_replication_failed_total",
+            "Failed attempts to push changes to replication peers.",
+            &self.replication_failed,
+        );
+        counter(
+            "sekursranko_migration_hits_total",
+            "Backup downloads served from local storage while migration is enabled.",
+            &self.migration_hits,
+        );
+        counter(
+            "sekursranko_migration_misses_total",
+            "Backup downloads not found in local storage while migration is enabled.",
+            &self.migration_misses,
+        );
+        counter(
+            "sekursranko_migration_migrated_total",
+            "Backups migrated from the upstream server.",
+            &self.migration_migrated,
+        );
+        counter(
+            "sekursranko_webhook_delivered_total",
+            "Webhook events delivered.",
+            &self.webhook_delivered,
+        );
+        counter(
+            "sekursranko_webhook_failed_total",
+            "Webhook events not delivered after all attempts.",
+            &self.webhook_failed,
+        );
+        counter(
+            "sekursranko_webhook_dropped_total",
+            "Webhook events dropped because the delivery queue was full.",
+            &self.webhook_dropped,
+        );
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_counter() {
+        let metrics = Metrics::default();
+        Metrics::inc(&metrics.user_agent_rejected);
+        Metrics::inc(&metrics.user_agent_rejected);
+        let rendered = metrics.render();
+        assert!(rendered.contains("# TYPE sekursranko_user_agent_rejected_total counter\n"));
+        assert!(rendered.contains("\nsekursranko_user_agent_rejected_total 2\n"));
+    }
+}
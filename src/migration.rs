@@ -0,0 +1,237 @@
+//! Read-through migration from a legacy Threema Safe server.
+//!
+//! If a requested backup does not exist locally, it is fetched from the
+//! upstream server with the same backup ID, stored locally (keeping the
+//! upstream `Last-Modified` time as upload time) and then served.
+//! Deletions are forwarded to the upstream server first, so that a deleted
+//! backup is never migrated again.
+//!
+//! Once all users have been migrated, the mode can be switched off with
+//! `enabled = false` (or by removing the `[migration]` section).
+
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use futures::StreamExt;
+use log::debug;
+use reqwest::{header, StatusCode};
+use serde_derive::Deserialize;
+
+use crate::{privacy::LogPrivacy, storage::Storage};
+
+/// The read-through migration configuration.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct MigrationConfig {
+    /// The base URL of the legacy server (e.g. "https://safe.example.com")
+    pub upstream_url: String,
+    /// Whether missing backups are fetched from the upstream server
+    /// (default: true)
+    pub enabled: Option<bool>,
+    /// The user agent sent to the upstream server (must be accepted by its
+    /// user agent policy)
+    pub user_agent: Option<String>,
+    /// The timeout of requests to the upstream server in seconds
+    /// (default: 30)
+    pub timeout_secs: Option<u64>,
+}
+
+impl MigrationConfig {
+    pub fn enabled(&self) -> bool {
+        self.enabled.unwrap_or(true)
+    }
+
+    fn user_agent(&self) -> String {
+        self.user_agent.clone().unwrap_or_else(|| {
+            format!(
+                "Threema (compatible; sekursranko/{}; migration)",
+                crate::VERSION
+            )
+        })
+    }
+
+    fn backup_url(&self, backup_id: &str) -> String {
+        format!(
+            "{}/backups/{}",
+            self.upstream_url.trim_end_matches('/'),
+            backup_id
+        )
+    }
+}
+
+/// Migrates backups from the upstream server on demand.
+#[derive(Debug)]
+pub struct Migrator {
+    config: MigrationConfig,
+    client: reqwest::Client,
+    log_privacy: LogPrivacy,
+}
+
+impl Migrator {
+    pub fn new(config: &MigrationConfig, log_privacy: LogPrivacy) -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.timeout_secs.unwrap_or(30)))
+            .build()
+            .context("Could not create HTTP client for migration")?;
+        Ok(Self {
+            config: config.clone(),
+            client,
+            log_privacy,
+        })
+    }
+
+    /// Fetch a backup from the upstream server and store it locally.
+    ///
+    /// Return the size of the migrated backup, or `None` if the upstream
+    /// server does not have the backup (or it was stored locally in the
+    /// meantime).
+    pub async fn migrate(
+        &self,
+        storage: &Storage,
+        backup_id: &str,
+        max_bytes: u64,
+    ) -> anyhow::Result<Option<u64>> {
+        debug!(
+            "Fetching backup {} from upstream",
+            self.log_privacy.id(backup_id)
+        );
+        let response = self
+            .client
+            .get(self.config.backup_url(backup_id))
+            .header(header::USER_AGENT, self.config.user_agent())
+            .header(header::ACCEPT, "application/octet-stream")
+            .send()
+            .await
+            // The URL contains the backup ID, which must not end up in the logs
+            .map_err(reqwest::Error::without_url)
+            .context("Request to upstream failed")?;
+        match response.status() {
+            StatusCode::OK => {}
+            StatusCode::NOT_FOUND => return Ok(None),
+            status => bail!("Upstream responded with status {}", status),
+        }
+        if response.content_length().is_some_and(|len| len > max_bytes) {
+            bail!("Upstream backup is too large");
+        }
+        let stored_at = response
+            .headers()
+            .get(header::LAST_MODIFIED)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| httpdate::parse_http_date(value).ok());
+
+        // The content length may be missing or wrong, so the limit is also
+        // enforced while reading the body.
+        let mut data = Vec::new();
+        let mut body = response.bytes_stream();
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk
+                .map_err(reqwest::Error::without_url)
+                .context("Could not read upstream response")?;
+            if (data.len() + chunk.len()) as u64 > max_bytes {
+                bail!("Upstream backup is too large");
+            }
+            data.extend_from_slice(&chunk);
+        }
+
+        // Never replace a backup that was uploaded while fetching
+        if storage.path(backup_id).exists() {
+            return Ok(None);
+        }
+        storage.write_data(backup_id, &data).await?;
+        if let Some(stored_at) = stored_at {
+            storage
+                .set_stored_at(backup_id, stored_at)
+                .await
+                .context("Could not set the upload time of the migrated backup")?;
+        }
+        Ok(Some(data.len() as u64))
+    }
+
+    /// Delete a backup on the upstream server.
+    ///
+    /// Return whether the backup existed on the upstream server.
+    pub async fn delete(&self, backup_id: &str) -> anyhow::Result<bool> {
+        debug!(
+            "Deleting backup {} on upstream",
+            self.log_privacy.id(backup_id)
+        );
+        let response = self
+            .client
+            .delete(self.config.backup_url(backup_id))
+            .header(header::USER_AGENT, self.config.user_agent())
+            .send()
+            .await
+            .map_err(reqwest::Error::without_url)
+            .context("Request to upstream failed")?;
+        match response.status() {
+            status if status.is_success() => Ok(true),
+            StatusCode::NOT_FOUND => Ok(false),
+            status => bail!("Upstream responded with status {}", status),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    const BACKUP_ID: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    /// Start an upstream server that sends every backup in two chunks of 8
+    /// bytes, without a content length.
+    fn chunked_upstream(last_modified: SystemTime) -> String {
+        use hyper::{
+            service::{make_service_fn, service_fn},
+            Body, Response, Server,
+        };
+
+        let make_service = make_service_fn(move |_| async move {
+            Ok::<_, hyper::Error>(service_fn(move |_| async move {
+                let chunks = vec![Ok::<_, std::io::Error>(vec![1; 8]), Ok(vec![2; 8])];
+                Response::builder()
+                    .header(
+                        header::LAST_MODIFIED,
+                        httpdate::fmt_http_date(last_modified),
+                    )
+                    .body(Body::wrap_stream(futures::stream::iter(chunks)))
+            }))
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        url
+    }
+
+    fn migrator(upstream_url: String) -> Migrator {
+        let config = MigrationConfig {
+            upstream_url,
+            enabled: None,
+            user_agent: None,
+            timeout_secs: None,
+        };
+        Migrator::new(&config, LogPrivacy::default()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn limit_without_content_length() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Storage::new(dir.path().to_path_buf(), None);
+        let migrator = migrator(chunked_upstream(SystemTime::now()));
+        let err = migrator.migrate(&storage, BACKUP_ID, 10).await.unwrap_err();
+        assert_eq!(err.to_string(), "Upstream backup is too large");
+        assert!(!storage.path(BACKUP_ID).exists());
+    }
+
+    #[tokio::test]
+    async fn keep_upstream_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Storage::new(dir.path().to_path_buf(), None);
+        let last_modified = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let migrator = migrator(chunked_upstream(last_modified));
+        let size = migrator.migrate(&storage, BACKUP_ID, 16).await.unwrap();
+        assert_eq!(size, Some(16));
+        assert_eq!(storage.read(BACKUP_ID).await.unwrap().len(), 16);
+        assert_eq!(storage.stored_at(BACKUP_ID).await.unwrap(), last_modified);
+    }
+}
@@ -0,0 +1,438 @@
+//! OpenAPI description of the Safe API.
+//!
+//! The document is generated from the Safe API operations and their error
+//! table (which the handlers are checked against), so that it always matches
+//! the running implementation. It is served on `/openapi.json` (route group
+//! `openapi`) and printed by the `openapi` command.
+
+use std::collections::BTreeMap;
+
+use hyper::Method;
+use serde_json::{json, Map, Value};
+
+use crate::{
+    config::ServerConfig,
+    errors::ApiError,
+    handlers::{BACKUP_MEDIA_TYPE, CONFIG_MEDIA_TYPE, EXPIRES_HEADER},
+    routing::{Features, Operation},
+};
+
+/// The OpenAPI version of the generated document.
+const OPENAPI_VERSION: &str = "3.0.3";
+
+/// Generate the OpenAPI document for the Safe API of a server.
+///
+/// Optional features of the config (e.g. authentication or migration) are
+/// reflected in the documented operations, security schemes and error
+/// responses.
+pub fn document(config: &ServerConfig) -> Value {
+    let features = Features::new(config);
+    let mut paths = Map::new();
+    for operation in Operation::ALL {
+        if operation.is_preflight() && !features.cors {
+            continue;
+        }
+        let path = paths
+            .entry(openapi_path(operation.route().path()))
+            .or_insert_with(|| json!({}));
+        path[operation.method().as_str().to_lowercase()] = describe(*operation, config, &features);
+    }
+
+    let mut components = json!({
+        "schemas": {
+            "ServerConfig": {
+                "type": "object",
+                "required": ["maxBackupBytes", "retentionDays"],
+                "properties": {
+                    "maxBackupBytes": {
+                        "type": "integer",
+                        "description": "The max file size for backups",
+                        "example": config.max_backup_bytes,
+                    },
+                    "retentionDays": {
+                        "type": "integer",
+                        "description": "The number of days a backup will be retained",
+                        "example": config.retention_days,
+                    },
+                },
+            },
+        },
+    });
+    if config.auth.is_some() {
+        components["securitySchemes"] = json!({
+            "basicAuth": { "type": "http", "scheme": "basic" },
+        });
+    }
+
+    json!({
+        "openapi": OPENAPI_VERSION,
+        "info": {
+            "title": format!("{} Safe API", crate::NAME),
+            "version": crate::VERSION,
+            "description": "The Threema Safe backup API.",
+        },
+        "paths": paths,
+        "components": components,
+    })
+}
+
+/// Convert a router path pattern (e.g. `/backups/:backupId`) to an OpenAPI
+/// path template (e.g. `/backups/{backupId}`).
+fn openapi_path(pattern: &str) -> String {
+    pattern
+        .split('/')
+        .map(|segment| match segment.strip_prefix(':') {
+            Some(param) => format!("{{{}}}", param),
+            None => segment.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Describe a single operation of the Safe API.
+fn describe(op: Operation, config: &ServerConfig, features: &Features) -> Value {
+    let mut parameters = vec![];
+    if !op.is_preflight() {
+        parameters.push(json!({
+            "name": "User-Agent",
+            "in": "header",
+            "required": true,
+            "description": "The client user agent (e.g. `Threema/4.62A`), checked against the user agent policy",
+            "schema": { "type": "string" },
+        }));
+    }
+    let method = op.method();
+    let mut responses = Map::new();
+    let mut operation = Map::new();
+    match op {
+        Operation::GetConfig => {
+            operation.insert("operationId".into(), json!("getConfig"));
+            operation.insert("summary".into(), json!("Get the server limits"));
+            operation.insert("description".into(), accept_description(CONFIG_MEDIA_TYPE));
+            responses.insert(
+                "200".into(),
+                json!({
+                    "description": "The server limits",
+                    "content": {
+                        CONFIG_MEDIA_TYPE: {
+                            "schema": { "$ref": "#/components/schemas/ServerConfig" },
+                        },
+                    },
+                }),
+            );
+        }
+        Operation::GetBackup | Operation::HeadBackup => {
+            let head = method == Method::HEAD;
+            operation.insert(
+                "operationId".into(),
+                json!(if head { "headBackup" } else { "getBackup" }),
+            );
+            operation.insert(
+                "summary".into(),
+                json!(if head {
+                    "Check whether a backup exists"
+                } else {
+                    "Download a backup"
+                }),
+            );
+            parameters.push(backup_id_param());
+            operation.insert("description".into(), accept_description(BACKUP_MEDIA_TYPE));
+            let mut ok = json!({
+                "description": "The backup",
+                "headers": {
+                    "Content-Length": {
+                        "description": "The size of the backup in bytes",
+                        "schema": { "type": "integer" },
+                    },
+                    "Last-Modified": {
+                        "description": "The time of the last upload",
+                        "schema": { "type": "string" },
+                    },
+                },
+            });
+            if config.retention_days > 0 {
+                ok["headers"][EXPIRES_HEADER] = json!({
+                    "description": "The time the backup expires unless it is uploaded again",
+                    "schema": { "type": "string" },
+                });
+            }
+            if !head {
+                ok["content"] = json!({
+                    BACKUP_MEDIA_TYPE: { "schema": { "type": "string", "format": "binary" } },
+                });
+            }
+            responses.insert("200".into(), ok);
+        }
+        Operation::PutBackup => {
+            operation.insert("operationId".into(), json!("putBackup"));
+            operation.insert("summary".into(), json!("Upload a backup"));
+            parameters.push(backup_id_param());
+            parameters.push(json!({
+                "name": "Content-Length",
+                "in": "header",
+                "required": true,
+                "schema": { "type": "integer", "maximum": config.max_backup_bytes },
+            }));
+            operation.insert(
+                "requestBody".into(),
+                json!({
+                    "required": true,
+                    "content": {
+                        BACKUP_MEDIA_TYPE: {
+                            "schema": {
+                                "type": "string",
+                                "format": "binary",
+                                "maxLength": config.max_backup_bytes,
+                            },
+                        },
+                    },
+                }),
+            );
+            responses.insert("201".into(), json!({ "description": "Backup created" }));
+            responses.insert("204".into(), json!({ "description": "Backup updated" }));
+        }
+        Operation::DeleteBackup => {
+            operation.insert("operationId".into(), json!("deleteBackup"));
+            operation.insert("summary".into(), json!("Delete a backup"));
+            parameters.push(backup_id_param());
+            responses.insert("204".into(), json!({ "description": "Backup deleted" }));
+        }
+        Operation::PreflightConfig | Operation::PreflightBackup => {
+            let backup = op == Operation::PreflightBackup;
+            operation.insert(
+                "operationId".into(),
+                json!(if backup {
+                    "preflightBackup"
+                } else {
+                    "preflightConfig"
+                }),
+            );
+            operation.insert("summary".into(), json!("CORS preflight request"));
+            if backup {
+                parameters.push(backup_id_param());
+            }
+            parameters.push(json!({
+                "name": "Access-Control-Request-Method",
+                "in": "header",
+                "required": true,
+                "schema": { "type": "string" },
+            }));
+            responses.insert(
+                "204".into(),
+                json!({ "description": "The request is allowed (see the `Access-Control-Allow-*` headers)" }),
+            );
+        }
+    }
+
+    // Group the possible errors by status code
+    let mut errors: BTreeMap<u16, Vec<ApiError>> = BTreeMap::new();
+    for error in op.errors(features) {
+        errors
+            .entry(error.status().as_u16())
+            .or_default()
+            .push(error);
+    }
+    for (status, errors) in errors {
+        responses.insert(status.to_string(), error_response(&errors, &method));
+    }
+
+    operation.insert("parameters".into(), Value::Array(parameters));
+    operation.insert("responses".into(), Value::Object(responses));
+    if features.basic_auth && !op.is_preflight() {
+        operation.insert("security".into(), json!([{ "basicAuth": [] }]));
+    }
+    Value::Object(operation)
+}
+
+/// Describe the error response for errors with the same status code.
+fn error_response(errors: &[ApiError], method: &Method) -> Value {
+    let description = errors
+        .iter()
+        .map(|e| e.detail())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let mut response = json!({ "description": description });
+    if errors.contains(&ApiError::Unauthorized) {
+        response["headers"] = json!({
+            "WWW-Authenticate": {
+                "description": "The Basic authentication challenge",
+                "schema": { "type": "string" },
+            },
+        });
+    }
+    // Responses to HEAD requests never have a body
+    if method != Method::HEAD {
+        let codes: Vec<_> = errors.iter().map(|e| e.code()).collect();
+        response["content"] = json!({
+            "application/json": {
+                "schema": {
+                    "type": "object",
+                    "required": ["code", "detail"],
+                    "properties": {
+                        "code": { "type": "string", "enum": codes },
+                        "detail": { "type": "string" },
+                    },
+                },
+            },
+        });
+    }
+    response
+}
+
+fn backup_id_param() -> Value {
+    json!({
+        "name": "backupId",
+        "in": "path",
+        "required": true,
+        "description": "The backup ID (64 lowercase hex characters)",
+        "schema": { "type": "string", "pattern": "^[0-9a-f]{64}$" },
+    })
+}
+
+/// Describe the required `Accept` header.
+///
+/// OpenAPI ignores `Accept` header parameters, so this is only documented in
+/// the operation description.
+fn accept_description(media_type: &str) -> Value {
+    json!(format!("Requires the `Accept: {}` header.", media_type))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    use crate::{
+        auth::AuthConfig,
+        config::{ClientIdentity, ListenerConfig, TlsConfig},
+    };
+
+    #[test]
+    fn path_template() {
+        assert_eq!(openapi_path("/config"), "/config");
+        assert_eq!(openapi_path("/backups/:backupId"), "/backups/{backupId}");
+    }
+
+    #[test]
+    fn safe_api_operations() {
+        let config = ServerConfig::default();
+        let document = document(&config);
+        assert_eq!(document["openapi"], OPENAPI_VERSION);
+        let paths = document["paths"].as_object().unwrap();
+        assert_eq!(paths.len(), 2);
+        assert!(paths["/config"]["get"].is_object());
+        for method in &["get", "head", "put", "delete"] {
+            assert!(
+                paths["/backups/{backupId}"][method].is_object(),
+                "{}",
+                method
+            );
+        }
+        let put = &paths["/backups/{backupId}"]["put"];
+        assert_eq!(
+            put["requestBody"]["content"][BACKUP_MEDIA_TYPE]["schema"]["maxLength"],
+            config.max_backup_bytes
+        );
+        assert_eq!(
+            put["responses"]["400"]["content"]["application/json"]["schema"]["properties"]["code"]
+                ["enum"],
+            json!([
+                "invalid_user_agent",
+                "invalid_content_type_header",
+                "invalid_content_length_header",
+                "invalid_backup_id"
+            ])
+        );
+        assert!(put["responses"]["413"].is_object());
+        assert!(put.get("security").is_none());
+        let head = &paths["/backups/{backupId}"]["head"];
+        assert!(head["responses"]["200"]["headers"]["Last-Modified"].is_object());
+        assert!(head["responses"]["200"]["headers"]
+            .get(EXPIRES_HEADER)
+            .is_none());
+        let document = super::document(&ServerConfig {
+            retention_days: 180,
+            ..Default::default()
+        });
+        let get = &document["paths"]["/backups/{backupId}"]["get"];
+        assert!(get["responses"]["200"]["headers"][EXPIRES_HEADER].is_object());
+        assert!(document["components"].get("securitySchemes").is_none());
+    }
+
+    #[test]
+    fn basic_auth() {
+        let config = ServerConfig {
+            auth: Some(AuthConfig {
+                htpasswd_file: PathBuf::from("htpasswd"),
+                realm: None,
+                reload_interval_secs: None,
+                quota: Default::default(),
+                user_quotas: Default::default(),
+            }),
+            ..Default::default()
+        };
+        let document = document(&config);
+        let get = &document["paths"]["/backups/{backupId}"]["get"];
+        assert_eq!(get["security"], json!([{ "basicAuth": [] }]));
+        assert!(get["responses"]["401"]["headers"]["WWW-Authenticate"].is_object());
+        assert_eq!(
+            document["components"]["securitySchemes"]["basicAuth"]["scheme"],
+            "basic"
+        );
+        let put = &document["paths"]["/backups/{backupId}"]["put"];
+        assert!(
+            put["responses"]["403"]["content"]["application/json"]["schema"]["properties"]["code"]
+                ["enum"]
+                .as_array()
+                .unwrap()
+                .contains(&json!("quota_exceeded"))
+        );
+    }
+
+    #[test]
+    fn cors_preflight() {
+        let document = document(&ServerConfig::default());
+        assert!(document["paths"]["/config"].get("options").is_none());
+        let document = super::document(&ServerConfig {
+            allow_browser: Some(true),
+            ..Default::default()
+        });
+        let options = &document["paths"]["/backups/{backupId}"]["options"];
+        assert_eq!(
+            options["responses"]["403"]["content"]["application/json"]["schema"]["properties"]
+                ["code"]["enum"],
+            json!(["cors_forbidden"])
+        );
+        assert!(document["paths"]["/config"]["options"]["responses"]["204"].is_object());
+    }
+
+    #[test]
+    fn client_certificate_owners() {
+        let config = ServerConfig {
+            listeners: vec![ListenerConfig {
+                tls: Some(TlsConfig {
+                    cert_file: PathBuf::from("cert.pem"),
+                    key_file: PathBuf::from("key.pem"),
+                    client_ca_file: Some(PathBuf::from("client-ca.pem")),
+                    client_crl_file: None,
+                    client_identity: Some(ClientIdentity::CommonName),
+                    quota: Default::default(),
+                    user_quotas: Default::default(),
+                }),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let document = document(&config);
+        let put = &document["paths"]["/backups/{backupId}"]["put"];
+        assert!(put.get("security").is_none());
+        assert!(put["responses"].get("401").is_none());
+        assert_eq!(
+            put["responses"]["403"]["content"]["application/json"]["schema"]["properties"]["code"]
+                ["enum"],
+            json!(["quota_exceeded"])
+        );
+        assert!(put["responses"]["404"].is_object());
+    }
+}
@@ -0,0 +1,204 @@
+//! Pseudonymization of backup IDs.
+//!
+//! Anyone who knows a backup ID can download or delete the backup, so IDs
+//! are replaced by a keyed hash (HMAC-SHA256) wherever they would otherwise
+//! be retained.
+//!
+//! Log lines must never format backup IDs or client addresses directly, but
+//! always through the server's `LogPrivacy`. In log privacy mode, it
+//! replaces backup IDs by a truncated keyed hash and omits client addresses.
+
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{bail, Context};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde_derive::Deserialize;
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The number of hex characters of the keyed hash shown in logs.
+const LOG_HASH_LEN: usize = 12;
+
+/// The log privacy configuration.
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+pub struct LogPrivacyConfig {
+    /// Path to a file containing the key for hashing backup IDs. If not set,
+    /// a random key is used, so hashes only match within the same process.
+    pub hash_key_file: Option<PathBuf>,
+}
+
+/// The log privacy mode of a server.
+///
+/// Every server has its own mode (and key), so that multiple servers in the
+/// same process don't affect each other's logs.
+#[derive(Debug, Clone, Default)]
+pub struct LogPrivacy {
+    hasher: Option<Arc<IdHasher>>,
+}
+
+impl LogPrivacy {
+    /// Create the log privacy mode (disabled if there is no configuration).
+    pub fn new(config: Option<&LogPrivacyConfig>) -> anyhow::Result<Self> {
+        let hasher = match config {
+            Some(LogPrivacyConfig {
+                hash_key_file: Some(ref key_file),
+            }) => Some(IdHasher::load(key_file)?),
+            Some(LogPrivacyConfig {
+                hash_key_file: None,
+            }) => {
+                let mut key = [0; 32];
+                rand::thread_rng().fill_bytes(&mut key);
+                Some(IdHasher::new(&key))
+            }
+            None => None,
+        };
+        Ok(Self {
+            hasher: hasher.map(Arc::new),
+        })
+    }
+
+    /// Return whether log privacy mode is enabled.
+    pub fn enabled(&self) -> bool {
+        self.hasher.is_some()
+    }
+
+    /// Return a representation of a backup ID that may be logged.
+    pub fn id<'a>(&'a self, backup_id: &'a str) -> LogId<'a> {
+        LogId {
+            hasher: self.hasher.as_deref(),
+            backup_id,
+        }
+    }
+
+    /// Return a representation of a client address that may be logged.
+    #[cfg(feature = "tls")]
+    pub fn addr<T: fmt::Display>(&self, addr: T) -> LogAddr<T> {
+        LogAddr {
+            addr,
+            redact: self.hasher.is_some(),
+        }
+    }
+}
+
+/// A backup ID formatted according to the log privacy mode.
+pub struct LogId<'a> {
+    hasher: Option<&'a IdHasher>,
+    backup_id: &'a str,
+}
+
+impl fmt::Display for LogId<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", redact_id(self.hasher, self.backup_id))
+    }
+}
+
+fn redact_id(hasher: Option<&IdHasher>, backup_id: &str) -> String {
+    match hasher {
+        Some(hasher) => format!("h:{}", &hasher.hash(backup_id)[..LOG_HASH_LEN]),
+        None => backup_id.to_string(),
+    }
+}
+
+/// A client address formatted according to the log privacy mode.
+#[cfg(feature = "tls")]
+pub struct LogAddr<T> {
+    addr: T,
+    redact: bool,
+}
+
+#[cfg(feature = "tls")]
+impl<T: fmt::Display> fmt::Display for LogAddr<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.redact {
+            write!(f, "[client]")
+        } else {
+            write!(f, "{}", self.addr)
+        }
+    }
+}
+
+/// Load a secret key from a file.
+///
+/// Leading and trailing whitespace is ignored.
+pub fn load_key(key_file: &Path) -> anyhow::Result<Vec<u8>> {
+    let contents = fs::read_to_string(key_file)
+        .with_context(|| format!("Could not read key file {:?}", key_file))?;
+    let key = contents.trim();
+    if key.len() < 16 {
+        bail!("Key in {:?} is too short (min 16 characters)", key_file);
+    }
+    Ok(key.as_bytes().to_vec())
+}
+
+/// Computes keyed hashes of backup IDs.
+#[derive(Clone)]
+pub struct IdHasher {
+    key: Vec<u8>,
+}
+
+impl fmt::Debug for IdHasher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("IdHasher").finish_non_exhaustive()
+    }
+}
+
+impl IdHasher {
+    pub fn new(key: &[u8]) -> Self {
+        Self { key: key.to_vec() }
+    }
+
+    /// Load the hash key from a file (see `load_key`).
+    pub fn load(key_file: &Path) -> anyhow::Result<Self> {
+        Ok(Self::new(&load_key(key_file)?))
+    }
+
+    /// Return the hex encoded keyed hash of a backup ID.
+    pub fn hash(&self, backup_id: &str) -> String {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC accepts any key length");
+        mac.update(backup_id.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_is_keyed() {
+        let id = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+        let a = IdHasher::new(b"first key");
+        let b = IdHasher::new(b"second key");
+        assert_eq!(a.hash(id), a.hash(id));
+        assert_ne!(a.hash(id), b.hash(id));
+        assert_ne!(a.hash(id), id);
+        assert_eq!(a.hash(id).len(), 64);
+    }
+
+    #[test]
+    fn redact_ids() {
+        let id = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+        let hasher = IdHasher::new(b"log key");
+        assert_eq!(redact_id(None, id), id);
+        let redacted = redact_id(Some(&hasher), id);
+        assert_eq!(redacted, format!("h:{}", &hasher.hash(id)[..12]));
+    }
+
+    #[test]
+    fn independent_modes() {
+        let id = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+        let disabled = LogPrivacy::new(None).unwrap();
+        let a = LogPrivacy::new(Some(&LogPrivacyConfig::default())).unwrap();
+        let b = LogPrivacy::new(Some(&LogPrivacyConfig::default())).unwrap();
+        assert_eq!(disabled.id(id).to_string(), id);
+        assert!(a.id(id).to_string().starts_with("h:"));
+        assert_eq!(a.id(id).to_string(), a.clone().id(id).to_string());
+        assert_ne!(a.id(id).to_string(), b.id(id).to_string());
+    }
+}
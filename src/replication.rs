@@ -0,0 +1,537 @@
+//! Asynchronous replication of backups to peer servers.
+//!
+//! Changed backup IDs are recorded as marker files in a per-peer queue
+//! directory (`<queue_dir>/<peer>/<backup_id>`). A worker per peer pushes the
+//! *current* state of each queued backup to the peer over the Threema Safe
+//! API: if the backup exists locally it is uploaded, otherwise it is deleted
+//! on the peer. Multiple changes to the same backup are therefore coalesced,
+//! and the queue survives restarts. The modification time of a marker is the
+//! time of the oldest change that hasn't been replicated yet.
+
+use std::{
+    fmt::Write as _,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, Context};
+use log::{debug, info, warn};
+use reqwest::{header, StatusCode};
+use serde_derive::Deserialize;
+use tokio::{sync::Notify, task::JoinHandle};
+
+use crate::{metrics::Metrics, privacy::LogPrivacy, storage::Storage};
+
+/// Extension of a queue marker while the change is being pushed.
+const INFLIGHT_EXT: &str = "inflight";
+
+/// The replication configuration.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct ReplicationConfig {
+    /// The directory where the replication queues are stored
+    pub queue_dir: PathBuf,
+    /// The maximum delay between retries in seconds (default: 300)
+    pub max_backoff_secs: Option<u64>,
+    /// The peers to replicate to
+    pub peers: Vec<PeerConfig>,
+}
+
+/// A replication peer.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct PeerConfig {
+    /// A unique name for this peer (used for the queue directory and metrics)
+    pub name: String,
+    /// The base URL of the peer (e.g. "https://standby.example.com")
+    pub url: String,
+    /// The user agent sent to the peer (must be accepted by the peer's policy)
+    pub user_agent: Option<String>,
+}
+
+impl PeerConfig {
+    fn user_agent(&self) -> String {
+        self.user_agent.clone().unwrap_or_else(|| {
+            format!(
+                "Threema (compatible; sekursranko/{}; replication)",
+                crate::VERSION
+            )
+        })
+    }
+
+    fn backup_url(&self, backup_id: &str) -> String {
+        format!("{}/backups/{}", self.url.trim_end_matches('/'), backup_id)
+    }
+}
+
+/// The replication state of a single peer.
+#[derive(Debug)]
+struct Peer {
+    config: PeerConfig,
+    queue_dir: PathBuf,
+    notify: Notify,
+}
+
+/// Replicates backups to all configured peers.
+#[derive(Debug)]
+pub struct Replicator {
+    peers: Vec<Arc<Peer>>,
+    storage: Arc<Storage>,
+    max_backoff: Duration,
+    client: reqwest::Client,
+    log_privacy: LogPrivacy,
+}
+
+impl Replicator {
+    pub fn new(config: &ReplicationConfig, storage: Arc<Storage>, log_privacy: LogPrivacy) -> Self {
+        let peers = config
+            .peers
+            .iter()
+            .map(|peer| {
+                Arc::new(Peer {
+                    config: peer.clone(),
+                    queue_dir: config.queue_dir.join(&peer.name),
+                    notify: Notify::new(),
+                })
+            })
+            .collect();
+        Self {
+            peers,
+            storage,
+            max_backoff: Duration::from_secs(config.max_backoff_secs.unwrap_or(300)),
+            client: reqwest::Client::new(),
+            log_privacy,
+        }
+    }
+
+    /// Queue a changed backup for replication to all peers.
+    pub async fn enqueue(&self, backup_id: &str) {
+        for peer in &self.peers {
+            let res = match tokio::fs::create_dir_all(&peer.queue_dir).await {
+                // An existing marker is kept, so that it still dates from the
+                // oldest pending change
+                Ok(()) => match tokio::fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(peer.queue_dir.join(backup_id))
+                    .await
+                {
+                    Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+                    res => res.map(drop),
+                },
+                Err(e) => Err(e),
+            };
+            match res {
+                Ok(()) => peer.notify.notify_one(),
+                Err(e) => warn!(
+                    "Could not queue backup for replication to {}: {}",
+                    peer.config.name, e
+                ),
+            }
+        }
+    }
+
+    /// Spawn a replication worker for every peer.
+    pub fn spawn(self: &Arc<Self>, metrics: Arc<Metrics>) -> Vec<JoinHandle<()>> {
+        self.peers
+            .iter()
+            .map(|peer| {
+                let replicator = self.clone();
+                let peer = peer.clone();
+                let metrics = metrics.clone();
+                tokio::spawn(async move { replicator.run(&peer, &metrics).await })
+            })
+            .collect()
+    }
+
+    async fn run(&self, peer: &Peer, metrics: &Metrics) {
+        info!("Starting replication to {}", peer.config.url);
+        let queue_dir = peer.queue_dir.clone();
+        if let Err(e) = blocking(move || recover_inflight(&queue_dir)).await {
+            warn!("Could not recover replication queue: {}", e);
+        }
+        let mut backoff = Duration::from_secs(1);
+        loop {
+            let queue_dir = peer.queue_dir.clone();
+            let queued = match blocking(move || queued_ids(&queue_dir)).await {
+                Ok(queued) => queued,
+                Err(e) => {
+                    warn!("Could not read replication queue: {}", e);
+                    vec![]
+                }
+            };
+            if queued.is_empty() {
+                // Wait for new changes, but rescan periodically
+                let _ = tokio::time::timeout(Duration::from_secs(60), peer.notify.notified()).await;
+                continue;
+            }
+            for (backup_id, _) in queued {
+                match self.push(peer, &backup_id).await {
+                    Ok(()) => {
+                        Metrics::inc(&metrics.replication_pushed);
+                        backoff = Duration::from_secs(1);
+                    }
+                    Err(e) => {
+                        warn!(
+                            "Replication to {} failed, retrying in {:?}: {:#}",
+                            peer.config.name, backoff, e
+                        );
+                        Metrics::inc(&metrics.replication_failed);
+                        tokio::time::sleep(backoff).await;
+                        backoff = (backoff * 2).min(self.max_backoff);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Push the current state of a queued backup to the peer.
+    async fn push(&self, peer: &Peer, backup_id: &str) -> anyhow::Result<()> {
+        let marker = peer.queue_dir.join(backup_id);
+        let inflight = marker.with_extension(INFLIGHT_EXT);
+        match tokio::fs::rename(&marker, &inflight).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e).context("Could not mark change as in flight"),
+        }
+        let res = self.send(&peer.config, backup_id).await;
+        if res.is_ok() {
+            // A newer change may have been queued in the meantime
+            tokio::fs::remove_file(&inflight)
+                .await
+                .context("Could not remove queue entry")?;
+        } else {
+            // This replaces a newer marker, keeping the time of the older
+            // change
+            tokio::fs::rename(&inflight, &marker)
+                .await
+                .context("Could not restore queue entry")?;
+        }
+        res
+    }
+
+    async fn send(&self, peer: &PeerConfig, backup_id: &str) -> anyhow::Result<()> {
+        send_backup(
+            &self.client,
+            peer,
+            &self.storage,
+            &self.log_privacy,
+            backup_id,
+        )
+        .await
+    }
+
+    /// Render replication gauges in the Prometheus text format.
+    ///
+    /// The queues are scanned on the blocking thread pool, as they can grow
+    /// large while a peer is unavailable.
+    pub async fn render_metrics(&self, out: &mut String) {
+        let _ = writeln!(
+            out,
+            "# HELP sekursranko_replication_queue_length Changes waiting to be replicated."
+        );
+        let _ = writeln!(out, "# TYPE sekursranko_replication_queue_length gauge");
+        let mut stats = vec![];
+        for peer in &self.peers {
+            let queue_dir = peer.queue_dir.clone();
+            let queued = blocking(move || queued_ids(&queue_dir)).await;
+            stats.push((peer, queued.unwrap_or_default()));
+        }
+        for (peer, queued) in &stats {
+            let _ = writeln!(
+                out,
+                "sekursranko_replication_queue_length{{peer=\"{}\"}} {}",
+                peer.config.name,
+                queued.len()
+            );
+        }
+        let _ = writeln!(
+            out,
+            "# HELP sekursranko_replication_lag_seconds Age of the oldest change waiting to be replicated."
+        );
+        let _ = writeln!(out, "# TYPE sekursranko_replication_lag_seconds gauge");
+        for (peer, queued) in &stats {
+            let lag = queued
+                .first()
+                .and_then(|(_, queued_at)| queued_at.elapsed().ok())
+                .map_or(0, |lag| lag.as_secs());
+            let _ = writeln!(
+                out,
+                "sekursranko_replication_lag_seconds{{peer=\"{}\"}} {}",
+                peer.config.name, lag
+            );
+        }
+    }
+}
+
+/// Upload a backup to the peer, or delete it there if it doesn't exist
+/// locally.
+async fn send_backup(
+    client: &reqwest::Client,
+    peer: &PeerConfig,
+    storage: &Storage,
+    log_privacy: &LogPrivacy,
+    backup_id: &str,
+) -> anyhow::Result<()> {
+    let url = peer.backup_url(backup_id);
+    let (request, deletion) = match storage.read(backup_id).await {
+        Ok(data) => {
+            debug!(
+                "Replicating backup {} to {}",
+                log_privacy.id(backup_id),
+                peer.name
+            );
+            let request = client
+                .put(&url)
+                .header(header::CONTENT_TYPE, "application/octet-stream")
+                .body(data);
+            (request, false)
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            debug!(
+                "Replicating deletion of {} to {}",
+                log_privacy.id(backup_id),
+                peer.name
+            );
+            (client.delete(&url), true)
+        }
+        Err(e) => return Err(e).context("Could not read backup"),
+    };
+    let response = request
+        .header(header::USER_AGENT, peer.user_agent())
+        .send()
+        .await
+        // The URL contains the backup ID, which must not end up in the logs
+        .map_err(reqwest::Error::without_url)
+        .context("Request to peer failed")?;
+    let status = response.status();
+    // A backup that doesn't exist on the peer is deleted already, but an
+    // upload must never fail that way (e.g. if the URL points elsewhere)
+    if status.is_success() || (deletion && status == StatusCode::NOT_FOUND) {
+        Ok(())
+    } else {
+        bail!("Peer responded with status {}", status)
+    }
+}
+
+/// Run a blocking file system operation on the blocking thread pool.
+async fn blocking<T, F>(f: F) -> io::Result<T>
+where
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .unwrap_or_else(|e| Err(io::Error::other(e)))
+}
+
+/// Return the queued backup IDs, oldest first.
+fn queued_ids(queue_dir: &Path) -> io::Result<Vec<(String, SystemTime)>> {
+    let entries = match fs::read_dir(queue_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let mut queued = vec![];
+    for entry in entries {
+        let entry = entry?;
+        let name = match entry.file_name().into_string() {
+            Ok(name) if crate::handlers::backup_id_valid(&name) => name,
+            _ => continue,
+        };
+        let queued_at = entry.metadata()?.modified()?;
+        queued.push((name, queued_at));
+    }
+    queued.sort_by_key(|(_, queued_at)| *queued_at);
+    Ok(queued)
+}
+
+/// Restore changes that were in flight when the server stopped.
+///
+/// They replace newer markers of the same backup, which keeps the time of
+/// the older change.
+fn recover_inflight(queue_dir: &Path) -> io::Result<()> {
+    let entries = match fs::read_dir(queue_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) == Some(INFLIGHT_EXT) {
+            fs::rename(&path, path.with_extension(""))?;
+        }
+    }
+    Ok(())
+}
+
+/// Statistics of a resync run.
+#[derive(Debug, Default)]
+pub struct ResyncStats {
+    pub pushed: usize,
+    pub failed: usize,
+}
+
+/// Push all local backups to a peer.
+///
+/// This is meant for bringing a new or outdated peer up to date. Backups
+/// that only exist on the peer are not removed.
+pub async fn resync(
+    config: &ReplicationConfig,
+    storage: &Storage,
+    log_privacy: &LogPrivacy,
+    peer_name: &str,
+) -> anyhow::Result<ResyncStats> {
+    let peer = match config.peers.iter().find(|peer| peer.name == peer_name) {
+        Some(peer) => peer,
+        None => bail!("Unknown replication peer: {}", peer_name),
+    };
+    let client = reqwest::Client::new();
+    let mut stats = ResyncStats::default();
+    let backup_ids = storage
+        .backup_ids()
+        .context("Could not read backup directory")?;
+    for backup_id in backup_ids {
+        match send_backup(&client, peer, storage, log_privacy, &backup_id).await {
+            Ok(()) => stats.pushed += 1,
+            Err(e) => {
+                warn!(
+                    "Could not push backup {}: {:#}",
+                    log_privacy.id(&backup_id),
+                    e
+                );
+                stats.failed += 1;
+            }
+        }
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BACKUP_ID: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    fn replicator(queue_dir: &Path) -> Replicator {
+        replicator_for(
+            queue_dir,
+            "http://127.0.0.1:1",
+            Storage::new(PathBuf::from("/nonexistent"), None),
+        )
+    }
+
+    fn replicator_for(queue_dir: &Path, url: &str, storage: Storage) -> Replicator {
+        let config = ReplicationConfig {
+            queue_dir: queue_dir.to_path_buf(),
+            max_backoff_secs: None,
+            peers: vec![PeerConfig {
+                name: "standby".into(),
+                url: url.into(),
+                user_agent: None,
+            }],
+        };
+        Replicator::new(&config, Arc::new(storage), LogPrivacy::default())
+    }
+
+    /// Start a peer that responds to every request with 404.
+    fn not_found_peer() -> String {
+        use hyper::{
+            service::{make_service_fn, service_fn},
+            Body, Response, Server,
+        };
+
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, hyper::Error>(service_fn(|_| async {
+                Response::builder().status(404).body(Body::empty())
+            }))
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        url
+    }
+
+    /// Pretend that a queue entry was created long ago.
+    fn backdate(path: &Path) -> SystemTime {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(time)
+            .unwrap();
+        time
+    }
+
+    #[tokio::test]
+    async fn enqueue_coalesces() {
+        let dir = tempfile::tempdir().unwrap();
+        let replicator = replicator(dir.path());
+        replicator.enqueue(BACKUP_ID).await;
+        let queued_at = backdate(&dir.path().join("standby").join(BACKUP_ID));
+        replicator.enqueue(BACKUP_ID).await;
+        let queued = queued_ids(&dir.path().join("standby")).unwrap();
+        assert_eq!(queued, vec![(BACKUP_ID.to_string(), queued_at)]);
+
+        let mut out = String::new();
+        replicator.render_metrics(&mut out).await;
+        assert!(out.contains("sekursranko_replication_queue_length{peer=\"standby\"} 1\n"));
+    }
+
+    #[test]
+    fn recover_inflight_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let inflight = dir.path().join(format!("{}.{}", BACKUP_ID, INFLIGHT_EXT));
+        fs::write(&inflight, b"").unwrap();
+        let queued_at = backdate(&inflight);
+        assert!(queued_ids(dir.path()).unwrap().is_empty());
+
+        // The older change is kept
+        fs::write(dir.path().join(BACKUP_ID), b"").unwrap();
+        recover_inflight(dir.path()).unwrap();
+        assert!(!inflight.exists());
+        assert_eq!(
+            queued_ids(dir.path()).unwrap(),
+            vec![(BACKUP_ID.to_string(), queued_at)]
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_push_is_requeued() {
+        let dir = tempfile::tempdir().unwrap();
+        let replicator = replicator(dir.path());
+        replicator.enqueue(BACKUP_ID).await;
+        let peer = replicator.peers[0].clone();
+        let queued_at = backdate(&peer.queue_dir.join(BACKUP_ID));
+        assert!(replicator.push(&peer, BACKUP_ID).await.is_err());
+        assert_eq!(
+            queued_ids(&peer.queue_dir).unwrap(),
+            vec![(BACKUP_ID.to_string(), queued_at)]
+        );
+    }
+
+    #[tokio::test]
+    async fn not_found_only_for_deletions() {
+        let dir = tempfile::tempdir().unwrap();
+        let backup_dir = tempfile::tempdir().unwrap();
+        let replicator = replicator_for(
+            dir.path(),
+            &not_found_peer(),
+            Storage::new(backup_dir.path().to_path_buf(), None),
+        );
+        let peer = replicator.peers[0].clone();
+
+        // The backup doesn't exist on the peer anymore
+        replicator.enqueue(BACKUP_ID).await;
+        replicator.push(&peer, BACKUP_ID).await.unwrap();
+        assert!(queued_ids(&peer.queue_dir).unwrap().is_empty());
+
+        // The upload must be retried
+        fs::write(backup_dir.path().join(BACKUP_ID), b"sekurkopio").unwrap();
+        replicator.enqueue(BACKUP_ID).await;
+        assert!(replicator.push(&peer, BACKUP_ID).await.is_err());
+        assert_eq!(queued_ids(&peer.queue_dir).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,177 @@
+//! Removal of expired backups and prior versions.
+//!
+//! A backup expires `retention_days` after it was last uploaded. Prior
+//! versions are kept for the same time, limited to the configured number of
+//! retained versions. Expired backups are deleted permanently, while deleted
+//! backups are purged from the trash once the grace period has passed.
+
+use std::{
+    io,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use log::{debug, info, warn};
+use tokio::{sync::watch, task::JoinHandle};
+
+use crate::{
+    events::{BackupEvent, EventKind, Source},
+    state::State,
+};
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Return the duration of the specified number of days.
+pub fn days(days: u32) -> Duration {
+    Duration::from_secs(u64::from(days) * SECONDS_PER_DAY)
+}
+
+/// Return the time a backup stored at `stored_at` expires, or `None` if
+/// backups never expire (`retention_days = 0`).
+pub fn expires_at(stored_at: SystemTime, retention_days: u32) -> Option<SystemTime> {
+    if retention_days == 0 {
+        return None;
+    }
+    Some(stored_at + days(retention_days))
+}
+
+/// Statistics of a retention sweep.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SweepStats {
+    /// The number of deleted expired backups
+    pub expired: usize,
+    /// The number of removed prior versions
+    pub pruned_versions: usize,
+    /// The number of deleted backups purged from the trash
+    pub purged_trash: usize,
+}
+
+/// Delete expired backups and prune prior versions.
+pub async fn sweep(state: &State) -> io::Result<SweepStats> {
+    let storage = &state.storage;
+    let retention_days = state.config.retention_days;
+    let now = SystemTime::now();
+    let cutoff = if retention_days == 0 {
+        None
+    } else {
+        now.checked_sub(days(retention_days))
+    };
+    let mut stats = SweepStats::default();
+
+    for backup_id in storage.backup_ids()? {
+        let stored_at = match storage.stored_at(&backup_id).await {
+            Ok(stored_at) => stored_at,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        let expired = expires_at(stored_at, retention_days).is_some_and(|at| at <= now);
+        if expired {
+            // Expire the upstream copy first, so that it is not migrated again
+            if let Some(ref migrator) = state.migrator {
+                if let Err(e) = migrator.delete(&backup_id).await {
+                    warn!(
+                        "Could not delete expired backup {} on upstream: {:#}",
+                        state.log_privacy.id(&backup_id),
+                        e
+                    );
+                    continue;
+                }
+            }
+            debug!(
+                "Deleting expired backup {}",
+                state.log_privacy.id(&backup_id)
+            );
+            storage.remove(&backup_id).await?;
+            state
+                .emit(&BackupEvent::new(
+                    EventKind::Expired,
+                    Source::Sweep,
+                    &backup_id,
+                    None,
+                ))
+                .await;
+            stats.expired += 1;
+        }
+    }
+
+    for backup_id in storage.versioned_backup_ids()? {
+        stats.pruned_versions += storage.prune_versions(&backup_id, cutoff).await?;
+    }
+
+    stats.purged_trash = storage.purge_trash().await?;
+
+    Ok(stats)
+}
+
+/// Run a retention sweep periodically.
+///
+/// The task exits once `stop` is set, but never in the middle of a sweep.
+pub fn spawn(
+    state: Arc<State>,
+    interval: Duration,
+    mut stop: watch::Receiver<bool>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            match sweep(&state).await {
+                Ok(stats) => info!(
+                    "Retention sweep: Deleted {} expired backups and {} prior versions, purged {} backups from the trash",
+                    stats.expired, stats.pruned_versions, stats.purged_trash
+                ),
+                Err(e) => warn!("Retention sweep failed: {}", e),
+            }
+            tokio::select! {
+                biased;
+                _ = stop.wait_for(|stop| *stop) => return,
+                _ = tokio::time::sleep(interval) => {}
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use crate::config::ServerConfig;
+
+    const BACKUP_ID: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn expiry() {
+        let stored_at = SystemTime::UNIX_EPOCH;
+        assert_eq!(expires_at(stored_at, 0), None);
+        assert_eq!(
+            expires_at(stored_at, 2),
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(2 * 86400))
+        );
+    }
+
+    #[tokio::test]
+    async fn sweep_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = State::new(ServerConfig {
+            backup_dir: dir.path().to_path_buf(),
+            retention_days: 180,
+            retained_versions: 5,
+            ..Default::default()
+        })
+        .unwrap();
+
+        state.storage.write_data(BACKUP_ID, b"v1").await.unwrap();
+        state.storage.write_data(BACKUP_ID, b"v2").await.unwrap();
+        assert_eq!(state.storage.versions(BACKUP_ID).await.unwrap().len(), 1);
+
+        // Current backups and recent versions are kept
+        assert_eq!(sweep(&state).await.unwrap(), SweepStats::default());
+
+        // Old versions are removed
+        let versions_dir = dir.path().join(".versions").join(BACKUP_ID);
+        fs::write(versions_dir.join("1000"), b"v0").unwrap();
+        let stats = sweep(&state).await.unwrap();
+        assert_eq!(stats.pruned_versions, 1);
+        assert_eq!(state.storage.versions(BACKUP_ID).await.unwrap().len(), 1);
+    }
+}
@@ -1,3 +1,8 @@
+use hyper::Method;
+use serde_derive::Deserialize;
+
+use crate::{config::ServerConfig, errors::ApiError};
+
 pub type Router = route_recognizer::Router<Route>;
 
 /// All possible routes.
@@ -6,13 +11,496 @@ pub enum Route {
     Index,
     Config,
     Backup,
+    Metrics,
+    OpenApi,
+}
+
+impl Route {
+    /// All routes, in the order they are added to the router.
+    pub const ALL: &'static [Route] = &[
+        Route::Index,
+        Route::Config,
+        Route::Backup,
+        Route::Metrics,
+        Route::OpenApi,
+    ];
+
+    /// Return the path pattern of this route (as used by the router).
+    pub fn path(self) -> &'static str {
+        match self {
+            Route::Index => "/",
+            Route::Config => "/config",
+            Route::Backup => "/backups/:backupId",
+            Route::Metrics => "/metrics",
+            Route::OpenApi => "/openapi.json",
+        }
+    }
+
+    /// Return the route group this route belongs to.
+    pub fn group(self) -> RouteGroup {
+        match self {
+            Route::Index => RouteGroup::Info,
+            Route::Config | Route::Backup => RouteGroup::Safe,
+            Route::Metrics => RouteGroup::Metrics,
+            Route::OpenApi => RouteGroup::OpenApi,
+        }
+    }
+
+    /// Return the value of the `Allow` header for this route.
+    ///
+    /// `OPTIONS` is included for the Safe API if CORS is enabled.
+    pub fn allow(self, cors: bool) -> &'static str {
+        match (self, cors) {
+            (Route::Index, _) | (Route::Metrics, _) | (Route::OpenApi, _) => "GET",
+            (Route::Config, false) => "GET",
+            (Route::Config, true) => "GET, OPTIONS",
+            (Route::Backup, false) => "GET, HEAD, PUT, DELETE",
+            (Route::Backup, true) => "GET, HEAD, PUT, DELETE, OPTIONS",
+        }
+    }
+}
+
+/// The operations of the Safe API.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Operation {
+    GetConfig,
+    PreflightConfig,
+    GetBackup,
+    HeadBackup,
+    PutBackup,
+    DeleteBackup,
+    PreflightBackup,
+}
+
+impl Operation {
+    /// All operations, in the order they are documented.
+    pub const ALL: &'static [Operation] = &[
+        Operation::GetConfig,
+        Operation::PreflightConfig,
+        Operation::GetBackup,
+        Operation::HeadBackup,
+        Operation::PutBackup,
+        Operation::DeleteBackup,
+        Operation::PreflightBackup,
+    ];
+
+    /// Return the operation of a request, or `None` if the route doesn't
+    /// belong to the Safe API or doesn't support the method.
+    pub fn new(route: Route, method: &Method) -> Option<Self> {
+        match route {
+            Route::Config => match *method {
+                Method::GET => Some(Operation::GetConfig),
+                Method::OPTIONS => Some(Operation::PreflightConfig),
+                _ => None,
+            },
+            Route::Backup => match *method {
+                Method::GET => Some(Operation::GetBackup),
+                Method::HEAD => Some(Operation::HeadBackup),
+                Method::PUT => Some(Operation::PutBackup),
+                Method::DELETE => Some(Operation::DeleteBackup),
+                Method::OPTIONS => Some(Operation::PreflightBackup),
+                _ => None,
+            },
+            Route::Index | Route::Metrics | Route::OpenApi => None,
+        }
+    }
+
+    pub fn route(self) -> Route {
+        match self {
+            Operation::GetConfig | Operation::PreflightConfig => Route::Config,
+            Operation::GetBackup
+            | Operation::HeadBackup
+            | Operation::PutBackup
+            | Operation::DeleteBackup
+            | Operation::PreflightBackup => Route::Backup,
+        }
+    }
+
+    pub fn method(self) -> Method {
+        match self {
+            Operation::GetConfig | Operation::GetBackup => Method::GET,
+            Operation::HeadBackup => Method::HEAD,
+            Operation::PutBackup => Method::PUT,
+            Operation::DeleteBackup => Method::DELETE,
+            Operation::PreflightConfig | Operation::PreflightBackup => Method::OPTIONS,
+        }
+    }
+
+    /// Return whether this is a CORS preflight request (only served if CORS
+    /// is enabled).
+    pub fn is_preflight(self) -> bool {
+        matches!(
+            self,
+            Operation::PreflightConfig | Operation::PreflightBackup
+        )
+    }
+
+    /// The errors of this operation (in the order they are checked), and
+    /// the feature they depend on.
+    ///
+    /// The handlers check in debug builds that they only respond with these
+    /// errors, and the OpenAPI document is generated from them.
+    fn error_table(self) -> &'static [(ApiError, Option<Feature>)] {
+        use ApiError::*;
+        use Feature::*;
+        match self {
+            Operation::GetConfig => &[
+                (InvalidUserAgent, None),
+                (Unauthorized, Some(BasicAuth)),
+                (InvalidAcceptHeader, None),
+                (InternalServerError, None),
+            ],
+            Operation::GetBackup | Operation::HeadBackup => &[
+                (InvalidUserAgent, None),
+                (Unauthorized, Some(BasicAuth)),
+                (InvalidAcceptHeader, None),
+                (NotFound, None),
+                (UpstreamUnavailable, Some(Migration)),
+                (InternalServerError, None),
+            ],
+            Operation::PutBackup => &[
+                (InvalidUserAgent, None),
+                (Unauthorized, Some(BasicAuth)),
+                (InvalidContentTypeHeader, None),
+                (InvalidContentLengthHeader, None),
+                (InvalidBackupId, None),
+                (BackupTooLarge, None),
+                (InvalidBackup, Some(Validation)),
+                (NotFound, Some(Owners)),
+                (QuotaExceeded, Some(Owners)),
+                (InternalServerError, None),
+            ],
+            Operation::DeleteBackup => &[
+                (InvalidUserAgent, None),
+                (Unauthorized, Some(BasicAuth)),
+                (InvalidBackupId, None),
+                (NotFound, None),
+                (UpstreamUnavailable, Some(Migration)),
+                (InternalServerError, None),
+            ],
+            Operation::PreflightConfig | Operation::PreflightBackup => &[(CorsForbidden, None)],
+        }
+    }
+
+    /// Return the errors this operation can respond with.
+    pub fn errors(self, features: &Features) -> Vec<ApiError> {
+        self.error_table()
+            .iter()
+            .filter(|(_, feature)| feature.map_or(true, |feature| features.has(feature)))
+            .map(|(error, _)| *error)
+            .collect()
+    }
+}
+
+/// Optional features that affect the errors of the Safe API.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Feature {
+    BasicAuth,
+    Owners,
+    Validation,
+    Migration,
+}
+
+/// The optional features enabled on a server.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Features {
+    /// HTTP Basic authentication is required
+    pub basic_auth: bool,
+    /// Backups are owned by users (with Basic authentication or client
+    /// certificates)
+    pub owners: bool,
+    /// Uploads are validated
+    pub validation: bool,
+    /// Missing backups are migrated from an upstream server
+    pub migration: bool,
+    /// CORS is enabled on at least one listener
+    pub cors: bool,
+}
+
+impl Features {
+    pub fn new(config: &ServerConfig) -> Self {
+        let listeners: Vec<_> = std::iter::once(config.default_listener())
+            .chain(config.listeners.iter().cloned())
+            .collect();
+        let client_identity = listeners.iter().any(|listener| {
+            listener
+                .tls
+                .as_ref()
+                .is_some_and(|tls| tls.client_identity.is_some())
+        });
+        Self {
+            basic_auth: config.auth.is_some(),
+            owners: config.auth.is_some() || client_identity,
+            validation: config.validate_backups,
+            migration: config.migration.as_ref().is_some_and(|m| m.enabled()),
+            cors: listeners.iter().any(|listener| listener.cors().is_some()),
+        }
+    }
+
+    fn has(&self, feature: Feature) -> bool {
+        match feature {
+            Feature::BasicAuth => self.basic_auth,
+            Feature::Owners => self.owners,
+            Feature::Validation => self.validation,
+            Feature::Migration => self.migration,
+        }
+    }
+}
+
+/// Groups of routes that can be exposed on a listener.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RouteGroup {
+    /// The index route, returning the server name and version
+    Info,
+    /// The Threema Safe API (`/config` and `/backups/:backupId`)
+    Safe,
+    /// Server metrics in the Prometheus text format (`/metrics`)
+    Metrics,
+    /// The OpenAPI description of the Safe API (`/openapi.json`)
+    OpenApi,
+}
+
+impl RouteGroup {
+    /// The route groups exposed on listeners that don't specify `routes`.
+    pub const DEFAULT: &'static [RouteGroup] = &[RouteGroup::Info, RouteGroup::Safe];
 }
 
 /// Create a new router instance.
 pub fn make_router() -> Router {
     let mut router = route_recognizer::Router::new();
-    router.add("/", Route::Index);
-    router.add("/config", Route::Config);
-    router.add("/backups/:backupId", Route::Backup);
+    for route in Route::ALL {
+        router.add(route.path(), *route);
+    }
     router
 }
+
+/// Remove a path prefix (e.g. "/safe") from a request path.
+///
+/// Return `None` if the path is not below the prefix.
+pub fn strip_path_prefix<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    match path.strip_prefix(prefix.trim_end_matches('/'))? {
+        "" => Some("/"),
+        rest if rest.starts_with('/') => Some(rest),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{collections::HashMap, path::Path};
+
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use hyper::{header, Body, Request};
+
+    use crate::{
+        auth::{AuthConfig, QuotaConfig},
+        cors::CorsConfig,
+        handlers::handler,
+        migration::MigrationConfig,
+        state::State,
+    };
+
+    const BACKUP_ID: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+    const OTHER_BACKUP_ID: &str =
+        "1123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+    const DIRECTORY_BACKUP_ID: &str =
+        "2123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    /// Create a config with the features selected by the bits of `features`.
+    fn config(dir: &Path, features: u8) -> ServerConfig {
+        let htpasswd_file = dir.join("htpasswd");
+        let hash = "$2b$04$okK9S1/hF7QJO1ReoRi4puM/bvcq882iNaLpVZk5hzl9GYT/w.HgW";
+        std::fs::write(&htpasswd_file, format!("alice:{}\nbob:{}\n", hash, hash)).unwrap();
+        let backup_dir = dir.join("backups");
+        std::fs::create_dir_all(backup_dir.join(DIRECTORY_BACKUP_ID)).unwrap();
+        ServerConfig {
+            backup_dir,
+            max_backup_bytes: 1024,
+            auth: (features & 1 != 0).then(|| AuthConfig {
+                htpasswd_file,
+                realm: None,
+                reload_interval_secs: None,
+                quota: QuotaConfig {
+                    max_backups: Some(1),
+                    max_bytes: None,
+                },
+                user_quotas: HashMap::new(),
+            }),
+            validate_backups: features & 2 != 0,
+            migration: (features & 4 != 0).then(|| MigrationConfig {
+                // Nothing listens on port 1
+                upstream_url: "http://127.0.0.1:1".into(),
+                enabled: None,
+                user_agent: None,
+                timeout_secs: Some(5),
+            }),
+            cors: (features & 8 != 0).then(|| CorsConfig {
+                allowed_origins: vec!["https://safe.example.com".into()],
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    /// Create a request from `user` (with the password "secret").
+    fn request(method: Method, path: &str, user: Option<&str>) -> hyper::http::request::Builder {
+        let builder = Request::builder()
+            .method(method)
+            .uri(path)
+            .header(header::USER_AGENT, "Threema");
+        match user {
+            Some(user) => builder.header(
+                header::AUTHORIZATION,
+                format!("Basic {}", STANDARD.encode(format!("{}:secret", user))),
+            ),
+            None => builder,
+        }
+    }
+
+    fn upload(backup_id: &str, user: Option<&str>, size: usize) -> Request<Body> {
+        request(Method::PUT, &format!("/backups/{}", backup_id), user)
+            .header(header::CONTENT_TYPE, "application/octet-stream")
+            .header(header::CONTENT_LENGTH, size)
+            .body(Body::from(vec![0; size]))
+            .unwrap()
+    }
+
+    /// Requests that run into the error paths of the handlers (in order).
+    fn requests() -> Vec<Request<Body>> {
+        let backup = format!("/backups/{}", BACKUP_ID);
+        let other_backup = format!("/backups/{}", OTHER_BACKUP_ID);
+        let alice = Some("alice");
+        let mut requests = vec![];
+        for method in &[Method::GET, Method::HEAD] {
+            let get = |path: &str, user, accept| {
+                request(method.clone(), path, user)
+                    .header(header::ACCEPT, accept)
+                    .body(Body::empty())
+                    .unwrap()
+            };
+            requests.extend(vec![
+                Request::builder()
+                    .method(method.clone())
+                    .uri(&backup)
+                    .body(Body::empty())
+                    .unwrap(),
+                get(&backup, None, "application/octet-stream"),
+                get(&backup, alice, "text/html"),
+                get(&backup, alice, "application/octet-stream"),
+                get("/backups/invalid", alice, "application/octet-stream"),
+            ]);
+        }
+        requests.extend(vec![
+            Request::get("/config").body(Body::empty()).unwrap(),
+            request(Method::GET, "/config", None)
+                .body(Body::empty())
+                .unwrap(),
+            request(Method::GET, "/config", alice)
+                .header(header::ACCEPT, "text/html")
+                .body(Body::empty())
+                .unwrap(),
+            Request::put(&backup).body(Body::empty()).unwrap(),
+            upload(BACKUP_ID, None, 100),
+            request(Method::PUT, &backup, alice)
+                .body(Body::empty())
+                .unwrap(),
+            request(Method::PUT, &backup, alice)
+                .header(header::CONTENT_TYPE, "application/octet-stream")
+                .body(Body::empty())
+                .unwrap(),
+            upload("invalid", alice, 100),
+            upload(BACKUP_ID, alice, 2048),
+            upload(DIRECTORY_BACKUP_ID, alice, 100),
+            upload(BACKUP_ID, alice, 100),
+            upload(BACKUP_ID, Some("bob"), 100),
+            upload(OTHER_BACKUP_ID, alice, 100),
+            Request::delete(&backup).body(Body::empty()).unwrap(),
+            request(Method::DELETE, &backup, None)
+                .body(Body::empty())
+                .unwrap(),
+            request(Method::DELETE, "/backups/invalid", alice)
+                .body(Body::empty())
+                .unwrap(),
+            request(Method::DELETE, &backup, Some("bob"))
+                .body(Body::empty())
+                .unwrap(),
+            request(Method::DELETE, &other_backup, Some("bob"))
+                .body(Body::empty())
+                .unwrap(),
+        ]);
+        for path in &["/config", &backup] {
+            requests.push(
+                Request::options(*path)
+                    .header(header::ORIGIN, "https://evil.example.com")
+                    .header(header::ACCESS_CONTROL_REQUEST_METHOD, "GET")
+                    .body(Body::empty())
+                    .unwrap(),
+            );
+        }
+        requests
+    }
+
+    /// The handlers only respond with the errors documented for the
+    /// operation (see `Operation::errors`), for all combinations of features.
+    #[tokio::test]
+    async fn documented_errors() {
+        let router = make_router();
+        let mut seen = vec![];
+        for combination in 0..16 {
+            let dir = tempfile::tempdir().unwrap();
+            let state = State::new(config(dir.path(), combination)).unwrap();
+            let listener = state.config.default_listener();
+            let features = Features::new(&state.config);
+            for req in requests() {
+                let operation = router
+                    .recognize(req.uri().path())
+                    .ok()
+                    .and_then(|m| Operation::new(**m.handler(), req.method()))
+                    .filter(|operation| !operation.is_preflight() || features.cors);
+                let (method, uri) = (req.method().clone(), req.uri().clone());
+                let response = handler(req, &router, &state, &listener, None)
+                    .await
+                    .unwrap();
+                if response.status().is_success() {
+                    continue;
+                }
+                let operation = match operation {
+                    Some(operation) => operation,
+                    None => continue,
+                };
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                let code = body["code"].as_str().unwrap().to_string();
+                assert!(
+                    operation
+                        .errors(&features)
+                        .iter()
+                        .any(|error| error.code() == code),
+                    "Undocumented error {} for {} {} with {:?}",
+                    code,
+                    method,
+                    uri,
+                    features
+                );
+                seen.push((operation, code));
+            }
+        }
+
+        // All documented errors were checked (internal errors can't be
+        // caused for every operation)
+        for operation in Operation::ALL {
+            for (error, _) in operation.error_table() {
+                assert!(
+                    *error == ApiError::InternalServerError
+                        || seen.contains(&(*operation, error.code().to_string())),
+                    "Error {:?} for {:?} was not checked",
+                    error,
+                    operation
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,219 @@
+//! The Threema Safe backup format, as specified in the Threema Cryptography
+//! Whitepaper.
+//!
+//! A 64 byte master key is derived from the Threema ID and the Safe password
+//! with scrypt (N = 65536, r = 8, p = 1, the ID as salt). The first 32 bytes
+//! are the backup ID, the remaining 32 bytes the encryption key. Backups are
+//! stored in the following format:
+//!
+//! ```text
+//! nonce (24 bytes) | XSalsa20-Poly1305 ciphertext of the gzip compressed JSON
+//! ```
+//!
+//! The server never needs any of this; it is only used by support tooling
+//! that runs locally with the user's consent.
+
+use std::io::Read;
+
+use anyhow::{anyhow, bail, Context};
+use crypto_secretbox::{
+    aead::{Aead, KeyInit},
+    Key, Nonce, XSalsa20Poly1305,
+};
+use flate2::read::GzDecoder;
+use serde_json::Value;
+
+/// The length of the nonce in front of the ciphertext.
+pub const NONCE_LEN: usize = 24;
+/// The length of the authentication tag of the ciphertext.
+pub const TAG_LEN: usize = 16;
+
+/// The maximum size of a decompressed backup (against gzip bombs).
+const MAX_DECOMPRESSED_BYTES: u64 = 64 * 1024 * 1024;
+
+/// The backup ID and encryption key of a Threema Safe backup.
+pub struct SafeKeys {
+    pub backup_id: String,
+    encryption_key: Key,
+}
+
+impl SafeKeys {
+    /// Derive the keys from a Threema ID and the Safe password.
+    ///
+    /// This takes a while (and 64 MiB of memory) by design.
+    pub fn derive(identity: &str, password: &str) -> anyhow::Result<Self> {
+        let params = scrypt::Params::new(16, 8, 1, 64).expect("Invalid scrypt params");
+        Self::derive_with_params(identity, password, &params)
+    }
+
+    fn derive_with_params(
+        identity: &str,
+        password: &str,
+        params: &scrypt::Params,
+    ) -> anyhow::Result<Self> {
+        let identity = identity.to_ascii_uppercase();
+        if !identity_valid(&identity) {
+            bail!("Invalid Threema ID: {}", identity);
+        }
+        let mut master_key = [0; 64];
+        scrypt::scrypt(
+            password.as_bytes(),
+            identity.as_bytes(),
+            params,
+            &mut master_key,
+        )
+        .map_err(|e| anyhow!("Could not derive the master key: {}", e))?;
+        Ok(Self {
+            backup_id: hex::encode(&master_key[..32]),
+            encryption_key: *Key::from_slice(&master_key[32..]),
+        })
+    }
+
+    /// Decrypt and decompress a backup, returning the JSON contents.
+    pub fn decrypt(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        if data.len() < NONCE_LEN + TAG_LEN {
+            bail!("Backup is too short");
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        let compressed = XSalsa20Poly1305::new(&self.encryption_key)
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow!("Could not decrypt backup (wrong key or corrupted data)"))?;
+        let mut json = vec![];
+        GzDecoder::new(&compressed[..])
+            .take(MAX_DECOMPRESSED_BYTES + 1)
+            .read_to_end(&mut json)
+            .context("Could not decompress backup")?;
+        if json.len() as u64 > MAX_DECOMPRESSED_BYTES {
+            bail!("Decompressed backup is too large");
+        }
+        Ok(json)
+    }
+}
+
+/// Return whether this is a valid Threema ID (8 uppercase alphanumeric
+/// characters, or `*` followed by 7 for gateway IDs).
+fn identity_valid(identity: &str) -> bool {
+    identity.len() == 8
+        && identity
+            .char_indices()
+            .all(|(i, c)| c.is_ascii_digit() || c.is_ascii_uppercase() || (i == 0 && c == '*'))
+}
+
+/// A summary of the contents of a decrypted backup.
+///
+/// This deliberately contains no keys or contact details.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupSummary {
+    /// The backup format version
+    pub version: Option<u64>,
+    /// The device that created the backup (e.g. "Android")
+    pub device: Option<String>,
+    pub nickname: Option<String>,
+    pub has_private_key: bool,
+    pub contacts: usize,
+    pub groups: usize,
+    pub distribution_lists: usize,
+    pub settings: usize,
+}
+
+impl BackupSummary {
+    /// Summarize the JSON contents of a backup.
+    pub fn from_json(json: &[u8]) -> anyhow::Result<Self> {
+        let value: Value = serde_json::from_slice(json).context("Backup is not valid JSON")?;
+        if !value.is_object() {
+            bail!("Backup is not a JSON object");
+        }
+        let len = |key: &str| value[key].as_array().map_or(0, Vec::len);
+        Ok(Self {
+            version: value["info"]["version"].as_u64(),
+            device: value["info"]["device"].as_str().map(str::to_string),
+            nickname: value["user"]["nickname"].as_str().map(str::to_string),
+            has_private_key: value["user"]["privatekey"].is_string(),
+            contacts: len("contacts"),
+            groups: len("groups"),
+            distribution_lists: len("distributionlists"),
+            settings: value["settings"].as_object().map_or(0, |s| s.len()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+
+    /// Cheap scrypt params, the real ones are too slow for debug builds.
+    fn test_params() -> scrypt::Params {
+        scrypt::Params::new(4, 8, 1, 64).unwrap()
+    }
+
+    /// Encrypt a backup like a Threema client does.
+    fn encrypt(keys: &SafeKeys, json: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(json).unwrap();
+        let compressed = encoder.finish().unwrap();
+        let nonce = [7; NONCE_LEN];
+        let mut data = nonce.to_vec();
+        data.extend(
+            XSalsa20Poly1305::new(&keys.encryption_key)
+                .encrypt(Nonce::from_slice(&nonce), &compressed[..])
+                .unwrap(),
+        );
+        data
+    }
+
+    #[test]
+    fn derive_keys() {
+        let keys = SafeKeys::derive_with_params("echoecho", "password", &test_params()).unwrap();
+        let upper = SafeKeys::derive_with_params("ECHOECHO", "password", &test_params()).unwrap();
+        assert_eq!(keys.backup_id, upper.backup_id);
+        assert_eq!(keys.backup_id.len(), 64);
+        let other = SafeKeys::derive_with_params("ECHOECHO", "passw0rd", &test_params()).unwrap();
+        assert_ne!(keys.backup_id, other.backup_id);
+
+        assert!(SafeKeys::derive_with_params("ECHO", "password", &test_params()).is_err());
+        assert!(SafeKeys::derive_with_params("ECHO-ECH", "password", &test_params()).is_err());
+        assert!(SafeKeys::derive_with_params("*GATEWAY", "password", &test_params()).is_ok());
+    }
+
+    #[test]
+    fn decrypt_and_summarize() {
+        let keys = SafeKeys::derive_with_params("ECHOECHO", "password", &test_params()).unwrap();
+        let json = br#"{
+            "info": {"version": 1, "device": "Android"},
+            "user": {"privatekey": "AAAA", "nickname": "Echo"},
+            "contacts": [{"identity": "ABCDEFGH"}, {"identity": "12345678"}],
+            "groups": [{"id": "0011223344556677"}],
+            "distributionlists": [],
+            "settings": {"syncContacts": false, "blockUnknown": true}
+        }"#;
+        let data = encrypt(&keys, json);
+        let decrypted = keys.decrypt(&data).unwrap();
+        assert_eq!(decrypted, json.to_vec());
+        assert_eq!(
+            BackupSummary::from_json(&decrypted).unwrap(),
+            BackupSummary {
+                version: Some(1),
+                device: Some("Android".into()),
+                nickname: Some("Echo".into()),
+                has_private_key: true,
+                contacts: 2,
+                groups: 1,
+                distribution_lists: 0,
+                settings: 2,
+            }
+        );
+
+        // Wrong key or tampered data
+        let other = SafeKeys::derive_with_params("ECHOECHO", "passw0rd", &test_params()).unwrap();
+        assert!(other.decrypt(&data).is_err());
+        let mut tampered = data.clone();
+        tampered[NONCE_LEN] ^= 1;
+        assert!(keys.decrypt(&tampered).is_err());
+        assert!(keys.decrypt(&data[..NONCE_LEN]).is_err());
+        assert!(BackupSummary::from_json(b"[]").is_err());
+    }
+}
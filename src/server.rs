@@ -0,0 +1,200 @@
+//! Running the server inside another tokio application.
+//!
+//! ```no_run
+//! # async fn run(config: sekursranko::ServerConfig) -> anyhow::Result<()> {
+//! let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+//! let handle = sekursranko::ServerBuilder::new(config)
+//!     .tcp_listener(listener)
+//!     .start()?;
+//! println!("Listening on {:?}", handle.local_addr());
+//!
+//! // Stop accepting connections and wait for open requests
+//! handle.shutdown();
+//! handle.await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail};
+use tokio::{sync::watch, task::JoinHandle};
+
+use crate::{
+    config::ServerConfig,
+    listen::{self, BoundListener, ListenAddr},
+    service::MakeBackupService,
+    state::State,
+    storage::Storage,
+};
+
+/// How long background tasks may take to finish after the listeners have
+/// shut down.
+const BACKGROUND_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Builds and starts a server.
+#[derive(Debug)]
+pub struct ServerBuilder {
+    config: ServerConfig,
+    tcp_listener: Option<std::net::TcpListener>,
+    storage: Option<Arc<Storage>>,
+}
+
+impl ServerBuilder {
+    /// Create a builder for a server with the specified config.
+    ///
+    /// Without further settings, the server binds all listeners of the
+    /// config, like the `sekursranko` binary.
+    pub fn new(config: ServerConfig) -> Self {
+        Self {
+            config,
+            tcp_listener: None,
+            storage: None,
+        }
+    }
+
+    /// Serve the default listener on a pre-bound TCP socket (instead of
+    /// binding `listen_on`).
+    ///
+    /// Additional `listeners` of the config are still bound.
+    pub fn tcp_listener(mut self, tcp_listener: std::net::TcpListener) -> Self {
+        self.tcp_listener = Some(tcp_listener);
+        self
+    }
+
+    /// Use the specified storage (instead of opening `backup_dir`).
+    pub fn storage(mut self, storage: Arc<Storage>) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    /// Bind all listeners and start serving them, together with the
+    /// background tasks (e.g. replication workers).
+    ///
+    /// This must be called from within a tokio runtime.
+    pub fn start(self) -> anyhow::Result<ServerHandle> {
+        let state = match self.storage {
+            Some(storage) => State::with_storage(self.config.clone(), storage)?,
+            None => State::new(self.config.clone())?,
+        };
+        let state = Arc::new(state);
+
+        // Bind all listeners first, so that errors are reported immediately
+        let mut listeners = vec![];
+        let mut configured = self.config.listeners().into_iter();
+        if let Some(tcp_listener) = self.tcp_listener {
+            tcp_listener.set_nonblocking(true)?;
+            if self.config.listen_on.is_some() {
+                configured.next();
+            }
+            listeners.push((
+                self.config.default_listener(),
+                BoundListener::Tcp(tcp_listener),
+            ));
+        }
+        for listener in configured {
+            let addr: ListenAddr = listener.listen_on.parse().map_err(|e| {
+                anyhow!("Invalid listening address {:?}: {}", listener.listen_on, e)
+            })?;
+            let bound = listen::bind(&addr, &listener)?;
+            listeners.push((listener, bound));
+        }
+        if listeners.is_empty() {
+            bail!("No listeners configured");
+        }
+        let local_addr = listeners.first().and_then(|(_, bound)| bound.local_addr());
+
+        let background_tasks = state.spawn_background_tasks();
+        let service = MakeBackupService::from_state(state);
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let servers: Vec<_> = listeners
+            .into_iter()
+            .map(|(listener, bound)| {
+                let service = service.for_listener(listener.clone());
+                let mut shutdown_rx = shutdown_rx.clone();
+                async move {
+                    let shutdown = async move {
+                        let _ = shutdown_rx.wait_for(|shutdown| *shutdown).await;
+                    };
+                    listen::serve_bound(bound, &listener, service, shutdown).await
+                }
+            })
+            .collect();
+        let task = tokio::spawn(async move {
+            let result = futures::future::try_join_all(servers).await;
+            background_tasks.shutdown(BACKGROUND_SHUTDOWN_TIMEOUT).await;
+            result.map(|_| ())
+        });
+
+        Ok(ServerHandle {
+            local_addr,
+            shutdown: ShutdownTrigger(Arc::new(shutdown_tx)),
+            task,
+        })
+    }
+}
+
+/// Triggers a graceful shutdown of a server.
+#[derive(Debug, Clone)]
+pub struct ShutdownTrigger(Arc<watch::Sender<bool>>);
+
+impl ShutdownTrigger {
+    /// Stop accepting new connections and close open connections once their
+    /// current request is answered.
+    pub fn shutdown(&self) {
+        self.0.send_replace(true);
+    }
+}
+
+/// A handle to a running server.
+///
+/// Awaiting the handle waits until the server has shut down (or failed).
+#[derive(Debug)]
+pub struct ServerHandle {
+    local_addr: Option<SocketAddr>,
+    shutdown: ShutdownTrigger,
+    task: JoinHandle<anyhow::Result<()>>,
+}
+
+impl ServerHandle {
+    /// The address of the first listener, or `None` if it is not a TCP
+    /// socket.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    /// Trigger a graceful shutdown of the server.
+    ///
+    /// The background tasks are stopped once all listeners have shut down.
+    /// Queued webhook events are still delivered and a running retention
+    /// sweep is completed, for at most 30 seconds.
+    pub fn shutdown(&self) {
+        self.shutdown.shutdown();
+    }
+
+    /// Return a trigger that can be used to shut down the server from
+    /// elsewhere.
+    pub fn shutdown_trigger(&self) -> ShutdownTrigger {
+        self.shutdown.clone()
+    }
+}
+
+impl Future for ServerHandle {
+    type Output = anyhow::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.task)
+            .poll(cx)
+            .map(|result| match result {
+                Ok(result) => result,
+                Err(e) => Err(anyhow!("Server task failed: {}", e)),
+            })
+    }
+}
@@ -9,18 +9,22 @@ use hyper::{service::Service, Body, Request, Response};
 use log::trace;
 
 use crate::{
-    config::ServerConfig,
+    config::{ListenerConfig, ServerConfig},
     handlers::handler,
     routing::{make_router, Router},
+    state::{BackgroundTasks, State},
 };
 
 // Note: Implementation based on `service_struct_impl.rs` example in the hyper repo.
 
-/// A `BackupService` wraps a configuration and a reference counted file system pool.
+/// A `BackupService` wraps the shared server state and the listener configuration.
 #[derive(Debug, Clone)]
 pub struct BackupService {
-    config: Arc<ServerConfig>,
+    state: Arc<State>,
+    listener: Arc<ListenerConfig>,
     router: Arc<Router>,
+    /// The user identity from the client certificate of the connection
+    client_identity: Option<Arc<str>>,
 }
 
 type PinBox<T> = Pin<Box<T>>;
@@ -38,26 +42,86 @@ impl Service<Request<Body>> for BackupService {
         trace!("BackupService::call");
 
         // Copy Arc references that will be moved into the future
-        let config = self.config.clone();
+        let state = self.state.clone();
+        let listener = self.listener.clone();
         let router = self.router.clone();
+        let client_identity = self.client_identity.clone();
 
         // Call handler
-        Box::pin(async move { handler(req, &router, &config).await })
+        Box::pin(async move {
+            handler(req, &router, &state, &listener, client_identity.as_deref()).await
+        })
     }
 }
 
+#[derive(Debug, Clone)]
 pub struct MakeBackupService {
-    config: Arc<ServerConfig>,
+    state: Arc<State>,
+    listener: Arc<ListenerConfig>,
     router: Arc<Router>,
 }
 
 impl MakeBackupService {
-    pub fn new(config: ServerConfig) -> Self {
+    /// Create a service for the default listener (configured through the
+    /// top-level settings like `listen_on` and `allow_browser`).
+    ///
+    /// This fails if the encryption keys cannot be loaded.
+    pub fn new(config: ServerConfig) -> anyhow::Result<Self> {
+        Ok(Self::from_state(Arc::new(State::new(config)?)))
+    }
+
+    /// Create a service for the default listener with existing state.
+    pub(crate) fn from_state(state: Arc<State>) -> Self {
+        let listener = state.config.default_listener();
         Self {
-            config: Arc::new(config),
+            state,
+            listener: Arc::new(listener),
             router: Arc::new(make_router()),
         }
     }
+
+    /// Spawn the background tasks (e.g. replication workers).
+    ///
+    /// This must be called once, from within a tokio runtime. Call
+    /// `BackgroundTasks::shutdown` on the result once the server has stopped,
+    /// so that queued webhook events are delivered.
+    pub fn spawn_background_tasks(&self) -> BackgroundTasks {
+        self.state.spawn_background_tasks()
+    }
+
+    /// Create a request service (as done by hyper for every connection).
+    #[cfg(feature = "tower")]
+    pub(crate) fn service(&self) -> BackupService {
+        self.connection_service(None)
+    }
+
+    /// Return the log privacy mode of the server.
+    #[cfg(feature = "tls")]
+    pub(crate) fn log_privacy(&self) -> &crate::privacy::LogPrivacy {
+        &self.state.log_privacy
+    }
+
+    /// Create a request service for a connection with the specified client
+    /// identity.
+    pub(crate) fn connection_service(&self, client_identity: Option<String>) -> BackupService {
+        BackupService {
+            state: self.state.clone(),
+            listener: self.listener.clone(),
+            router: self.router.clone(),
+            client_identity: client_identity.map(Arc::from),
+        }
+    }
+
+    /// Create a service for the specified listener.
+    ///
+    /// The returned service shares its config and state with `self`.
+    pub fn for_listener(&self, listener: ListenerConfig) -> Self {
+        Self {
+            state: self.state.clone(),
+            listener: Arc::new(listener),
+            router: self.router.clone(),
+        }
+    }
 }
 
 impl<T> Service<T> for MakeBackupService {
@@ -70,9 +134,7 @@ impl<T> Service<T> for MakeBackupService {
     }
 
     fn call(&mut self, _: T) -> Self::Future {
-        let config = self.config.clone();
-        let router = self.router.clone();
-        let fut = async move { Ok(BackupService { config, router }) };
-        Box::pin(fut)
+        let service = self.connection_service(None);
+        Box::pin(async move { Ok(service) })
     }
 }
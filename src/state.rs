@@ -0,0 +1,251 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::bail;
+use futures::future::join_all;
+use log::warn;
+use tokio::{sync::watch, task::JoinHandle};
+
+use crate::{
+    audit::AuditLog,
+    auth::Authenticator,
+    config::ServerConfig,
+    events::BackupEvent,
+    metrics::Metrics,
+    migration::Migrator,
+    privacy::LogPrivacy,
+    replication::Replicator,
+    retention,
+    storage::Storage,
+    tenants::{self, Tenant, TenantConfig},
+    webhooks::Webhooks,
+};
+
+/// State shared between all listeners and requests.
+#[derive(Debug)]
+pub struct State {
+    pub config: ServerConfig,
+    pub storage: Arc<Storage>,
+    pub metrics: Arc<Metrics>,
+    pub replicator: Option<Arc<Replicator>>,
+    pub audit_log: Option<Arc<AuditLog>>,
+    /// The Basic authentication (only if enabled)
+    pub auth: Option<Arc<Authenticator>>,
+    /// The read-through migration (only if enabled)
+    pub migrator: Option<Migrator>,
+    pub webhooks: Option<Arc<Webhooks>>,
+    pub tenants: Vec<Tenant>,
+    /// The log privacy mode of this server
+    pub log_privacy: LogPrivacy,
+}
+
+impl State {
+    /// Create the shared state.
+    pub fn new(config: ServerConfig) -> anyhow::Result<Self> {
+        let storage = Arc::new(Storage::from_config(&config)?);
+        Self::with_storage(config, storage)
+    }
+
+    /// Create the shared state with the specified storage.
+    pub fn with_storage(config: ServerConfig, storage: Arc<Storage>) -> anyhow::Result<Self> {
+        // Storage and handlers must hash backup IDs with the same key, so the
+        // storage's mode is used unless it lacks the configured one.
+        let log_privacy = if config.log_privacy.is_some() && !storage.log_privacy().enabled() {
+            LogPrivacy::new(config.log_privacy.as_ref())?
+        } else {
+            storage.log_privacy().clone()
+        };
+        if !config.tenants.is_empty() {
+            if config.replication.is_some() || config.migration.is_some() {
+                bail!("Replication and migration are not supported with tenants");
+            }
+            tenants::validate(&config.tenants, &config.backup_dir)?;
+        }
+        let replicator = config.replication.as_ref().map(|replication| {
+            Arc::new(Replicator::new(
+                replication,
+                storage.clone(),
+                log_privacy.clone(),
+            ))
+        });
+        let audit_log = match config.audit_log {
+            Some(ref audit_log) => Some(Arc::new(AuditLog::new(audit_log)?)),
+            None => None,
+        };
+        let auth = match config.auth {
+            Some(ref auth) => Some(Arc::new(Authenticator::new(auth)?)),
+            None => None,
+        };
+        let migrator = match config.migration {
+            Some(ref migration) if migration.enabled() => {
+                Some(Migrator::new(migration, log_privacy.clone())?)
+            }
+            _ => None,
+        };
+        let webhooks = match config.webhooks {
+            Some(ref webhooks) => Some(Arc::new(Webhooks::new(webhooks)?)),
+            None => None,
+        };
+        let mut state = Self {
+            config,
+            storage,
+            metrics: Arc::new(Metrics::default()),
+            replicator,
+            audit_log,
+            auth,
+            migrator,
+            webhooks,
+            tenants: vec![],
+            log_privacy,
+        };
+        state.tenants = state
+            .config
+            .tenants
+            .iter()
+            .map(|tenant| state.tenant(tenant))
+            .collect::<anyhow::Result<_>>()?;
+        Ok(state)
+    }
+
+    /// Create the state of a tenant.
+    ///
+    /// Metrics, the audit log and webhooks are shared with the tenant, and
+    /// so are the users unless the tenant has its own.
+    fn tenant(&self, tenant: &TenantConfig) -> anyhow::Result<Tenant> {
+        let config = self.config.tenant_config(tenant);
+        let storage =
+            Arc::new(Storage::from_config(&config)?.with_log_privacy(self.log_privacy.clone()));
+        let auth = match tenant.auth {
+            Some(ref auth) => Some(Arc::new(Authenticator::new(auth)?)),
+            None => self.auth.clone(),
+        };
+        let state = Self {
+            config,
+            storage,
+            metrics: self.metrics.clone(),
+            replicator: None,
+            audit_log: self.audit_log.clone(),
+            auth,
+            migrator: None,
+            webhooks: self.webhooks.clone(),
+            tenants: vec![],
+            log_privacy: self.log_privacy.clone(),
+        };
+        Ok(Tenant {
+            config: tenant.clone(),
+            state: Arc::new(state),
+        })
+    }
+
+    /// Spawn the background tasks (e.g. replication workers).
+    ///
+    /// This must be called from within a tokio runtime. The tasks run until
+    /// they are stopped with `BackgroundTasks::shutdown`.
+    pub fn spawn_background_tasks(self: &Arc<Self>) -> BackgroundTasks {
+        let (stop, stop_rx) = watch::channel(false);
+        let mut tasks = BackgroundTasks {
+            idle: vec![],
+            sweeps: vec![],
+            deliveries: vec![],
+            stop,
+            webhooks: self.webhooks.clone(),
+        };
+        // The replication queue is persistent, so its workers can be
+        // aborted at any time
+        if let Some(ref replicator) = self.replicator {
+            tasks.idle.extend(replicator.spawn(self.metrics.clone()));
+        }
+        if let Some(ref webhooks) = self.webhooks {
+            tasks
+                .deliveries
+                .extend(webhooks.spawn(self.metrics.clone()));
+        }
+        if let Some(ref auth) = self.auth {
+            tasks.idle.push(auth.spawn());
+        }
+        for tenant in &self.tenants {
+            // Tenants without their own users share the top-level users
+            if let (Some(ref auth), Some(_)) = (&tenant.state.auth, &tenant.config.auth) {
+                tasks.idle.push(auth.spawn());
+            }
+        }
+        if let Some(interval) = self.config.sweep_interval_secs {
+            let interval = Duration::from_secs(interval);
+            tasks
+                .sweeps
+                .push(retention::spawn(self.clone(), interval, stop_rx.clone()));
+            for tenant in &self.tenants {
+                tasks.sweeps.push(retention::spawn(
+                    tenant.state.clone(),
+                    interval,
+                    stop_rx.clone(),
+                ));
+            }
+        }
+        tasks
+    }
+
+    /// Notify all interested components about a backup change.
+    pub async fn emit(&self, event: &BackupEvent) {
+        if let Some(ref audit_log) = self.audit_log {
+            audit_log.record(event).await;
+        }
+        if !event.kind.is_change() {
+            return;
+        }
+        if let Some(ref replicator) = self.replicator {
+            replicator.enqueue(&event.backup_id).await;
+        }
+        if let Some(ref webhooks) = self.webhooks {
+            webhooks.notify(event, &self.metrics);
+        }
+    }
+}
+
+/// The background tasks spawned by `State::spawn_background_tasks`.
+#[derive(Debug)]
+pub struct BackgroundTasks {
+    /// Tasks that can be aborted at any time (e.g. reload timers)
+    idle: Vec<JoinHandle<()>>,
+    /// The periodic retention sweeps
+    sweeps: Vec<JoinHandle<()>>,
+    /// The webhook delivery workers
+    deliveries: Vec<JoinHandle<()>>,
+    /// Stops the retention sweeps between two runs
+    stop: watch::Sender<bool>,
+    webhooks: Option<Arc<Webhooks>>,
+}
+
+impl BackgroundTasks {
+    /// Stop all background tasks.
+    ///
+    /// A running retention sweep is completed and queued webhook events are
+    /// still delivered. Tasks that don't finish within `timeout` are aborted.
+    pub async fn shutdown(mut self, timeout: Duration) {
+        for task in &self.idle {
+            task.abort();
+        }
+        self.stop.send_replace(true);
+        let drain = async {
+            join_all(self.sweeps.iter_mut()).await;
+            // Sweeps emit events, so the queues are closed only afterwards
+            if let Some(ref webhooks) = self.webhooks {
+                webhooks.close();
+            }
+            join_all(self.deliveries.iter_mut()).await;
+        };
+        if tokio::time::timeout(timeout, drain).await.is_err() {
+            warn!(
+                "Background tasks did not finish within {:?}, aborting them",
+                timeout
+            );
+            self.abort();
+        }
+    }
+
+    /// Abort all background tasks immediately.
+    pub fn abort(&self) {
+        for task in self.idle.iter().chain(&self.sweeps).chain(&self.deliveries) {
+            task.abort();
+        }
+    }
+}
@@ -0,0 +1,960 @@
+//! Access to the stored backups.
+//!
+//! All reads and writes of backup files go through `Storage`, which takes
+//! care of atomic writes, of retaining prior versions and of the optional
+//! encryption at rest.
+//!
+//! Prior versions of a backup are hard links in
+//! `<backup_dir>/.versions/<backup_id>/<version>`, where the version is the
+//! time the version was stored (in milliseconds since the Unix epoch).
+//!
+//! If soft deletion is enabled, deleted backups (and their prior versions)
+//! are moved to `<backup_dir>/.trash/<backup_id>/<deleted_at>/` until the
+//! grace period has passed.
+//!
+//! If authentication is enabled, the owner of a backup (the user that
+//! created it) is stored in `<backup_dir>/.owners/<backup_id>`.
+
+use std::{
+    fs as std_fs,
+    io::{self, Error as IoError},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context};
+use futures::StreamExt;
+use hyper::Body;
+use log::{trace, warn};
+use rand::Rng;
+use tokio::{fs, io::AsyncReadExt, io::AsyncWriteExt};
+
+use crate::{
+    config::ServerConfig,
+    encryption::{Header, Keyring},
+    privacy::LogPrivacy,
+    retention,
+};
+
+/// The directory (inside the backup directory) where prior versions are kept.
+const VERSIONS_DIR: &str = ".versions";
+
+/// The directory (inside the backup directory) where deleted backups are kept.
+const TRASH_DIR: &str = ".trash";
+
+/// The directory (inside the backup directory) where backup owners are kept.
+const OWNERS_DIR: &str = ".owners";
+
+/// The backup storage.
+#[derive(Debug)]
+pub struct Storage {
+    backup_dir: PathBuf,
+    keyring: Option<Keyring>,
+    retained_versions: usize,
+    trash_grace: Option<Duration>,
+    log_privacy: LogPrivacy,
+}
+
+/// A retained prior version of a backup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupVersion {
+    /// The version ID
+    pub id: String,
+    /// The time this version was stored
+    pub stored_at: SystemTime,
+    /// The (decrypted) size in bytes
+    pub size: u64,
+}
+
+impl Storage {
+    pub fn new(backup_dir: PathBuf, keyring: Option<Keyring>) -> Self {
+        Self {
+            backup_dir,
+            keyring,
+            retained_versions: 0,
+            trash_grace: None,
+            log_privacy: LogPrivacy::default(),
+        }
+    }
+
+    /// Enable soft deletion: Deleted backups are kept in the trash for the
+    /// specified grace period.
+    pub fn with_trash_grace(mut self, trash_grace: Option<Duration>) -> Self {
+        self.trash_grace = trash_grace;
+        self
+    }
+
+    /// Set the number of prior versions retained per backup.
+    pub fn with_retained_versions(mut self, retained_versions: usize) -> Self {
+        self.retained_versions = retained_versions;
+        self
+    }
+
+    /// Set the log privacy mode used for log lines about this storage.
+    pub(crate) fn with_log_privacy(mut self, log_privacy: LogPrivacy) -> Self {
+        self.log_privacy = log_privacy;
+        self
+    }
+
+    /// Return the log privacy mode of this storage.
+    pub(crate) fn log_privacy(&self) -> &LogPrivacy {
+        &self.log_privacy
+    }
+
+    /// Create the storage for the configured backup directory, loading the
+    /// encryption keys if configured.
+    pub fn from_config(config: &ServerConfig) -> anyhow::Result<Self> {
+        let keyring = match config.encryption {
+            Some(ref encryption) => Some(Keyring::load(encryption)?),
+            None => None,
+        };
+        Ok(Self::new(config.backup_dir.clone(), keyring)
+            .with_retained_versions(config.retained_versions as usize)
+            .with_trash_grace(config.delete_grace_days.map(retention::days))
+            .with_log_privacy(LogPrivacy::new(config.log_privacy.as_ref())?))
+    }
+
+    /// Return the path of the backup file.
+    pub fn path(&self, backup_id: &str) -> PathBuf {
+        self.backup_dir.join(backup_id)
+    }
+
+    /// Return the IDs of all stored backups.
+    pub fn backup_ids(&self) -> io::Result<Vec<String>> {
+        let mut ids = vec![];
+        for entry in std_fs::read_dir(&self.backup_dir)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            if let Ok(name) = entry.file_name().into_string() {
+                if crate::handlers::backup_id_valid(&name) {
+                    ids.push(name);
+                }
+            }
+        }
+        Ok(ids)
+    }
+
+    /// Read and decrypt a backup.
+    pub async fn read(&self, backup_id: &str) -> io::Result<Vec<u8>> {
+        let data = fs::read(self.path(backup_id)).await?;
+        match self.keyring {
+            Some(ref keyring) => keyring
+                .decrypt(backup_id, &data)
+                .map_err(|e| IoError::new(io::ErrorKind::InvalidData, format!("{:#}", e))),
+            None => Ok(data),
+        }
+    }
+
+    /// Return the (decrypted) size of a backup in bytes.
+    pub async fn size(&self, backup_id: &str) -> io::Result<u64> {
+        self.size_of(&self.path(backup_id)).await
+    }
+
+    async fn size_of(&self, path: &Path) -> io::Result<u64> {
+        let mut file = fs::File::open(path).await?;
+        let len = file.metadata().await?.len();
+        if self.keyring.is_none() {
+            return Ok(len);
+        }
+        let mut prefix = [0; Header::PREFIX_LEN];
+        if len < prefix.len() as u64 {
+            return Ok(len);
+        }
+        file.read_exact(&mut prefix).await?;
+        match Header::len_from_prefix(&prefix) {
+            Some(header_len) => Ok(len.saturating_sub(Header::overhead_for(header_len) as u64)),
+            None => Ok(len),
+        }
+    }
+
+    /// Return the time a backup was last stored.
+    ///
+    /// This is the start of the retention period.
+    pub async fn stored_at(&self, backup_id: &str) -> io::Result<SystemTime> {
+        fs::metadata(self.path(backup_id)).await?.modified()
+    }
+
+    /// Set the time a backup was last stored (e.g. when importing backups).
+    pub async fn set_stored_at(&self, backup_id: &str, stored_at: SystemTime) -> io::Result<()> {
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .open(self.path(backup_id))
+            .await?;
+        file.into_std().await.set_modified(stored_at)
+    }
+
+    /// Store a backup from a request body.
+    ///
+    /// Return true if an existing backup was updated, or false if a new
+    /// backup was created.
+    pub async fn write(&self, backup_id: &str, mut body: Body) -> anyhow::Result<bool> {
+        match self.keyring {
+            Some(ref keyring) => {
+                // The whole backup is needed for encrypting it. The size has
+                // already been limited by the caller.
+                let plaintext = hyper::body::to_bytes(body)
+                    .await
+                    .context("Could not read body")?;
+                let data = keyring.encrypt(backup_id, &plaintext)?;
+                self.write_atomic(backup_id, |mut file| async move {
+                    file.write_all(&data)
+                        .await
+                        .context("Could not write temporary file")
+                })
+                .await
+            }
+            None => {
+                self.write_atomic(backup_id, |mut file| async move {
+                    while let Some(chunk_or_error) = body.next().await {
+                        let chunk = chunk_or_error.context("Could not read body chunk")?;
+                        file.write_all(&chunk)
+                            .await
+                            .context("Could not write chunk to temporary file")?
+                    }
+                    Ok(())
+                })
+                .await
+            }
+        }
+    }
+
+    /// Store a backup from memory.
+    ///
+    /// Return true if an existing backup was updated.
+    pub async fn write_data(&self, backup_id: &str, plaintext: &[u8]) -> anyhow::Result<bool> {
+        let data = match self.keyring {
+            Some(ref keyring) => keyring.encrypt(backup_id, plaintext)?,
+            None => plaintext.to_vec(),
+        };
+        self.write_atomic(backup_id, |mut file| async move {
+            file.write_all(&data)
+                .await
+                .context("Could not write temporary file")
+        })
+        .await
+    }
+
+    /// Re-encrypt a backup and its prior versions with the active key.
+    ///
+    /// This is not an upload: The files are replaced in place, keeping the
+    /// time they were stored, and no prior version is retained. Return false
+    /// if everything was already encrypted with the active key.
+    pub async fn reencrypt(&self, backup_id: &str) -> anyhow::Result<bool> {
+        let keyring = match self.keyring {
+            Some(ref keyring) => keyring,
+            None => bail!("Encryption is not configured"),
+        };
+        let mut reencrypted = reencrypt_file(keyring, backup_id, &self.path(backup_id))
+            .await
+            .context("Could not re-encrypt backup")?;
+        for (millis, path) in millis_entries(&self.versions_dir(backup_id)).await? {
+            reencrypted |= reencrypt_file(keyring, backup_id, &path)
+                .await
+                .with_context(|| format!("Could not re-encrypt version {}", millis))?;
+        }
+        Ok(reencrypted)
+    }
+
+    /// Delete a backup, including its prior versions.
+    ///
+    /// If soft deletion is enabled, the backup is moved to the trash.
+    pub async fn delete(&self, backup_id: &str) -> io::Result<()> {
+        if self.trash_grace.is_none() {
+            return self.remove(backup_id).await;
+        }
+        let trash_dir = self.trash_dir(backup_id);
+        fs::create_dir_all(&trash_dir).await?;
+        let mut millis = unix_millis(SystemTime::now());
+        let entry = loop {
+            let entry = trash_dir.join(millis.to_string());
+            match fs::create_dir(&entry).await {
+                Ok(()) => break entry,
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => millis += 1,
+                Err(e) => return Err(e),
+            }
+        };
+        if let Err(e) = fs::rename(self.path(backup_id), entry.join("backup")).await {
+            let _ = fs::remove_dir(&entry).await;
+            return Err(e);
+        }
+        match fs::rename(self.versions_dir(backup_id), entry.join("versions")).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        match fs::rename(self.owner_path(backup_id), entry.join("owner")).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Permanently delete a backup, including its prior versions.
+    pub async fn remove(&self, backup_id: &str) -> io::Result<()> {
+        fs::remove_file(self.path(backup_id)).await?;
+        match fs::remove_dir_all(self.versions_dir(backup_id)).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        self.set_owner(backup_id, None).await
+    }
+
+    fn trash_dir(&self, backup_id: &str) -> PathBuf {
+        self.backup_dir.join(TRASH_DIR).join(backup_id)
+    }
+
+    /// Restore the most recently deleted copy of a backup from the trash.
+    pub async fn restore(&self, backup_id: &str) -> anyhow::Result<()> {
+        let backup_path = self.path(backup_id);
+        if backup_path.exists() {
+            bail!("Backup {} exists, it must be deleted first", backup_id);
+        }
+        let trash_dir = self.trash_dir(backup_id);
+        let entry = match millis_entries(&trash_dir)
+            .await?
+            .into_iter()
+            .filter(|(_, entry)| entry.is_dir())
+            .max_by_key(|(millis, _)| *millis)
+        {
+            Some((_, entry)) => entry,
+            None => bail!("Backup {} is not in the trash", backup_id),
+        };
+        fs::rename(entry.join("backup"), &backup_path)
+            .await
+            .context("Could not restore backup")?;
+        let versions_dir = self.versions_dir(backup_id);
+        if entry.join("versions").exists() && !versions_dir.exists() {
+            fs::create_dir_all(self.backup_dir.join(VERSIONS_DIR)).await?;
+            fs::rename(entry.join("versions"), &versions_dir)
+                .await
+                .context("Could not restore prior versions")?;
+        }
+        if entry.join("owner").exists() {
+            fs::create_dir_all(self.backup_dir.join(OWNERS_DIR)).await?;
+            fs::rename(entry.join("owner"), self.owner_path(backup_id))
+                .await
+                .context("Could not restore owner")?;
+        }
+        fs::remove_dir_all(&entry).await?;
+        let _ = fs::remove_dir(&trash_dir).await;
+        Ok(())
+    }
+
+    /// Permanently delete trashed backups whose grace period has passed.
+    ///
+    /// If soft deletion is disabled, the whole trash is purged. Unexpected
+    /// files in the trash are skipped. Return the number of purged backups.
+    pub async fn purge_trash(&self) -> io::Result<usize> {
+        let entries = match std_fs::read_dir(self.backup_dir.join(TRASH_DIR)) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        };
+        let now = SystemTime::now();
+        let mut purged = 0;
+        for entry in entries {
+            let entry = entry?;
+            let trash_dir = entry.path();
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !entry.file_type()?.is_dir() {
+                warn!(
+                    "Skipping unexpected file in the trash: {}",
+                    self.log_privacy.id(&name)
+                );
+                continue;
+            }
+            for (millis, entry) in millis_entries(&trash_dir).await? {
+                if !entry.is_dir() {
+                    warn!(
+                        "Skipping unexpected file {} in the trash of backup {}",
+                        millis,
+                        self.log_privacy.id(&name)
+                    );
+                    continue;
+                }
+                let deleted_at = UNIX_EPOCH + Duration::from_millis(millis);
+                let expired = match self.trash_grace {
+                    Some(grace) => deleted_at + grace <= now,
+                    None => true,
+                };
+                if expired {
+                    fs::remove_dir_all(&entry).await?;
+                    purged += 1;
+                }
+            }
+            // Only succeeds if empty
+            let _ = fs::remove_dir(&trash_dir).await;
+        }
+        Ok(purged)
+    }
+
+    fn owner_path(&self, backup_id: &str) -> PathBuf {
+        self.backup_dir.join(OWNERS_DIR).join(backup_id)
+    }
+
+    /// Return the owner of a backup (if any).
+    pub async fn owner(&self, backup_id: &str) -> io::Result<Option<String>> {
+        match fs::read_to_string(self.owner_path(backup_id)).await {
+            Ok(owner) => Ok(Some(owner)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Set (or remove) the owner of a backup.
+    pub async fn set_owner(&self, backup_id: &str, owner: Option<&str>) -> io::Result<()> {
+        let path = self.owner_path(backup_id);
+        let owner = match owner {
+            Some(owner) => owner,
+            None => {
+                return match fs::remove_file(&path).await {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                    _ => Ok(()),
+                };
+            }
+        };
+        fs::create_dir_all(self.backup_dir.join(OWNERS_DIR)).await?;
+        let tmp_path = path.with_extension("tmp");
+        let mut file = create_file(&tmp_path).await?;
+        file.write_all(owner.as_bytes()).await?;
+        file.sync_all().await?;
+        fs::rename(&tmp_path, &path).await
+    }
+
+    /// Return the IDs of all owned backups with their owner.
+    pub async fn owners(&self) -> io::Result<Vec<(String, String)>> {
+        let mut entries = match fs::read_dir(self.backup_dir.join(OWNERS_DIR)).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        let mut owners = vec![];
+        while let Some(entry) = entries.next_entry().await? {
+            let backup_id = match entry.file_name().into_string() {
+                Ok(name) if crate::handlers::backup_id_valid(&name) => name,
+                _ => continue,
+            };
+            if let Some(owner) = self.owner(&backup_id).await? {
+                owners.push((backup_id, owner));
+            }
+        }
+        Ok(owners)
+    }
+
+    fn versions_dir(&self, backup_id: &str) -> PathBuf {
+        self.backup_dir.join(VERSIONS_DIR).join(backup_id)
+    }
+
+    fn version_path(&self, backup_id: &str, version: &str) -> io::Result<PathBuf> {
+        if version.is_empty() || !version.chars().all(|c| c.is_ascii_digit()) {
+            return Err(IoError::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid version: {}", version),
+            ));
+        }
+        Ok(self.versions_dir(backup_id).join(version))
+    }
+
+    /// Return the IDs of all backups with prior versions.
+    ///
+    /// This includes backups whose current version does not exist anymore.
+    pub fn versioned_backup_ids(&self) -> io::Result<Vec<String>> {
+        let entries = match std_fs::read_dir(self.backup_dir.join(VERSIONS_DIR)) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        let mut ids = vec![];
+        for entry in entries {
+            if let Ok(name) = entry?.file_name().into_string() {
+                if crate::handlers::backup_id_valid(&name) {
+                    ids.push(name);
+                }
+            }
+        }
+        Ok(ids)
+    }
+
+    /// Return the prior versions of a backup, newest first.
+    pub async fn versions(&self, backup_id: &str) -> io::Result<Vec<BackupVersion>> {
+        let mut versions = vec![];
+        for (millis, path) in millis_entries(&self.versions_dir(backup_id)).await? {
+            versions.push(BackupVersion {
+                id: millis.to_string(),
+                stored_at: UNIX_EPOCH + Duration::from_millis(millis),
+                size: self.size_of(&path).await?,
+            });
+        }
+        versions.sort_by_key(|version| std::cmp::Reverse(version.stored_at));
+        Ok(versions)
+    }
+
+    /// Read and decrypt a prior version of a backup.
+    pub async fn read_version(&self, backup_id: &str, version: &str) -> io::Result<Vec<u8>> {
+        let data = fs::read(self.version_path(backup_id, version)?).await?;
+        match self.keyring {
+            Some(ref keyring) => keyring
+                .decrypt(backup_id, &data)
+                .map_err(|e| IoError::new(io::ErrorKind::InvalidData, format!("{:#}", e))),
+            None => Ok(data),
+        }
+    }
+
+    /// Make a prior version the current version of a backup.
+    ///
+    /// The promoted version keeps the time it was stored (and thus expires
+    /// like it would have as the current version). The replaced current
+    /// version is retained as a prior version.
+    pub async fn promote_version(&self, backup_id: &str, version: &str) -> anyhow::Result<()> {
+        let plaintext = self
+            .read_version(backup_id, version)
+            .await
+            .context("Could not read version")?;
+        let stored_at = fs::metadata(self.version_path(backup_id, version)?)
+            .await?
+            .modified()?;
+        self.write_data(backup_id, &plaintext).await?;
+        self.set_stored_at(backup_id, stored_at)
+            .await
+            .context("Could not set the time the version was stored")?;
+        match fs::remove_file(self.version_path(backup_id, version)?).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                Err(e).context("Could not remove promoted version")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Remove the prior versions that exceed the number of retained versions
+    /// or that were stored before `cutoff`.
+    ///
+    /// Return the number of removed versions.
+    pub async fn prune_versions(
+        &self,
+        backup_id: &str,
+        cutoff: Option<SystemTime>,
+    ) -> io::Result<usize> {
+        let versions = self.versions(backup_id).await?;
+        let mut pruned = 0;
+        for (i, version) in versions.iter().enumerate() {
+            let expired = cutoff.is_some_and(|cutoff| version.stored_at < cutoff);
+            if i >= self.retained_versions || expired {
+                fs::remove_file(self.version_path(backup_id, &version.id)?).await?;
+                pruned += 1;
+            }
+        }
+        if pruned > 0 && pruned == versions.len() {
+            fs::remove_dir(self.versions_dir(backup_id)).await?;
+        }
+        Ok(pruned)
+    }
+
+    /// Keep the current version of a backup as a prior version.
+    async fn retain_current(&self, backup_id: &str) -> io::Result<()> {
+        let path = self.path(backup_id);
+        let modified = fs::metadata(&path).await?.modified()?;
+        let versions_dir = self.versions_dir(backup_id);
+        fs::create_dir_all(&versions_dir).await?;
+        let mut millis = unix_millis(modified);
+        loop {
+            // A hard link keeps the current version available to readers
+            // until it is replaced.
+            match fs::hard_link(&path, versions_dir.join(millis.to_string())).await {
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => millis += 1,
+                res => return res,
+            }
+        }
+    }
+
+    /// Write a backup to a temporary file and move it to its final location
+    /// once complete. This prevents incomplete backups from being persisted.
+    async fn write_atomic<F, Fut>(&self, backup_id: &str, write: F) -> anyhow::Result<bool>
+    where
+        F: FnOnce(fs::File) -> Fut,
+        Fut: std::future::Future<Output = anyhow::Result<()>>,
+    {
+        let backup_path = self.path(backup_id);
+        let backup_path_dl = temp_path(&backup_path);
+        trace!(
+            "Writing temporary upload for {}",
+            self.log_privacy.id(backup_id)
+        );
+        if backup_path_dl.exists() {
+            bail!("Random upload path already exists!");
+        }
+
+        // Create the empty download file to ensure correct permissions before
+        // writing the data
+        let backup_file_dl = create_file(&backup_path_dl)
+            .await
+            .context("Could not create temporary file")?;
+
+        // Write data to temporary file
+        if let Err(e) = write(backup_file_dl).await {
+            let _ = fs::remove_file(&backup_path_dl).await;
+            return Err(e);
+        }
+        trace!("Wrote temp backup for {}", self.log_privacy.id(backup_id));
+
+        // Move temporary file to final location
+        let updated = backup_path.exists() && backup_path.is_file();
+        if updated && self.retained_versions > 0 {
+            if let Err(e) = self.retain_current(backup_id).await {
+                let _ = fs::remove_file(&backup_path_dl).await;
+                return Err(e).context("Could not retain prior version");
+            }
+        }
+        fs::rename(&backup_path_dl, &backup_path)
+            .await
+            .context("Could not move temporary backup to final location")?;
+        trace!(
+            "Moved temp backup for {} to final location",
+            self.log_privacy.id(backup_id)
+        );
+        if updated {
+            if let Err(e) = self.prune_versions(backup_id, None).await {
+                warn!(
+                    "Could not prune versions of backup {}: {}",
+                    self.log_privacy.id(backup_id),
+                    e
+                );
+            }
+        }
+
+        Ok(updated)
+    }
+}
+
+/// Return a random temporary path next to `path`.
+fn temp_path(path: &Path) -> PathBuf {
+    let random_ext: String = {
+        let mut rng = rand::thread_rng();
+        std::iter::repeat(())
+            .map(|_| rng.sample(rand::distributions::Alphanumeric))
+            .map(char::from)
+            .take(10)
+            .collect()
+    };
+    path.with_extension(random_ext)
+}
+
+/// Re-encrypt a backup file with the active key, keeping its modification
+/// time.
+///
+/// Return false if the file was already encrypted with the active key.
+async fn reencrypt_file(keyring: &Keyring, backup_id: &str, path: &Path) -> anyhow::Result<bool> {
+    let data = fs::read(path).await.context("Could not read file")?;
+    if let Some(header) = Header::parse(&data)? {
+        if header.key_id == keyring.active_key_id() {
+            return Ok(false);
+        }
+    }
+    let data = keyring.encrypt(backup_id, &keyring.decrypt(backup_id, &data)?)?;
+    let modified = fs::metadata(path).await?.modified()?;
+    let tmp_path = temp_path(path);
+    let result = async {
+        let mut file = create_file(&tmp_path).await?;
+        file.write_all(&data).await?;
+        file.sync_all().await?;
+        file.into_std().await.set_modified(modified)?;
+        fs::rename(&tmp_path, path).await
+    }
+    .await;
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path).await;
+    }
+    result.context("Could not replace file")?;
+    Ok(true)
+}
+
+pub(crate) fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Return the entries of a directory that are named by a timestamp in
+/// milliseconds.
+async fn millis_entries(dir: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut entries = match fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let mut result = vec![];
+    while let Some(entry) = entries.next_entry().await? {
+        if let Some(millis) = entry.file_name().to_str().and_then(|n| n.parse().ok()) {
+            result.push((millis, entry.path()));
+        }
+    }
+    Ok(result)
+}
+
+// Create a file with permissions set to 0600.
+async fn create_file(path: &Path) -> Result<fs::File, IoError> {
+    let file = fs::File::create(path).await?;
+    let mut perms = file.metadata().await?.permissions();
+    perms.set_mode(0o600);
+    file.set_permissions(perms).await?;
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::encryption::EncryptionConfig;
+
+    const BACKUP_ID: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    /// A keyring with only the "new" key (after the old one was retired).
+    fn new_keyring(dir: &Path) -> Keyring {
+        let key_file = dir.join("new-keys");
+        std_fs::write(
+            &key_file,
+            "new 0000000000000000000000000000000000000000000000000000000000000002\n",
+        )
+        .unwrap();
+        Keyring::load(&EncryptionConfig {
+            key_file,
+            key_id: "new".into(),
+        })
+        .unwrap()
+    }
+
+    fn keyring(dir: &Path, key_id: &str) -> Keyring {
+        let key_file = dir.join("keys");
+        std_fs::write(
+            &key_file,
+            "old 0000000000000000000000000000000000000000000000000000000000000001\n\
+             new 0000000000000000000000000000000000000000000000000000000000000002\n",
+        )
+        .unwrap();
+        Keyring::load(&EncryptionConfig {
+            key_file,
+            key_id: key_id.into(),
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn encrypted_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Storage::new(dir.path().to_path_buf(), Some(keyring(dir.path(), "old")));
+        assert!(!storage
+            .write(BACKUP_ID, Body::from("sekurkopio"))
+            .await
+            .unwrap());
+        let raw = std_fs::read(storage.path(BACKUP_ID)).unwrap();
+        assert!(!raw.windows(10).any(|w| w == b"sekurkopio"));
+        assert_eq!(storage.read(BACKUP_ID).await.unwrap(), b"sekurkopio");
+        assert_eq!(storage.size(BACKUP_ID).await.unwrap(), 10);
+        assert_eq!(storage.backup_ids().unwrap(), vec![BACKUP_ID.to_string()]);
+    }
+
+    #[tokio::test]
+    async fn reencrypt_with_new_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let old = Storage::new(dir.path().to_path_buf(), Some(keyring(dir.path(), "old")));
+        old.write_data(BACKUP_ID, b"sekurkopio").await.unwrap();
+
+        let new = Storage::new(dir.path().to_path_buf(), Some(keyring(dir.path(), "new")));
+        assert!(new.reencrypt(BACKUP_ID).await.unwrap());
+        assert!(!new.reencrypt(BACKUP_ID).await.unwrap());
+        let raw = std_fs::read(new.path(BACKUP_ID)).unwrap();
+        assert_eq!(Header::parse(&raw).unwrap().unwrap().key_id, "new");
+        assert_eq!(new.read(BACKUP_ID).await.unwrap(), b"sekurkopio");
+    }
+
+    #[tokio::test]
+    async fn reencrypt_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let old = Storage::new(dir.path().to_path_buf(), Some(keyring(dir.path(), "old")))
+            .with_retained_versions(2);
+        old.write_data(BACKUP_ID, b"v1").await.unwrap();
+        old.write_data(BACKUP_ID, b"v2").await.unwrap();
+        let stored_at = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        old.set_stored_at(BACKUP_ID, stored_at).await.unwrap();
+        let versions = old.versions(BACKUP_ID).await.unwrap();
+        assert_eq!(versions.len(), 1);
+
+        let new = Storage::new(dir.path().to_path_buf(), Some(keyring(dir.path(), "new")))
+            .with_retained_versions(2);
+        assert!(new.reencrypt(BACKUP_ID).await.unwrap());
+        assert!(!new.reencrypt(BACKUP_ID).await.unwrap());
+
+        // The rotation is neither an upload nor a new version
+        assert_eq!(new.stored_at(BACKUP_ID).await.unwrap(), stored_at);
+        assert_eq!(new.versions(BACKUP_ID).await.unwrap(), versions);
+
+        // Everything is readable without the old key
+        let retired = Storage::new(dir.path().to_path_buf(), Some(new_keyring(dir.path())));
+        assert_eq!(retired.read(BACKUP_ID).await.unwrap(), b"v2");
+        assert_eq!(
+            retired
+                .read_version(BACKUP_ID, &versions[0].id)
+                .await
+                .unwrap(),
+            b"v1"
+        );
+    }
+
+    #[tokio::test]
+    async fn reencrypt_plaintext() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = Storage::new(dir.path().to_path_buf(), None);
+        plain.write_data(BACKUP_ID, b"sekurkopio").await.unwrap();
+        assert_eq!(plain.size(BACKUP_ID).await.unwrap(), 10);
+
+        let encrypted = Storage::new(dir.path().to_path_buf(), Some(keyring(dir.path(), "new")));
+        assert_eq!(encrypted.read(BACKUP_ID).await.unwrap(), b"sekurkopio");
+        assert_eq!(encrypted.size(BACKUP_ID).await.unwrap(), 10);
+        assert!(encrypted.reencrypt(BACKUP_ID).await.unwrap());
+        assert_ne!(
+            std_fs::read(encrypted.path(BACKUP_ID)).unwrap(),
+            b"sekurkopio"
+        );
+        assert_eq!(encrypted.read(BACKUP_ID).await.unwrap(), b"sekurkopio");
+    }
+
+    #[tokio::test]
+    async fn retain_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Storage::new(dir.path().to_path_buf(), None).with_retained_versions(2);
+        for data in &["v1", "v2", "v3", "v4"] {
+            storage
+                .write_data(BACKUP_ID, data.as_bytes())
+                .await
+                .unwrap();
+        }
+        assert_eq!(storage.read(BACKUP_ID).await.unwrap(), b"v4");
+        let versions = storage.versions(BACKUP_ID).await.unwrap();
+        assert_eq!(versions.len(), 2);
+        assert!(versions[0].stored_at >= versions[1].stored_at);
+        assert_eq!(
+            storage
+                .read_version(BACKUP_ID, &versions[0].id)
+                .await
+                .unwrap(),
+            b"v3"
+        );
+        assert_eq!(
+            storage
+                .read_version(BACKUP_ID, &versions[1].id)
+                .await
+                .unwrap(),
+            b"v2"
+        );
+        assert_eq!(storage.backup_ids().unwrap(), vec![BACKUP_ID.to_string()]);
+        assert!(storage.read_version(BACKUP_ID, "../x").await.is_err());
+
+        // Promote
+        let stored_at = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        std_fs::File::options()
+            .write(true)
+            .open(storage.version_path(BACKUP_ID, &versions[1].id).unwrap())
+            .unwrap()
+            .set_modified(stored_at)
+            .unwrap();
+        storage
+            .promote_version(BACKUP_ID, &versions[1].id)
+            .await
+            .unwrap();
+        assert_eq!(storage.read(BACKUP_ID).await.unwrap(), b"v2");
+        assert_eq!(storage.stored_at(BACKUP_ID).await.unwrap(), stored_at);
+        let versions = storage.versions(BACKUP_ID).await.unwrap();
+        let contents: Vec<_> = futures::future::join_all(
+            versions
+                .iter()
+                .map(|v| storage.read_version(BACKUP_ID, &v.id)),
+        )
+        .await
+        .into_iter()
+        .map(Result::unwrap)
+        .collect();
+        assert_eq!(contents, vec![b"v4".to_vec(), b"v3".to_vec()]);
+
+        // Delete removes all versions
+        storage.delete(BACKUP_ID).await.unwrap();
+        assert!(storage.versions(BACKUP_ID).await.unwrap().is_empty());
+        assert!(storage.versioned_backup_ids().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn soft_delete_and_restore() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Storage::new(dir.path().to_path_buf(), None)
+            .with_retained_versions(1)
+            .with_trash_grace(Some(Duration::from_secs(3600)));
+        storage.write_data(BACKUP_ID, b"v1").await.unwrap();
+        storage.write_data(BACKUP_ID, b"v2").await.unwrap();
+        storage.delete(BACKUP_ID).await.unwrap();
+        assert!(!storage.path(BACKUP_ID).exists());
+        assert!(storage.backup_ids().unwrap().is_empty());
+        assert!(storage.versions(BACKUP_ID).await.unwrap().is_empty());
+
+        // Grace period has not passed yet
+        assert_eq!(storage.purge_trash().await.unwrap(), 0);
+
+        // Restore is refused while a new backup exists
+        storage.write_data(BACKUP_ID, b"new").await.unwrap();
+        assert!(storage.restore(BACKUP_ID).await.is_err());
+        storage.remove(BACKUP_ID).await.unwrap();
+
+        storage.restore(BACKUP_ID).await.unwrap();
+        assert_eq!(storage.read(BACKUP_ID).await.unwrap(), b"v2");
+        assert_eq!(storage.versions(BACKUP_ID).await.unwrap().len(), 1);
+        assert!(storage.restore(BACKUP_ID).await.is_err());
+        assert!(!dir.path().join(TRASH_DIR).join(BACKUP_ID).exists());
+    }
+
+    #[tokio::test]
+    async fn purge_trash() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Storage::new(dir.path().to_path_buf(), None)
+            .with_trash_grace(Some(Duration::from_secs(0)));
+        storage.write_data(BACKUP_ID, b"v1").await.unwrap();
+        storage.delete(BACKUP_ID).await.unwrap();
+        assert_eq!(storage.purge_trash().await.unwrap(), 1);
+        assert!(storage.restore(BACKUP_ID).await.is_err());
+        assert!(!dir.path().join(TRASH_DIR).join(BACKUP_ID).exists());
+
+        // Unexpected files are skipped
+        storage.write_data(BACKUP_ID, b"v2").await.unwrap();
+        storage.delete(BACKUP_ID).await.unwrap();
+        std_fs::write(dir.path().join(TRASH_DIR).join("stray"), b"").unwrap();
+        std_fs::write(dir.path().join(TRASH_DIR).join(BACKUP_ID).join("1"), b"").unwrap();
+        assert_eq!(storage.purge_trash().await.unwrap(), 1);
+        assert!(dir.path().join(TRASH_DIR).join("stray").exists());
+    }
+
+    #[tokio::test]
+    async fn owners() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Storage::new(dir.path().to_path_buf(), None)
+            .with_trash_grace(Some(Duration::from_secs(3600)));
+        storage.write_data(BACKUP_ID, b"data").await.unwrap();
+        assert_eq!(storage.owner(BACKUP_ID).await.unwrap(), None);
+        storage.set_owner(BACKUP_ID, Some("alice")).await.unwrap();
+        assert_eq!(
+            storage.owner(BACKUP_ID).await.unwrap().as_deref(),
+            Some("alice")
+        );
+        assert_eq!(
+            storage.owners().await.unwrap(),
+            vec![(BACKUP_ID.to_string(), "alice".to_string())]
+        );
+
+        // The owner is kept in the trash
+        storage.delete(BACKUP_ID).await.unwrap();
+        assert!(storage.owners().await.unwrap().is_empty());
+        storage.restore(BACKUP_ID).await.unwrap();
+        assert_eq!(
+            storage.owner(BACKUP_ID).await.unwrap().as_deref(),
+            Some("alice")
+        );
+
+        storage.remove(BACKUP_ID).await.unwrap();
+        assert_eq!(storage.owner(BACKUP_ID).await.unwrap(), None);
+    }
+}
@@ -0,0 +1,193 @@
+//! Serving several tenants from one process.
+//!
+//! Every tenant has its own backup directory, limits and user agent policy.
+//! Requests are assigned to a tenant by their `Host` header and/or a path
+//! prefix. Requests that match no tenant are served with the top-level
+//! settings.
+
+use std::{collections::HashSet, path::PathBuf, sync::Arc};
+
+use anyhow::bail;
+use hyper::{header, Body, Request};
+use serde_derive::Deserialize;
+
+use crate::{
+    auth::AuthConfig, routing::strip_path_prefix, state::State, user_agent::UserAgentPolicy,
+};
+
+/// The configuration of a tenant.
+///
+/// Settings that are not set are inherited from the top-level config.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct TenantConfig {
+    /// A unique name for this tenant
+    pub name: String,
+    /// The host names of this tenant (matched against the `Host` header)
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    /// The path prefix of this tenant (e.g. "/acme")
+    pub path_prefix: Option<String>,
+    /// The path to the directory where the tenant's backups are stored
+    pub backup_dir: PathBuf,
+    /// The max file size for backups
+    pub max_backup_bytes: Option<u64>,
+    /// The number of days a backup will be retained
+    pub retention_days: Option<u32>,
+    /// The user agent policy
+    pub user_agent: Option<UserAgentPolicy>,
+    /// The Basic authentication settings (e.g. a separate htpasswd file)
+    pub auth: Option<AuthConfig>,
+}
+
+impl TenantConfig {
+    /// Return whether a request with the specified host and path belongs to
+    /// this tenant, and if so, the path without the tenant's prefix.
+    fn matches<'a>(&self, host: Option<&str>, path: &'a str) -> Option<&'a str> {
+        if !self.hosts.is_empty() {
+            let host = host?;
+            if !self.hosts.iter().any(|h| h.eq_ignore_ascii_case(host)) {
+                return None;
+            }
+        }
+        match self.path_prefix {
+            Some(ref prefix) => strip_path_prefix(path, prefix),
+            None => Some(path),
+        }
+    }
+}
+
+/// A tenant with its own state.
+#[derive(Debug)]
+pub struct Tenant {
+    pub config: TenantConfig,
+    pub state: Arc<State>,
+}
+
+/// Check that the tenants can be told apart and don't share backups.
+pub fn validate(tenants: &[TenantConfig], backup_dir: &std::path::Path) -> anyhow::Result<()> {
+    let mut names = HashSet::new();
+    let mut dirs = HashSet::new();
+    dirs.insert(backup_dir);
+    for tenant in tenants {
+        if !names.insert(&tenant.name) {
+            bail!("Duplicate tenant name: {}", tenant.name);
+        }
+        if tenant.hosts.is_empty() && tenant.path_prefix.is_none() {
+            bail!("Tenant {} needs hosts or a path_prefix", tenant.name);
+        }
+        if let Some(ref prefix) = tenant.path_prefix {
+            if !prefix.starts_with('/') || prefix.len() < 2 {
+                bail!("Invalid path prefix of tenant {}: {}", tenant.name, prefix);
+            }
+        }
+        if !dirs.insert(&tenant.backup_dir) {
+            bail!(
+                "The backup directory of tenant {} is used more than once",
+                tenant.name
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Return the host name of a request (without the port).
+fn request_host(req: &Request<Body>) -> Option<&str> {
+    let host = req
+        .headers()
+        .get(header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| req.uri().host())?;
+    Some(strip_port(host))
+}
+
+fn strip_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        // IPv6 address
+        return match host.find(']') {
+            Some(end) => &host[..=end],
+            None => host,
+        };
+    }
+    host.split(':').next().unwrap_or(host)
+}
+
+/// Select the tenant of a request.
+///
+/// Return the tenant's state (or `default` if no tenant matches) and the
+/// request path without the tenant's prefix.
+pub fn select<'s, 'r>(
+    tenants: &'s [Tenant],
+    default: &'s State,
+    req: &'r Request<Body>,
+) -> (&'s State, &'r str) {
+    let host = request_host(req);
+    let path = req.uri().path();
+    tenants
+        .iter()
+        .find_map(|tenant| {
+            tenant
+                .config
+                .matches(host, path)
+                .map(|path| (&*tenant.state, path))
+        })
+        .unwrap_or((default, path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tenant(hosts: &[&str], path_prefix: Option<&str>) -> TenantConfig {
+        TenantConfig {
+            name: "acme".into(),
+            hosts: hosts.iter().map(|h| h.to_string()).collect(),
+            path_prefix: path_prefix.map(Into::into),
+            backup_dir: "/tmp/acme".into(),
+            max_backup_bytes: None,
+            retention_days: None,
+            user_agent: None,
+            auth: None,
+        }
+    }
+
+    #[test]
+    fn host_without_port() {
+        assert_eq!(strip_port("safe.example.com"), "safe.example.com");
+        assert_eq!(strip_port("safe.example.com:8443"), "safe.example.com");
+        assert_eq!(strip_port("[::1]:3000"), "[::1]");
+    }
+
+    #[test]
+    fn match_tenants() {
+        let by_host = tenant(&["safe.acme.example"], None);
+        assert_eq!(
+            by_host.matches(Some("SAFE.acme.example"), "/config"),
+            Some("/config")
+        );
+        assert_eq!(by_host.matches(Some("other.example"), "/config"), None);
+        assert_eq!(by_host.matches(None, "/config"), None);
+
+        let by_prefix = tenant(&[], Some("/acme"));
+        assert_eq!(by_prefix.matches(None, "/acme/config"), Some("/config"));
+        assert_eq!(by_prefix.matches(None, "/acme"), Some("/"));
+        assert_eq!(by_prefix.matches(None, "/acmex/config"), None);
+
+        let both = tenant(&["safe.example"], Some("/acme"));
+        assert_eq!(
+            both.matches(Some("safe.example"), "/acme/config"),
+            Some("/config")
+        );
+        assert_eq!(both.matches(Some("safe.example"), "/config"), None);
+    }
+
+    #[test]
+    fn validate_tenants() {
+        let backup_dir = std::path::Path::new("/tmp/default");
+        assert!(validate(&[tenant(&["a.example"], None)], backup_dir).is_ok());
+        assert!(validate(&[tenant(&[], None)], backup_dir).is_err());
+        assert!(validate(&[tenant(&[], Some("acme"))], backup_dir).is_err());
+        let mut other = tenant(&["b.example"], None);
+        other.name = "other".into();
+        assert!(validate(&[tenant(&["a.example"], None), other], backup_dir).is_err());
+    }
+}
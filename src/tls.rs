@@ -0,0 +1,292 @@
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    pin::Pin,
+    sync::Arc,
+    task::{Context as TaskContext, Poll},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context};
+use futures::{channel::mpsc, Future, SinkExt, Stream};
+use log::{debug, warn};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+use tokio_rustls::{
+    rustls::{
+        self,
+        server::{AllowAnyAuthenticatedClient, UnparsedCertRevocationList},
+        Certificate, PrivateKey, RootCertStore,
+    },
+    server::TlsStream,
+    TlsAcceptor,
+};
+use x509_parser::{certificate::X509Certificate, extensions::GeneralName, prelude::FromDer};
+
+use crate::{
+    config::{ClientIdentity, TlsConfig},
+    privacy::LogPrivacy,
+};
+
+/// The number of established TLS connections that may be queued before
+/// accepting new TCP connections is paused.
+const ACCEPT_QUEUE_SIZE: usize = 64;
+
+/// The time a client may take to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Create a TLS acceptor from the certificate and key files.
+///
+/// If a client CA is configured, clients must present a certificate issued
+/// by it that is not revoked.
+pub fn make_acceptor(config: &TlsConfig) -> anyhow::Result<TlsAcceptor> {
+    let certs = load_certs(&config.cert_file)?;
+    let key = load_key(&config.key_file)?;
+    let builder = rustls::ServerConfig::builder().with_safe_defaults();
+    let builder = match config.client_ca_file {
+        Some(ref client_ca_file) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(client_ca_file)? {
+                roots
+                    .add(&cert)
+                    .with_context(|| format!("Invalid client CA in {:?}", client_ca_file))?;
+            }
+            let crls = match config.client_crl_file {
+                Some(ref path) => load_crls(path)?,
+                None => vec![],
+            };
+            let verifier = AllowAnyAuthenticatedClient::new(roots)
+                .with_crls(crls)
+                .map_err(|e| anyhow!("Invalid client certificate revocation list: {:?}", e))?;
+            builder.with_client_cert_verifier(verifier.boxed())
+        }
+        None if config.client_crl_file.is_some() || config.client_identity.is_some() => {
+            bail!("Client certificate settings require a client_ca_file")
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut tls_config = builder
+        .with_single_cert(certs, key)
+        .context("Invalid TLS certificate or key")?;
+    tls_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(tls_config)))
+}
+
+fn load_certs(path: &std::path::Path) -> anyhow::Result<Vec<Certificate>> {
+    let file = File::open(path).with_context(|| format!("Could not open {:?}", path))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .with_context(|| format!("Could not read certificates from {:?}", path))?;
+    if certs.is_empty() {
+        bail!("No certificates found in {:?}", path);
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_crls(path: &std::path::Path) -> anyhow::Result<Vec<UnparsedCertRevocationList>> {
+    let file = File::open(path).with_context(|| format!("Could not open {:?}", path))?;
+    let crls = rustls_pemfile::crls(&mut BufReader::new(file))
+        .with_context(|| format!("Could not read revocation lists from {:?}", path))?;
+    if crls.is_empty() {
+        bail!("No revocation lists found in {:?}", path);
+    }
+    Ok(crls.into_iter().map(UnparsedCertRevocationList).collect())
+}
+
+fn load_key(path: &std::path::Path) -> anyhow::Result<PrivateKey> {
+    let file = File::open(path).with_context(|| format!("Could not open {:?}", path))?;
+    let items = rustls_pemfile::read_all(&mut BufReader::new(file))
+        .with_context(|| format!("Could not read private key from {:?}", path))?;
+    for item in items {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {}
+        }
+    }
+    bail!("No private key found in {:?}", path)
+}
+
+/// Return the user identity from a (verified) client certificate.
+fn client_identity(cert: &Certificate, field: ClientIdentity) -> Option<String> {
+    let (_, cert) = X509Certificate::from_der(&cert.0).ok()?;
+    if field == ClientIdentity::CommonName {
+        let common_name = cert.subject().iter_common_name().next()?;
+        return common_name.as_str().ok().map(String::from);
+    }
+    let san = cert.subject_alternative_name().ok()??;
+    san.value
+        .general_names
+        .iter()
+        .find_map(|name| match (field, name) {
+            (ClientIdentity::SanEmail, GeneralName::RFC822Name(value))
+            | (ClientIdentity::SanDns, GeneralName::DNSName(value))
+            | (ClientIdentity::SanUri, GeneralName::URI(value)) => Some(value.to_string()),
+            _ => None,
+        })
+}
+
+/// An established TLS connection.
+pub struct TlsConnection {
+    stream: TlsStream<TcpStream>,
+    /// The user identity from the client certificate (if configured)
+    pub client_identity: Option<String>,
+}
+
+impl AsyncRead for TlsConnection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TlsConnection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// Accept TCP connections and perform TLS handshakes.
+///
+/// Handshakes run in their own tasks with a timeout, so that a slow or
+/// malicious client cannot block the accept loop. Failed handshakes are
+/// logged and dropped, and so are connections whose client certificate lacks
+/// the identity field. Once `shutdown` completes, the listener is closed.
+pub fn incoming(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    identity_field: Option<ClientIdentity>,
+    log_privacy: LogPrivacy,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> impl Stream<Item = Result<TlsConnection, std::io::Error>> {
+    let (tx, rx) = mpsc::channel(ACCEPT_QUEUE_SIZE);
+    tokio::spawn(async move {
+        tokio::pin!(shutdown);
+        loop {
+            let accepted = tokio::select! {
+                () = &mut shutdown => break,
+                accepted = listener.accept() => accepted,
+            };
+            let (stream, peer) = match accepted {
+                Ok(conn) => conn,
+                Err(e) => {
+                    warn!("Could not accept connection: {}", e);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            if tx.is_closed() {
+                break;
+            }
+            let acceptor = acceptor.clone();
+            let log_privacy = log_privacy.clone();
+            let mut tx = tx.clone();
+            tokio::spawn(async move {
+                let stream = match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(e)) => {
+                        debug!(
+                            "TLS handshake with {} failed: {}",
+                            log_privacy.addr(peer),
+                            e
+                        );
+                        return;
+                    }
+                    Err(_) => {
+                        debug!("TLS handshake with {} timed out", log_privacy.addr(peer));
+                        return;
+                    }
+                };
+                let client_identity = match identity_field {
+                    Some(field) => {
+                        let identity = stream
+                            .get_ref()
+                            .1
+                            .peer_certificates()
+                            .and_then(|certs| certs.first())
+                            .and_then(|cert| client_identity(cert, field));
+                        if identity.is_none() {
+                            warn!(
+                                "Client certificate of {} has no {:?}, closing connection",
+                                log_privacy.addr(peer),
+                                field
+                            );
+                            return;
+                        }
+                        identity
+                    }
+                    None => None,
+                };
+                let _ = tx
+                    .send(Ok(TlsConnection {
+                        stream,
+                        client_identity,
+                    }))
+                    .await;
+            });
+        }
+    });
+    rx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> std::path::PathBuf {
+        std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name)
+    }
+
+    #[test]
+    fn identity_from_certificate() {
+        let cert = load_certs(&fixture("client-alice.pem")).unwrap().remove(0);
+        assert_eq!(
+            client_identity(&cert, ClientIdentity::CommonName).as_deref(),
+            Some("alice")
+        );
+        assert_eq!(
+            client_identity(&cert, ClientIdentity::SanEmail).as_deref(),
+            Some("alice@example.com")
+        );
+        assert_eq!(client_identity(&cert, ClientIdentity::SanDns), None);
+    }
+
+    #[test]
+    fn client_auth_settings() {
+        let config = TlsConfig {
+            cert_file: fixture("cert.pem"),
+            key_file: fixture("key.pem"),
+            client_ca_file: Some(fixture("client-ca.pem")),
+            client_crl_file: Some(fixture("client-crl.pem")),
+            client_identity: Some(ClientIdentity::CommonName),
+            quota: Default::default(),
+            user_quotas: Default::default(),
+        };
+        assert!(make_acceptor(&config).is_ok());
+        assert!(make_acceptor(&TlsConfig {
+            client_ca_file: None,
+            ..config
+        })
+        .is_err());
+    }
+}
@@ -0,0 +1,201 @@
+//! The Safe API as a `tower::Service` that can be mounted in other apps.
+//!
+//! `SafeApi` handles requests for `/config` and `/backups/:backupId` below a
+//! configurable path prefix. It can be wrapped in any tower middleware, or
+//! mounted in an axum (0.6) app:
+//!
+//! ```text
+//! let api = SafeApi::new(config)?;
+//! let background_tasks = api.spawn_background_tasks();
+//! let app = axum::Router::new().nest_service("/safe", api);
+//! ```
+//!
+//! (axum strips the prefix itself, so no prefix is set here.)
+
+use std::{
+    convert::Infallible,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use hyper::{
+    http::uri::{PathAndQuery, Uri},
+    Body, Request, Response,
+};
+use log::error;
+use tower_service::Service;
+
+use crate::{
+    config::{ListenerConfig, ServerConfig},
+    errors::ApiError,
+    routing::{strip_path_prefix, RouteGroup},
+    service::{BackupService, MakeBackupService},
+    state::BackgroundTasks,
+};
+
+/// The Safe API as a `tower::Service`.
+#[derive(Debug, Clone)]
+pub struct SafeApi {
+    make_service: MakeBackupService,
+    service: BackupService,
+    prefix: Arc<str>,
+}
+
+impl SafeApi {
+    /// Create the Safe API service.
+    ///
+    /// The top-level listener settings of the config (e.g. `allow_browser`
+    /// and `cors`) apply, but only the Safe API routes are exposed.
+    pub fn new(config: ServerConfig) -> anyhow::Result<Self> {
+        let listener = ListenerConfig {
+            routes: Some(vec![RouteGroup::Safe]),
+            ..config.default_listener()
+        };
+        let make_service = MakeBackupService::new(config)?;
+        Ok(Self::for_listener(make_service, listener))
+    }
+
+    fn for_listener(make_service: MakeBackupService, listener: ListenerConfig) -> Self {
+        let make_service = make_service.for_listener(listener);
+        Self {
+            service: make_service.service(),
+            make_service,
+            prefix: Arc::from(""),
+        }
+    }
+
+    /// Use different listener settings (e.g. to expose the metrics route).
+    pub fn with_listener(self, listener: ListenerConfig) -> Self {
+        Self {
+            prefix: self.prefix,
+            ..Self::for_listener(self.make_service, listener)
+        }
+    }
+
+    /// Only handle requests below the specified path prefix (e.g. "/safe").
+    ///
+    /// The prefix is removed before routing. Requests outside the prefix are
+    /// answered with 404.
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        self.prefix = Arc::from(prefix.trim_end_matches('/'));
+        self
+    }
+
+    /// Spawn the background tasks (e.g. replication workers).
+    ///
+    /// This must be called once, from within a tokio runtime. Call
+    /// `BackgroundTasks::shutdown` on the result once the app has stopped.
+    pub fn spawn_background_tasks(&self) -> BackgroundTasks {
+        self.make_service.spawn_background_tasks()
+    }
+}
+
+/// Remove the prefix from the request path.
+///
+/// Return false if the path is not below the prefix.
+fn strip_prefix(req: &mut Request<Body>, prefix: &str) -> bool {
+    if prefix.is_empty() {
+        return true;
+    }
+    let rest = match strip_path_prefix(req.uri().path(), prefix) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let path_and_query = match req.uri().query() {
+        Some(query) => format!("{}?{}", rest, query),
+        None => rest.to_string(),
+    };
+    let mut parts = req.uri().clone().into_parts();
+    parts.path_and_query = match path_and_query.parse::<PathAndQuery>() {
+        Ok(path_and_query) => Some(path_and_query),
+        Err(_) => return false,
+    };
+    match Uri::from_parts(parts) {
+        Ok(uri) => {
+            *req.uri_mut() = uri;
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+impl Service<Request<Body>> for SafeApi {
+    type Response = Response<Body>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, mut req: Request<Body>) -> Self::Future {
+        if !strip_prefix(&mut req, &self.prefix) {
+            return Box::pin(async { Ok(ApiError::NotFound.into_response()) });
+        }
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            Ok(fut.await.unwrap_or_else(|e| {
+                error!("Could not handle request: {}", e);
+                ApiError::InternalServerError.into_response()
+            }))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use hyper::{header, StatusCode};
+
+    fn request(uri: &str) -> Request<Body> {
+        Request::get(uri)
+            .header(header::USER_AGENT, "Threema")
+            .header(header::ACCEPT, "application/json")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn strip_prefixes() {
+        let mut req = request("http://example.com/safe/config?x=1");
+        assert!(strip_prefix(&mut req, "/safe"));
+        assert_eq!(req.uri().path(), "/config");
+        assert_eq!(req.uri().query(), Some("x=1"));
+
+        let mut req = request("/safe");
+        assert!(strip_prefix(&mut req, "/safe"));
+        assert_eq!(req.uri().path(), "/");
+
+        assert!(!strip_prefix(&mut request("/safer/config"), "/safe"));
+        assert!(!strip_prefix(&mut request("/config"), "/safe"));
+    }
+
+    #[tokio::test]
+    async fn mounted_under_prefix() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut api = SafeApi::new(ServerConfig {
+            max_backup_bytes: 1000,
+            retention_days: 180,
+            backup_dir: dir.path().to_path_buf(),
+            ..Default::default()
+        })
+        .unwrap()
+        .with_prefix("/safe/");
+
+        let res = api.call(request("/safe/config")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert!(body.starts_with(b"{\"maxBackupBytes\":1000"));
+
+        // Outside of the prefix
+        let res = api.call(request("/config")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        // Only the Safe API is exposed
+        let res = api.call(request("/safe/")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,262 @@
+use std::{cmp::Ordering, fmt, str::FromStr};
+
+use regex::Regex;
+use serde::{de, Deserialize, Deserializer};
+use serde_derive::Deserialize;
+
+/// A regular expression pattern that can be deserialized from a string.
+#[derive(Debug, Clone)]
+pub struct Pattern(Regex);
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Result<Self, regex::Error> {
+        Regex::new(pattern).map(Pattern)
+    }
+
+    pub fn is_match(&self, s: &str) -> bool {
+        self.0.is_match(s)
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Pattern::new(&s).map_err(de::Error::custom)
+    }
+}
+
+/// A dotted client version number (e.g. "4.62" or "5.7.3").
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Version(Vec<u32>);
+
+impl Version {
+    /// Extract the client version from a Threema user agent.
+    ///
+    /// The version is expected to follow `Threema/` (e.g. `Threema/4.62A`).
+    /// Trailing non-numeric characters (like a platform suffix) are ignored.
+    pub fn from_user_agent(user_agent: &str) -> Option<Self> {
+        let start = user_agent.find("Threema/")? + "Threema/".len();
+        let version: String = user_agent[start..]
+            .chars()
+            .take_while(|c| c.is_ascii_digit() || *c == '.')
+            .collect();
+        version.trim_end_matches('.').parse().ok()
+    }
+}
+
+impl FromStr for Version {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split('.')
+            .map(|part| part.parse())
+            .collect::<Result<Vec<u32>, _>>()
+            .map(Version)
+            .map_err(|_| format!("Invalid version: {:?}", s))
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Compare component-wise, treating missing components as zero
+        let len = self.0.len().max(other.0.len());
+        (0..len)
+            .map(|i| {
+                let a = self.0.get(i).copied().unwrap_or(0);
+                let b = other.0.get(i).copied().unwrap_or(0);
+                a.cmp(&b)
+            })
+            .find(|ordering| *ordering != Ordering::Equal)
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let parts: Vec<String> = self.0.iter().map(u32::to_string).collect();
+        write!(f, "{}", parts.join("."))
+    }
+}
+
+impl<'de> Deserialize<'de> for Version {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+/// A minimum client version for user agents matching a pattern.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct MinVersion {
+    /// The user agents this rule applies to (e.g. "Android")
+    pub pattern: Pattern,
+    /// The minimum version (e.g. "4.62")
+    pub version: Version,
+}
+
+/// The user agent policy.
+///
+/// A user agent is accepted if it matches at least one `allow` pattern, none
+/// of the `deny` patterns, and satisfies all applicable minimum versions.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct UserAgentPolicy {
+    /// Patterns of allowed user agents (default: "Threema")
+    #[serde(default = "default_allow")]
+    pub allow: Vec<Pattern>,
+    /// Patterns of denied user agents
+    #[serde(default)]
+    pub deny: Vec<Pattern>,
+    /// Minimum client versions
+    ///
+    /// User agents without a parseable version are not affected.
+    #[serde(default)]
+    pub min_versions: Vec<MinVersion>,
+}
+
+fn default_allow() -> Vec<Pattern> {
+    vec![Pattern::new("Threema").expect("Invalid default pattern")]
+}
+
+impl Default for UserAgentPolicy {
+    fn default() -> Self {
+        Self {
+            allow: default_allow(),
+            deny: vec![],
+            min_versions: vec![],
+        }
+    }
+}
+
+/// The reason why a user agent was rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rejection {
+    Missing,
+    NotAllowed,
+    Denied,
+    VersionTooOld(Version),
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Rejection::Missing => write!(f, "missing user agent"),
+            Rejection::NotAllowed => write!(f, "user agent not allowed"),
+            Rejection::Denied => write!(f, "user agent denied"),
+            Rejection::VersionTooOld(min) => {
+                write!(f, "client version too old (minimum {})", min)
+            }
+        }
+    }
+}
+
+impl UserAgentPolicy {
+    /// Check whether the user agent is accepted by this policy.
+    pub fn check(&self, user_agent: Option<&str>) -> Result<(), Rejection> {
+        let user_agent = user_agent.ok_or(Rejection::Missing)?;
+        if !self.allow.iter().any(|p| p.is_match(user_agent)) {
+            return Err(Rejection::NotAllowed);
+        }
+        if self.deny.iter().any(|p| p.is_match(user_agent)) {
+            return Err(Rejection::Denied);
+        }
+        if let Some(version) = Version::from_user_agent(user_agent) {
+            for min in &self.min_versions {
+                if min.pattern.is_match(user_agent) && version < min.version {
+                    return Err(Rejection::VersionTooOld(min.version.clone()));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(s: &str) -> Version {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_version() {
+        assert_eq!(version("4.62"), Version(vec![4, 62]));
+        assert_eq!(version("5.7.3"), Version(vec![5, 7, 3]));
+        assert!("".parse::<Version>().is_err());
+        assert!("4.x".parse::<Version>().is_err());
+    }
+
+    #[test]
+    fn compare_versions() {
+        assert!(version("4.62") > version("4.8"));
+        assert!(version("5") > version("4.99.99"));
+        assert_eq!(version("4.6").cmp(&version("4.6.0")), Ordering::Equal);
+        assert!(version("4.6.1") > version("4.6"));
+    }
+
+    #[test]
+    fn version_from_user_agent() {
+        assert_eq!(
+            Version::from_user_agent("Threema/4.62A"),
+            Some(version("4.62"))
+        );
+        assert_eq!(
+            Version::from_user_agent("Mozilla Threema/5.7.3 (iOS)"),
+            Some(version("5.7.3"))
+        );
+        assert_eq!(Version::from_user_agent("Threema"), None);
+        assert_eq!(Version::from_user_agent("Threema/beta"), None);
+    }
+
+    #[test]
+    fn default_policy() {
+        let policy = UserAgentPolicy::default();
+        assert_eq!(policy.check(None), Err(Rejection::Missing));
+        assert_eq!(policy.check(Some("curl/7.0")), Err(Rejection::NotAllowed));
+        assert_eq!(policy.check(Some("A Threema B")), Ok(()));
+    }
+
+    #[test]
+    fn deny_and_min_versions() {
+        let policy: UserAgentPolicy = toml::from_str(
+            r#"
+            deny = ["^Threema/4\\.50A"]
+            min_versions = [{ pattern = "A$", version = "4.40" }]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(policy.check(Some("Threema/4.50A")), Err(Rejection::Denied));
+        assert_eq!(
+            policy.check(Some("Threema/4.39A")),
+            Err(Rejection::VersionTooOld(version("4.40")))
+        );
+        assert_eq!(policy.check(Some("Threema/4.39I")), Ok(()));
+        assert_eq!(policy.check(Some("Threema/4.62A")), Ok(()));
+        assert_eq!(policy.check(Some("Threema")), Ok(()));
+    }
+
+    #[test]
+    fn invalid_pattern() {
+        let res: Result<UserAgentPolicy, _> = toml::from_str("allow = [\"(\"]");
+        assert!(res.is_err());
+    }
+}
@@ -0,0 +1,174 @@
+//! Optional structural validation of uploaded backups.
+//!
+//! The server cannot decrypt backups, but it can reject uploads that clearly
+//! aren't Threema Safe backups (see `safe_backup` for the format):
+//!
+//! - A backup must be large enough for the nonce, the authentication tag and
+//!   the smallest possible gzip stream.
+//! - The byte distribution must be consistent with ciphertext. Plaintext,
+//!   padding and most file formats are far from uniformly distributed.
+//!
+//! The body is validated while it is streamed to storage, so uploads are not
+//! buffered. An invalid body fails at its end, before the backup is
+//! committed.
+
+use std::fmt;
+
+use futures::{stream, StreamExt};
+use hyper::Body;
+
+use crate::safe_backup::{NONCE_LEN, TAG_LEN};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// The size of the smallest gzip stream (header, empty deflate block and
+/// trailer).
+const MIN_GZIP_LEN: u64 = 10 + 2 + 8;
+
+/// The minimum size of a Threema Safe backup.
+pub const MIN_BACKUP_BYTES: u64 = NONCE_LEN as u64 + TAG_LEN as u64 + MIN_GZIP_LEN;
+
+/// The maximum chi-squared statistic of the byte distribution.
+///
+/// For uniformly distributed bytes, the statistic has 255 degrees of freedom
+/// (mean 255, standard deviation ~22.6). The threshold is 20 standard
+/// deviations above the mean, so genuine ciphertext is never rejected.
+const MAX_CHI_SQUARED: f64 = 707.0;
+
+/// The reason an upload is not a valid backup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvalidBackup {
+    TooShort,
+    NotCiphertext,
+}
+
+impl fmt::Display for InvalidBackup {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InvalidBackup::TooShort => write!(f, "Backup is too short"),
+            InvalidBackup::NotCiphertext => write!(f, "Backup does not look encrypted"),
+        }
+    }
+}
+
+impl std::error::Error for InvalidBackup {}
+
+/// Incremental validation of a backup.
+struct Validator {
+    len: u64,
+    histogram: [u64; 256],
+}
+
+impl Validator {
+    fn new() -> Self {
+        Self {
+            len: 0,
+            histogram: [0; 256],
+        }
+    }
+
+    fn update(&mut self, chunk: &[u8]) {
+        self.len += chunk.len() as u64;
+        for byte in chunk {
+            self.histogram[*byte as usize] += 1;
+        }
+    }
+
+    fn finish(&self) -> Result<(), InvalidBackup> {
+        if self.len < MIN_BACKUP_BYTES {
+            return Err(InvalidBackup::TooShort);
+        }
+        let expected = self.len as f64 / 256.0;
+        let chi_squared: f64 = self
+            .histogram
+            .iter()
+            .map(|count| (*count as f64 - expected).powi(2) / expected)
+            .sum();
+        if chi_squared > MAX_CHI_SQUARED {
+            return Err(InvalidBackup::NotCiphertext);
+        }
+        Ok(())
+    }
+}
+
+/// Wrap a request body, so that it fails with `InvalidBackup` at its end if
+/// it is not a valid backup.
+pub fn validate_body(body: Body) -> Body {
+    let chunks = stream::unfold(
+        (body, Some(Validator::new())),
+        |(mut body, validator)| async move {
+            let mut validator = validator?;
+            match body.next().await {
+                Some(Ok(chunk)) => {
+                    validator.update(&chunk);
+                    Some((Ok(chunk), (body, Some(validator))))
+                }
+                Some(Err(e)) => Some((Err(BoxError::from(e)), (body, None))),
+                // The stream ends after the validation error (if any)
+                None => validator
+                    .finish()
+                    .err()
+                    .map(|e| (Err(e.into()), (body, None))),
+            }
+        },
+    );
+    Body::wrap_stream(chunks)
+}
+
+/// Return the validation error that caused a failed write, if any.
+pub fn invalid_backup(e: &anyhow::Error) -> Option<InvalidBackup> {
+    e.chain()
+        .find_map(|cause| cause.downcast_ref::<InvalidBackup>())
+        .copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::RngCore;
+
+    fn validate(data: &[u8]) -> Result<(), InvalidBackup> {
+        let mut validator = Validator::new();
+        validator.update(data);
+        validator.finish()
+    }
+
+    fn random_bytes(len: usize) -> Vec<u8> {
+        let mut data = vec![0; len];
+        rand::thread_rng().fill_bytes(&mut data);
+        data
+    }
+
+    #[test]
+    fn validate_ciphertext() {
+        for len in &[MIN_BACKUP_BYTES as usize, 100, 1000, 65536, 524_288] {
+            assert_eq!(validate(&random_bytes(*len)), Ok(()), "{}", len);
+        }
+    }
+
+    #[test]
+    fn validate_invalid() {
+        assert_eq!(validate(b""), Err(InvalidBackup::TooShort));
+        assert_eq!(
+            validate(&random_bytes(MIN_BACKUP_BYTES as usize - 1)),
+            Err(InvalidBackup::TooShort)
+        );
+        assert_eq!(validate(&[0; 1000]), Err(InvalidBackup::NotCiphertext));
+        let text = "This server is free storage for everything! ".repeat(20);
+        assert_eq!(validate(text.as_bytes()), Err(InvalidBackup::NotCiphertext));
+    }
+
+    #[tokio::test]
+    async fn validate_streaming() {
+        let data = random_bytes(1000);
+        let chunks: Vec<Result<_, std::io::Error>> =
+            vec![Ok(data[..500].to_vec()), Ok(data[500..].to_vec())];
+        let body = validate_body(Body::wrap_stream(stream::iter(chunks)));
+        assert_eq!(hyper::body::to_bytes(body).await.unwrap(), data);
+
+        let body = validate_body(Body::from(vec![0; 1000]));
+        let e = anyhow::Error::new(hyper::body::to_bytes(body).await.unwrap_err());
+        assert_eq!(invalid_backup(&e), Some(InvalidBackup::NotCiphertext));
+    }
+}
@@ -0,0 +1,377 @@
+//! Webhook notifications for backup lifecycle events.
+//!
+//! Every created, updated, deleted or expired backup is posted to the
+//! configured endpoints as a JSON object, e.g.
+//!
+//! ```text
+//! {"event":"created","backup_id_hash":"3fa9…","size":1024,"timestamp":"2025-04-01T12:00:00.000Z","source":"api"}
+//! ```
+//!
+//! The body is signed with the endpoint secret (HMAC-SHA256) in the
+//! `X-Sekursranko-Signature: sha256=<hex>` header. Deliveries are queued per
+//! endpoint in a bounded in-memory queue and retried with exponential
+//! backoff. If the queue of a slow endpoint is full, new events for it are
+//! dropped, so that requests are never blocked.
+//!
+//! Admin commands run their own delivery workers and wait for them (see
+//! `Webhooks::flush`), so that their events are delivered before they exit.
+
+use std::{
+    fmt,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use futures::future::join_all;
+use hmac::{Hmac, Mac};
+use log::{debug, info, warn};
+use reqwest::header;
+use serde_derive::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::{sync::mpsc, task::JoinHandle};
+
+use crate::{
+    events::{BackupEvent, EventKind},
+    metrics::Metrics,
+    privacy::{load_key, IdHasher},
+};
+
+/// The header containing the signature of the request body.
+pub const SIGNATURE_HEADER: &str = "x-sekursranko-signature";
+
+/// The webhook configuration.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct WebhookConfig {
+    /// Path to a file containing the key for hashing backup IDs
+    pub hash_key_file: PathBuf,
+    /// The maximum number of queued deliveries per endpoint (default: 1000)
+    pub queue_size: Option<usize>,
+    /// The maximum number of delivery attempts (default: 5)
+    pub max_attempts: Option<u32>,
+    /// The timeout of a delivery attempt in seconds (default: 10)
+    pub timeout_secs: Option<u64>,
+    /// The endpoints to notify
+    pub endpoints: Vec<WebhookEndpoint>,
+}
+
+/// A webhook endpoint.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct WebhookEndpoint {
+    /// A unique name for this endpoint (used in logs)
+    pub name: String,
+    /// The URL the events are posted to
+    pub url: String,
+    /// Path to a file containing the secret for signing requests
+    pub secret_file: PathBuf,
+    /// The events to send (`created`, `updated`, `deleted`, `expired`).
+    /// If not set, all events are sent.
+    pub events: Option<Vec<String>>,
+}
+
+impl WebhookEndpoint {
+    fn wants(&self, kind: EventKind) -> bool {
+        match self.events {
+            Some(ref events) => events.iter().any(|event| *event == kind.to_string()),
+            None => true,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Payload {
+    event: String,
+    backup_id_hash: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    timestamp: String,
+    source: String,
+}
+
+/// The events that can be sent to webhooks.
+const EVENTS: &[&str] = &["created", "updated", "deleted", "expired"];
+
+/// A webhook endpoint with its delivery queue.
+struct Endpoint {
+    config: WebhookEndpoint,
+    secret: Vec<u8>,
+    /// The sending half of the queue (`None` once closed)
+    sender: Mutex<Option<mpsc::Sender<String>>>,
+    receiver: Mutex<Option<mpsc::Receiver<String>>>,
+}
+
+impl fmt::Debug for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Endpoint")
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Sends backup lifecycle events to all configured endpoints.
+#[derive(Debug)]
+pub struct Webhooks {
+    hasher: IdHasher,
+    endpoints: Vec<Arc<Endpoint>>,
+    max_attempts: u32,
+    client: reqwest::Client,
+}
+
+impl Webhooks {
+    pub fn new(config: &WebhookConfig) -> anyhow::Result<Self> {
+        let queue_size = config.queue_size.unwrap_or(1000);
+        if queue_size == 0 {
+            bail!("Webhook queue size must be at least 1");
+        }
+        let endpoints = config
+            .endpoints
+            .iter()
+            .map(|endpoint| {
+                for event in endpoint.events.iter().flatten() {
+                    if !EVENTS.contains(&event.as_str()) {
+                        bail!("Unknown webhook event for {}: {}", endpoint.name, event);
+                    }
+                }
+                let (sender, receiver) = mpsc::channel(queue_size);
+                Ok(Arc::new(Endpoint {
+                    config: endpoint.clone(),
+                    secret: load_key(&endpoint.secret_file)?,
+                    sender: Mutex::new(Some(sender)),
+                    receiver: Mutex::new(Some(receiver)),
+                }))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.timeout_secs.unwrap_or(10)))
+            .build()
+            .context("Could not create HTTP client for webhooks")?;
+        Ok(Self {
+            hasher: IdHasher::load(&config.hash_key_file)?,
+            endpoints,
+            max_attempts: config.max_attempts.unwrap_or(5).max(1),
+            client,
+        })
+    }
+
+    /// Queue an event for delivery to all interested endpoints.
+    ///
+    /// If the queue of an endpoint is full or closed, the event is dropped
+    /// for that endpoint. Queued events are delivered by the workers
+    /// started by `spawn`.
+    pub fn notify(&self, event: &BackupEvent, metrics: &Metrics) {
+        let body = self.payload(event);
+        for endpoint in &self.endpoints {
+            if !endpoint.config.wants(event.kind) {
+                continue;
+            }
+            let queued = match *endpoint.sender.lock().unwrap_or_else(|e| e.into_inner()) {
+                Some(ref sender) => sender.try_send(body.clone()).is_ok(),
+                None => false,
+            };
+            if !queued {
+                warn!(
+                    "Webhook queue for {} is full or closed, dropping {} event",
+                    endpoint.config.name, event.kind
+                );
+                Metrics::inc(&metrics.webhook_dropped);
+            }
+        }
+    }
+
+    fn payload(&self, event: &BackupEvent) -> String {
+        let payload = Payload {
+            event: event.kind.to_string(),
+            backup_id_hash: self.hasher.hash(&event.backup_id),
+            size: event.size,
+            timestamp: humantime::format_rfc3339_millis(event.timestamp).to_string(),
+            source: event.source.to_string(),
+        };
+        serde_json::to_string(&payload).expect("Could not serialize webhook payload")
+    }
+
+    /// Spawn a delivery worker for every endpoint.
+    ///
+    /// The workers exit once the queues are closed (see `close`) and all
+    /// queued events are delivered.
+    pub fn spawn(self: &Arc<Self>, metrics: Arc<Metrics>) -> Vec<JoinHandle<()>> {
+        let mut tasks = vec![];
+        for endpoint in &self.endpoints {
+            let receiver = endpoint
+                .receiver
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .take();
+            let mut receiver = match receiver {
+                Some(receiver) => receiver,
+                None => continue,
+            };
+            let webhooks = self.clone();
+            let endpoint = endpoint.clone();
+            let metrics = metrics.clone();
+            tasks.push(tokio::spawn(async move {
+                info!("Starting webhook delivery to {}", endpoint.config.name);
+                while let Some(body) = receiver.recv().await {
+                    webhooks.deliver(&endpoint, &body, &metrics).await;
+                }
+            }));
+        }
+        tasks
+    }
+
+    /// Stop accepting events.
+    ///
+    /// Events that are already queued are still delivered.
+    pub fn close(&self) {
+        for endpoint in &self.endpoints {
+            endpoint
+                .sender
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .take();
+        }
+    }
+
+    /// Stop accepting events and wait until the queued events are delivered.
+    ///
+    /// `workers` are the delivery workers returned by `spawn`. Admin commands
+    /// call this before they exit.
+    pub async fn flush(&self, workers: Vec<JoinHandle<()>>) {
+        self.close();
+        join_all(workers).await;
+    }
+
+    /// Deliver an event, retrying with exponential backoff.
+    async fn deliver(&self, endpoint: &Endpoint, body: &str, metrics: &Metrics) {
+        let mut backoff = Duration::from_secs(1);
+        for attempt in 1..=self.max_attempts {
+            match self.send(endpoint, body).await {
+                Ok(()) => {
+                    debug!("Delivered webhook to {}", endpoint.config.name);
+                    Metrics::inc(&metrics.webhook_delivered);
+                    return;
+                }
+                Err(e) if attempt < self.max_attempts => {
+                    warn!(
+                        "Webhook delivery to {} failed, retrying in {:?}: {:#}",
+                        endpoint.config.name, backoff, e
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                Err(e) => {
+                    warn!(
+                        "Webhook delivery to {} failed, giving up: {:#}",
+                        endpoint.config.name, e
+                    );
+                    Metrics::inc(&metrics.webhook_failed);
+                }
+            }
+        }
+    }
+
+    async fn send(&self, endpoint: &Endpoint, body: &str) -> anyhow::Result<()> {
+        let response = self
+            .client
+            .post(&endpoint.config.url)
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(
+                SIGNATURE_HEADER,
+                format!("sha256={}", sign(&endpoint.secret, body)),
+            )
+            .body(body.to_string())
+            .send()
+            .await
+            .context("Request to webhook endpoint failed")?;
+        let status = response.status();
+        if !status.is_success() {
+            bail!("Webhook endpoint responded with status {}", status);
+        }
+        Ok(())
+    }
+}
+
+/// Return the hex encoded HMAC-SHA256 of a request body.
+fn sign(secret: &[u8], body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(body.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{fs, sync::atomic::Ordering};
+
+    use crate::events::Source;
+
+    const BACKUP_ID: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    fn webhooks(dir: &tempfile::TempDir, queue_size: usize) -> Webhooks {
+        let key_file = dir.path().join("key");
+        fs::write(&key_file, "a webhook secret key\n").unwrap();
+        Webhooks::new(&WebhookConfig {
+            hash_key_file: key_file.clone(),
+            queue_size: Some(queue_size),
+            max_attempts: None,
+            timeout_secs: None,
+            endpoints: vec![WebhookEndpoint {
+                name: "ops".into(),
+                url: "http://127.0.0.1:1/".into(),
+                secret_file: key_file,
+                events: Some(vec!["created".into(), "deleted".into()]),
+            }],
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn payload() {
+        let dir = tempfile::tempdir().unwrap();
+        let webhooks = webhooks(&dir, 1);
+        let event = BackupEvent::new(EventKind::Created, Source::Api, BACKUP_ID, Some(42));
+        let payload: serde_json::Value = serde_json::from_str(&webhooks.payload(&event)).unwrap();
+        assert_eq!(payload["event"], "created");
+        assert_eq!(
+            payload["backup_id_hash"],
+            IdHasher::new(b"a webhook secret key").hash(BACKUP_ID)
+        );
+        assert_eq!(payload["size"], 42);
+        assert_eq!(payload["source"], "api");
+        assert!(payload["timestamp"].as_str().unwrap().ends_with('Z'));
+        assert!(!payload.to_string().contains(BACKUP_ID));
+    }
+
+    #[test]
+    fn bounded_queue() {
+        let dir = tempfile::tempdir().unwrap();
+        let webhooks = webhooks(&dir, 1);
+        let metrics = Metrics::default();
+        let created = BackupEvent::new(EventKind::Created, Source::Api, BACKUP_ID, Some(1));
+        let updated = BackupEvent::new(EventKind::Updated, Source::Api, BACKUP_ID, Some(1));
+
+        // Filtered events are not queued
+        webhooks.notify(&updated, &metrics);
+        webhooks.notify(&created, &metrics);
+        assert_eq!(metrics.webhook_dropped.load(Ordering::Relaxed), 0);
+
+        // The queue is full now
+        webhooks.notify(&created, &metrics);
+        assert_eq!(metrics.webhook_dropped.load(Ordering::Relaxed), 1);
+
+        // Closed queues don't accept events
+        webhooks.close();
+        webhooks.notify(&created, &metrics);
+        assert_eq!(metrics.webhook_dropped.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn signature() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            sign(b"Jefe", "what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+}
@@ -1,18 +1,28 @@
+use std::collections::HashMap;
 use std::env;
 use std::fs::File;
 use std::io::{Read, Write};
 use std::os::unix::fs::PermissionsExt;
+use std::sync::Arc;
 use std::sync::Once;
 use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
 
+use hmac::{Hmac, Mac};
 use hyper::Server;
 use reqwest::{
     blocking::{Client, Response},
     header, Method,
 };
+use sha2::Sha256;
 use tempfile::{self, TempDir};
 
-use sekursranko::{MakeBackupService, ServerConfig};
+use sekursranko::{
+    client::{Client as SafeClient, ClientError},
+    ApiError, AuditLogConfig, AuthConfig, CorsConfig, EncryptionConfig, ListenAddr, ListenerConfig,
+    MakeBackupService, MigrationConfig, PeerConfig, QuotaConfig, ReplicationConfig, RouteGroup,
+    ServerBuilder, ServerConfig, Storage, TenantConfig, WebhookConfig, WebhookEndpoint,
+};
 
 static LOGGER_INIT: Once = Once::new();
 
@@ -26,6 +36,17 @@ struct TestServer {
 impl TestServer {
     /// Create a new test server instance.
     fn new() -> Self {
+        Self::with_listener(ListenerConfig::default())
+    }
+
+    /// Create a new test server instance with custom listener settings.
+    fn with_listener(listener: ListenerConfig) -> Self {
+        Self::with_config(listener, |_| {})
+    }
+
+    /// Create a new test server instance with custom listener settings and a
+    /// customized config.
+    fn with_config(listener: ListenerConfig, configure: impl FnOnce(&mut ServerConfig)) -> Self {
         // Initialize logger
         LOGGER_INIT.call_once(|| {
             if env::var("RUST_LOG")
@@ -44,21 +65,24 @@ impl TestServer {
             .expect("Could not create temporary backup directory");
 
         // Create config object
-        let config = ServerConfig {
+        let mut config = ServerConfig {
             max_backup_bytes: 524_288,
             retention_days: 180,
             backup_dir: backup_dir.path().to_path_buf(),
-            listen_on: "-integrationtest-".to_string(),
-            allow_browser: None,
+            ..Default::default()
         };
+        configure(&mut config);
 
         // Run server
         let addr = ([127, 0, 0, 1], 0).into();
-        let service = MakeBackupService::new(config.clone());
+        let service = MakeBackupService::new(config.clone())
+            .unwrap()
+            .for_listener(listener);
         let (port_tx, port_rx) = std::sync::mpsc::channel();
         let handle = thread::spawn(move || {
             let rt = tokio::runtime::Runtime::new().unwrap();
             rt.block_on(async move {
+                service.spawn_background_tasks();
                 let server = Server::bind(&addr).serve(service);
                 let port = server.local_addr().port();
 
@@ -86,6 +110,11 @@ impl TestServer {
     }
 }
 
+/// Return the expected JSON body of an API error.
+fn error_json(code: &str, detail: &str) -> String {
+    format!("{{\"code\":\"{}\",\"detail\":\"{}\"}}", code, detail)
+}
+
 macro_rules! user_agent_required {
     ($name:ident, $url:expr) => {
         #[test]
@@ -96,6 +125,8 @@ macro_rules! user_agent_required {
                 .send()
                 .unwrap();
             assert_eq!(res.status().as_u16(), 400);
+            let text = res.text().unwrap();
+            assert_eq!(text, error_json("invalid_user_agent", "Invalid user agent"));
         }
     };
 }
@@ -104,6 +135,56 @@ user_agent_required!(user_agent_required_index, "/");
 user_agent_required!(user_agent_required_config, "/config");
 user_agent_required!(user_agent_required_backup_download, "/backups/abcd1234");
 
+/// Rejected user agents get a JSON error and are counted in the metrics.
+#[test]
+fn user_agent_policy_rejected() {
+    let TestServer { base_url, .. } = TestServer::with_config(
+        ListenerConfig {
+            routes: Some(vec![RouteGroup::Info, RouteGroup::Metrics]),
+            ..Default::default()
+        },
+        |config| {
+            config.user_agent = toml::from_str(
+                r#"
+                deny = ["Threema/4\\.50A"]
+                min_versions = [{ pattern = "A$", version = "4.40" }]
+                "#,
+            )
+            .unwrap();
+        },
+    );
+    for (user_agent, status) in &[
+        ("curl/7.0", 400),
+        ("Threema/4.50A", 400),
+        ("Threema/4.39A", 400),
+        ("Threema/4.39I", 200),
+        ("Threema/4.62A", 200),
+    ] {
+        let res = Client::new()
+            .get(&base_url)
+            .header(header::USER_AGENT, *user_agent)
+            .send()
+            .unwrap();
+        assert_eq!(res.status().as_u16(), *status, "{}", user_agent);
+        if *status == 400 {
+            assert_eq!(
+                res.text().unwrap(),
+                error_json("invalid_user_agent", "Invalid user agent")
+            );
+        }
+    }
+
+    // Metrics do not require a Threema user agent
+    let res = Client::new()
+        .get(format!("{}/metrics", base_url))
+        .send()
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let text = res.text().unwrap();
+    println!("{}", text);
+    assert!(text.contains("\nsekursranko_user_agent_rejected_total 3\n"));
+}
+
 macro_rules! method_not_allowed {
     ($name:ident, $method:expr, $url:expr) => {
         #[test]
@@ -115,6 +196,10 @@ macro_rules! method_not_allowed {
                 .send()
                 .unwrap();
             assert_eq!(res.status().as_u16(), 405);
+            assert_eq!(res.headers()[header::CONTENT_TYPE], "application/json");
+            assert!(res.headers().contains_key(header::ALLOW));
+            let text = res.text().unwrap();
+            assert_eq!(text, error_json("method_not_allowed", "Method not allowed"));
         }
     };
 }
@@ -144,6 +229,53 @@ fn index_ok() {
     assert_eq!(text, format!("Sekurŝranko {}", env!("CARGO_PKG_VERSION")));
 }
 
+#[test]
+fn config_ok() {
+    let TestServer { base_url, .. } = TestServer::new();
+    let res = Client::new()
+        .get(format!("{}/config", base_url))
+        .header(header::USER_AGENT, "Threema")
+        .header(header::ACCEPT, "application/json")
+        .send()
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    assert_eq!(res.headers()[header::CONTENT_TYPE], "application/json");
+    let text = res.text().unwrap();
+    println!("{}", text);
+    assert_eq!(text, "{\"maxBackupBytes\":524288,\"retentionDays\":180}");
+}
+
+#[test]
+fn openapi_document() {
+    // Not exposed by default
+    let TestServer { base_url, .. } = TestServer::new();
+    let res = Client::new()
+        .get(format!("{}/openapi.json", base_url))
+        .header(header::USER_AGENT, "Threema")
+        .send()
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 404);
+
+    // Does not require a Threema user agent
+    let TestServer { base_url, .. } = TestServer::with_config(
+        ListenerConfig {
+            routes: Some(vec![RouteGroup::Safe, RouteGroup::OpenApi]),
+            ..Default::default()
+        },
+        |_| {},
+    );
+    let res = Client::new()
+        .get(format!("{}/openapi.json", base_url))
+        .send()
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    assert_eq!(res.headers()[header::CONTENT_TYPE], "application/json");
+    let document: serde_json::Value = serde_json::from_str(&res.text().unwrap()).unwrap();
+    assert_eq!(document["info"]["version"], env!("CARGO_PKG_VERSION"));
+    assert!(document["paths"]["/config"]["get"].is_object());
+    assert!(document["paths"]["/backups/{backupId}"]["put"].is_object());
+}
+
 #[test]
 fn config_require_json() {
     let TestServer { base_url, .. } = TestServer::new();
@@ -155,7 +287,10 @@ fn config_require_json() {
     assert_eq!(res.status().as_u16(), 400);
     let text = res.text().unwrap();
     println!("{}", text);
-    assert_eq!(text, "{\"detail\": \"Invalid accept header\"}");
+    assert_eq!(
+        text,
+        error_json("invalid_accept_header", "Invalid accept header")
+    );
 }
 
 #[test]
@@ -171,7 +306,10 @@ fn backup_download_require_octet_stream() {
     assert_eq!(res.status().as_u16(), 400);
     let text = res.text().unwrap();
     println!("{}", text);
-    assert_eq!(text, "{\"detail\": \"Invalid accept header\"}");
+    assert_eq!(
+        text,
+        error_json("invalid_accept_header", "Invalid accept header")
+    );
 }
 
 #[test]
@@ -187,7 +325,7 @@ fn backup_download_not_found() {
     assert_eq!(res.status().as_u16(), 404);
     let text = res.text().unwrap();
     println!("{}", text);
-    assert_eq!(text, "");
+    assert_eq!(text, error_json("not_found", "Not found"));
 }
 
 #[test]
@@ -223,6 +361,10 @@ fn backup_download_ok() {
         .send()
         .unwrap();
     assert_eq!(res.status().as_u16(), 200);
+    assert_eq!(
+        res.headers()[header::CONTENT_TYPE],
+        "application/octet-stream"
+    );
     let text = res.text().unwrap();
     println!("{}", text);
     assert_eq!(text, "tre sekura");
@@ -245,11 +387,55 @@ fn backup_download_present_head() {
         .send()
         .unwrap();
     assert_eq!(res.status().as_u16(), 200);
+    assert_eq!(res.headers()[header::CONTENT_LENGTH], "10");
     let text = res.text().unwrap();
     println!("{}", text);
     assert_eq!(text, "");
 }
 
+/// Responses include the upload and expiry time of the backup.
+#[test]
+fn backup_download_expiry_headers() {
+    let backup_id = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+    let stored_at = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_600_000_000);
+    for retention_days in &[180, 0] {
+        let retention_days = *retention_days;
+        let server = TestServer::with_config(ListenerConfig::default(), |config| {
+            config.retention_days = retention_days;
+        });
+        File::create(server.backup_dir.path().join(backup_id))
+            .unwrap()
+            .set_modified(stored_at)
+            .unwrap();
+        let url = format!("{}/backups/{}", server.base_url, backup_id);
+        for request in &[Client::new().get(&url), Client::new().head(&url)] {
+            let res = request
+                .try_clone()
+                .unwrap()
+                .header(header::USER_AGENT, "Threema")
+                .header(header::ACCEPT, "application/octet-stream")
+                .send()
+                .unwrap();
+            assert_eq!(res.status().as_u16(), 200);
+            let headers = res.headers();
+            assert_eq!(
+                headers[header::LAST_MODIFIED],
+                "Sun, 13 Sep 2020 12:26:40 GMT"
+            );
+            assert_eq!(
+                headers
+                    .get("X-Sekursranko-Expires")
+                    .map(|v| v.to_str().unwrap()),
+                if retention_days > 0 {
+                    Some("Fri, 12 Mar 2021 12:26:40 GMT")
+                } else {
+                    None
+                }
+            );
+        }
+    }
+}
+
 #[test]
 fn backup_upload_require_octet_stream() {
     let TestServer { base_url, .. } = TestServer::new();
@@ -263,7 +449,10 @@ fn backup_upload_require_octet_stream() {
     assert_eq!(res.status().as_u16(), 400);
     let text = res.text().unwrap();
     println!("{}", text);
-    assert_eq!(text, "{\"detail\": \"Invalid content-type header\"}");
+    assert_eq!(
+        text,
+        error_json("invalid_content_type_header", "Invalid content-type header")
+    );
 }
 
 #[test]
@@ -279,7 +468,7 @@ fn backup_upload_invalid_backup_id() {
     assert_eq!(res.status().as_u16(), 400);
     let text = res.text().unwrap();
     println!("{}", text);
-    assert_eq!(text, "{\"detail\": \"Invalid backup ID\"}");
+    assert_eq!(text, error_json("invalid_backup_id", "Invalid backup ID"));
 }
 
 /// Request with body that is exactly max bytes large (according to
@@ -303,7 +492,7 @@ fn backup_upload_payload_not_too_large() {
     assert_ne!(res.status().as_u16(), 413);
     let text = res.text().unwrap();
     println!("{}", text);
-    assert_ne!(text, "{\"detail\": \"Backup is too large\"}");
+    assert_ne!(text, error_json("backup_too_large", "Backup is too large"));
 }
 
 /// Request with body that is a byte too large (according to content-length
@@ -327,7 +516,7 @@ fn backup_upload_payload_too_large() {
     assert_eq!(res.status().as_u16(), 413);
     let text = res.text().unwrap();
     println!("{}", text);
-    assert_eq!(text, "{\"detail\": \"Backup is too large\"}");
+    assert_eq!(text, error_json("backup_too_large", "Backup is too large"));
 }
 
 fn upload_backup(base_url: &str, backup_id: &str, body: Vec<u8>) -> Response {
@@ -380,6 +569,45 @@ fn backup_upload_success_created() {
     assert_eq!(perms.mode(), 0o100_000 | 0o600);
 }
 
+/// Uploads that are not Threema Safe backups are rejected if validation is
+/// enabled.
+#[test]
+fn backup_upload_validation() {
+    let server = TestServer::with_config(ListenerConfig::default(), |config| {
+        config.validate_backups = true;
+    });
+    let backup_id = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+    let backup_path = server.backup_dir.path().join(backup_id);
+
+    // Too short (rejected before reading the body) or not encrypted
+    for body in &[
+        vec![],
+        b"tiu sekurkopio estas tre sekura!".to_vec(),
+        b"tiu sekurkopio estas tre sekura! ".repeat(100),
+        vec![0; 100_000],
+    ] {
+        let res = upload_backup(&server.base_url, backup_id, body.clone());
+        assert_eq!(res.status().as_u16(), 400, "{} bytes", body.len());
+        assert_eq!(
+            res.text().unwrap(),
+            error_json("invalid_backup", "Not a valid Threema Safe backup")
+        );
+        assert!(!backup_path.exists());
+    }
+
+    // Ciphertext is accepted
+    let mut body = vec![0; 100_000];
+    rand::RngCore::fill_bytes(&mut rand::thread_rng(), &mut body);
+    let res = upload_backup(&server.base_url, backup_id, body.clone());
+    assert_eq!(res.status().as_u16(), 201);
+    assert_eq!(std::fs::read(&backup_path).unwrap(), body);
+
+    // An invalid update keeps the existing backup
+    let res = upload_backup(&server.base_url, backup_id, vec![1; 1000]);
+    assert_eq!(res.status().as_u16(), 400);
+    assert_eq!(std::fs::read(&backup_path).unwrap(), body);
+}
+
 /// Successfully update a backup.
 #[test]
 fn backup_upload_success_updated() {
@@ -432,7 +660,7 @@ fn backup_delete_invalid_backup_id() {
     assert_eq!(res.status().as_u16(), 400);
     let text = res.text().unwrap();
     println!("{}", text);
-    assert_eq!(text, "{\"detail\": \"Invalid backup ID\"}");
+    assert_eq!(text, error_json("invalid_backup_id", "Invalid backup ID"));
 }
 
 #[test]
@@ -447,7 +675,7 @@ fn backup_delete_not_found() {
     assert_eq!(res.status().as_u16(), 404);
     let text = res.text().unwrap();
     println!("{}", text);
-    assert_eq!(text, "");
+    assert_eq!(text, error_json("not_found", "Not found"));
 }
 
 /// Delete a backup.
@@ -484,3 +712,1306 @@ fn backup_delete_success() {
     // Ensure file was deleted
     assert!(!backup_file_path.exists());
 }
+
+/// Serve on a Unix domain socket with a custom socket mode.
+#[test]
+fn unix_socket_index_ok() {
+    use std::os::unix::net::UnixStream;
+
+    let socket_dir = tempfile::Builder::new()
+        .prefix("sekursranko-test-socket")
+        .tempdir()
+        .unwrap();
+    let socket_path = socket_dir.path().join("sekursranko.sock");
+    let config = ServerConfig {
+        max_backup_bytes: 524_288,
+        retention_days: 180,
+        backup_dir: socket_dir.path().to_path_buf(),
+        listen_on: Some(format!("unix:{}", socket_path.display())),
+        unix_socket_mode: Some(0o660),
+        ..Default::default()
+    };
+
+    // Run server
+    let listener = config.listeners().remove(0);
+    let addr: ListenAddr = listener.listen_on.parse().unwrap();
+    let service = MakeBackupService::new(config).unwrap();
+    thread::spawn(move || {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move { sekursranko::serve(&addr, &listener, service).await })
+            .unwrap();
+    });
+
+    // Wait for socket to appear
+    let mut stream = None;
+    for _ in 0..100 {
+        if let Ok(s) = UnixStream::connect(&socket_path) {
+            stream = Some(s);
+            break;
+        }
+        thread::sleep(std::time::Duration::from_millis(10));
+    }
+    let mut stream = stream.expect("Could not connect to unix socket");
+
+    // Ensure socket mode
+    let perms = std::fs::metadata(&socket_path).unwrap().permissions();
+    assert_eq!(perms.mode() & 0o777, 0o660);
+
+    // Send request
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nUser-Agent: Threema\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    println!("{}", response);
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.ends_with(&format!("Sekurŝranko {}", env!("CARGO_PKG_VERSION"))));
+}
+
+/// Routes that are not exposed on a listener are not found.
+#[test]
+fn listener_route_groups() {
+    let TestServer { base_url, .. } = TestServer::with_listener(ListenerConfig {
+        routes: Some(vec![RouteGroup::Safe]),
+        ..Default::default()
+    });
+    let res = Client::new()
+        .get(&base_url)
+        .header(header::USER_AGENT, "Threema")
+        .send()
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 404);
+    let res = Client::new()
+        .get(format!("{}/config", base_url))
+        .header(header::USER_AGENT, "Threema")
+        .header(header::ACCEPT, "application/json")
+        .send()
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+}
+
+/// Browser access can be enabled per listener.
+#[test]
+fn listener_allow_browser() {
+    let TestServer { base_url, .. } = TestServer::with_listener(ListenerConfig {
+        allow_browser: Some(true),
+        ..Default::default()
+    });
+    let res = Client::new().get(&base_url).send().unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    assert_eq!(
+        res.headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .map(|v| v.as_bytes()),
+        Some(&b"*"[..])
+    );
+}
+
+/// Serve over TLS using the test certificate.
+#[cfg(feature = "tls")]
+#[test]
+fn listener_tls() {
+    let backup_dir = tempfile::Builder::new()
+        .prefix("sekursranko-test")
+        .tempdir()
+        .unwrap();
+    let config = ServerConfig {
+        max_backup_bytes: 524_288,
+        retention_days: 180,
+        backup_dir: backup_dir.path().to_path_buf(),
+        ..Default::default()
+    };
+
+    // Find a free port
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let fixtures = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let listener = ListenerConfig {
+        listen_on: format!("127.0.0.1:{}", port),
+        tls: Some(sekursranko::TlsConfig {
+            cert_file: fixtures.join("cert.pem"),
+            key_file: fixtures.join("key.pem"),
+            client_ca_file: None,
+            client_crl_file: None,
+            client_identity: None,
+            quota: Default::default(),
+            user_quotas: Default::default(),
+        }),
+        ..Default::default()
+    };
+
+    // Run server
+    let addr: ListenAddr = listener.listen_on.parse().unwrap();
+    let service = MakeBackupService::new(config)
+        .unwrap()
+        .for_listener(listener.clone());
+    thread::spawn(move || {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move { sekursranko::serve(&addr, &listener, service).await })
+            .unwrap();
+    });
+
+    // Send request
+    let client = Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap();
+    let mut res = None;
+    for _ in 0..100 {
+        match client
+            .get(format!("https://127.0.0.1:{}/", port))
+            .header(header::USER_AGENT, "Threema")
+            .send()
+        {
+            Ok(r) => {
+                res = Some(r);
+                break;
+            }
+            Err(_) => thread::sleep(std::time::Duration::from_millis(10)),
+        }
+    }
+    let res = res.expect("Could not connect to TLS listener");
+    assert_eq!(res.status().as_u16(), 200);
+    assert_eq!(
+        res.text().unwrap(),
+        format!("Sekurŝranko {}", env!("CARGO_PKG_VERSION"))
+    );
+}
+
+/// Only clients with a valid certificate can connect to a listener with
+/// client authentication, and the certificate identifies the user (for
+/// ownership and quotas, without Basic authentication).
+#[cfg(feature = "tls")]
+#[test]
+fn listener_mutual_tls() {
+    let backup_dir = tempfile::tempdir().unwrap();
+    let fixtures = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let config = ServerConfig {
+        max_backup_bytes: 524_288,
+        retention_days: 180,
+        backup_dir: backup_dir.path().to_path_buf(),
+        listeners: vec![ListenerConfig {
+            listen_on: "127.0.0.1:0".into(),
+            tls: Some(sekursranko::TlsConfig {
+                cert_file: fixtures.join("cert.pem"),
+                key_file: fixtures.join("key.pem"),
+                client_ca_file: Some(fixtures.join("client-ca.pem")),
+                client_crl_file: Some(fixtures.join("client-crl.pem")),
+                client_identity: Some(sekursranko::ClientIdentity::CommonName),
+                quota: QuotaConfig {
+                    max_backups: Some(1),
+                    max_bytes: None,
+                },
+                user_quotas: Default::default(),
+            }),
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+
+    // Run server
+    let (addr_tx, addr_rx) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let handle = ServerBuilder::new(config).start().unwrap();
+            addr_tx.send(handle.local_addr().unwrap()).unwrap();
+            handle.await.unwrap();
+        });
+    });
+    let base_url = format!("https://{}", addr_rx.recv().unwrap());
+    let upload_to = |client: Option<&str>, backup_id: &str| {
+        let mut builder = Client::builder()
+            .use_rustls_tls()
+            .danger_accept_invalid_certs(true);
+        if let Some(client) = client {
+            let mut pem = std::fs::read(fixtures.join(format!("client-{}.pem", client))).unwrap();
+            pem.extend(std::fs::read(fixtures.join(format!("client-{}-key.pem", client))).unwrap());
+            builder = builder.identity(reqwest::Identity::from_pem(&pem).unwrap());
+        }
+        builder
+            .build()
+            .unwrap()
+            .put(format!("{}/backups/{}", base_url, backup_id))
+            .header(header::USER_AGENT, "Threema")
+            .header(header::CONTENT_TYPE, "application/octet-stream")
+            .body(b"mtls".to_vec())
+            .send()
+    };
+    let upload = |client: Option<&str>| upload_to(client, &"ab".repeat(32));
+
+    let res = upload(Some("alice")).unwrap();
+    assert_eq!(res.status().as_u16(), 201);
+    let owner_file = backup_dir.path().join(".owners").join("ab".repeat(32));
+    assert_eq!(std::fs::read_to_string(owner_file).unwrap(), "alice");
+
+    // The quota of the listener applies
+    let res = upload_to(Some("alice"), &"cd".repeat(32)).unwrap();
+    assert_eq!(res.status().as_u16(), 403);
+
+    // Without a certificate or with a revoked certificate
+    assert!(upload(None).is_err());
+    assert!(upload(Some("mallory")).is_err());
+}
+
+/// Browsers can do a preflighted upload if browser access is allowed.
+#[test]
+fn cors_preflight_upload() {
+    let TestServer { base_url, .. } = TestServer::with_listener(ListenerConfig {
+        allow_browser: Some(true),
+        ..Default::default()
+    });
+    let backup_id = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+    let res = Client::new()
+        .request(
+            Method::OPTIONS,
+            format!("{}/backups/{}", base_url, backup_id),
+        )
+        .header(header::ORIGIN, "https://example.org")
+        .header(header::ACCESS_CONTROL_REQUEST_METHOD, "PUT")
+        .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "content-type")
+        .send()
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 204);
+    let headers = res.headers();
+    assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+    assert_eq!(
+        headers[header::ACCESS_CONTROL_ALLOW_METHODS],
+        "GET, HEAD, PUT, DELETE"
+    );
+    assert_eq!(
+        headers[header::ACCESS_CONTROL_ALLOW_HEADERS],
+        "Accept, Authorization, Content-Type"
+    );
+}
+
+/// Configured origins are echoed back.
+#[test]
+fn cors_allowed_origins() {
+    let TestServer { base_url, .. } = TestServer::with_listener(ListenerConfig {
+        allow_browser: Some(true),
+        cors: Some(CorsConfig {
+            allowed_origins: vec!["https://safe.example.com".into()],
+            ..Default::default()
+        }),
+        ..Default::default()
+    });
+    for (origin, allowed) in &[
+        ("https://safe.example.com", true),
+        ("https://evil.example.com", false),
+    ] {
+        let res = Client::new()
+            .get(format!("{}/config", base_url))
+            .header(header::ACCEPT, "application/json")
+            .header(header::ORIGIN, *origin)
+            .send()
+            .unwrap();
+        assert_eq!(res.status().as_u16(), 200);
+        let headers = res.headers();
+        assert_eq!(
+            headers
+                .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+                .map(|v| v.to_str().unwrap()),
+            if *allowed { Some(*origin) } else { None }
+        );
+        assert_eq!(headers[header::VARY], "Origin");
+    }
+}
+
+/// Without CORS, OPTIONS requests are not allowed.
+#[test]
+fn cors_disabled_options() {
+    let TestServer { base_url, .. } = TestServer::new();
+    let res = Client::new()
+        .request(Method::OPTIONS, format!("{}/config", base_url))
+        .header(header::USER_AGENT, "Threema")
+        .header(header::ORIGIN, "https://example.org")
+        .header(header::ACCESS_CONTROL_REQUEST_METHOD, "GET")
+        .send()
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 405);
+}
+
+/// Wait until the condition is true, or panic after a timeout.
+fn wait_for(description: &str, condition: impl Fn() -> bool) {
+    for _ in 0..500 {
+        if condition() {
+            return;
+        }
+        thread::sleep(std::time::Duration::from_millis(10));
+    }
+    panic!("Timeout while waiting for {}", description);
+}
+
+/// Uploads and deletions are replicated to the peer.
+#[test]
+fn replication_to_peer() {
+    let peer = TestServer::new();
+    let queue_dir = tempfile::tempdir().unwrap();
+    let peer_url = peer.base_url.clone();
+    let queue_path = queue_dir.path().to_path_buf();
+    let primary = TestServer::with_config(ListenerConfig::default(), move |config| {
+        config.replication = Some(ReplicationConfig {
+            queue_dir: queue_path,
+            max_backoff_secs: None,
+            peers: vec![PeerConfig {
+                name: "standby".into(),
+                url: peer_url,
+                user_agent: None,
+            }],
+        });
+    });
+
+    // Upload
+    let backup_id = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+    let res = upload_backup(&primary.base_url, backup_id, b"sekurkopio".to_vec());
+    assert_eq!(res.status().as_u16(), 201);
+    let peer_file = peer.backup_dir.path().join(backup_id);
+    wait_for("upload replication", || {
+        std::fs::read(&peer_file).ok().as_deref() == Some(&b"sekurkopio"[..])
+    });
+
+    // Delete
+    let res = Client::new()
+        .delete(format!("{}/backups/{}", primary.base_url, backup_id))
+        .header(header::USER_AGENT, "Threema")
+        .send()
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 204);
+    wait_for("delete replication", || !peer_file.exists());
+
+    // Queue is empty
+    let queued = std::fs::read_dir(queue_dir.path().join("standby"))
+        .unwrap()
+        .count();
+    assert_eq!(queued, 0);
+}
+
+/// Backups missing locally are migrated from the upstream server, and
+/// deletions are forwarded to it.
+#[test]
+fn migration_read_through() {
+    let upstream = TestServer::new();
+    let upstream_url = upstream.base_url.clone();
+    let server = TestServer::with_config(
+        ListenerConfig {
+            routes: Some(vec![RouteGroup::Safe, RouteGroup::Metrics]),
+            ..Default::default()
+        },
+        move |config| {
+            config.migration = Some(MigrationConfig {
+                upstream_url,
+                enabled: None,
+                user_agent: None,
+                timeout_secs: None,
+            });
+        },
+    );
+    let backup_id = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+    let missing_id = "fedcba9876543210fedcba9876543210fedcba9876543210fedcba9876543210";
+    let res = upload_backup(&upstream.base_url, backup_id, b"migrota".to_vec());
+    assert_eq!(res.status().as_u16(), 201);
+    let download = |backup_id: &str| {
+        Client::new()
+            .get(format!("{}/backups/{}", server.base_url, backup_id))
+            .header(header::USER_AGENT, "Threema")
+            .header(header::ACCEPT, "application/octet-stream")
+            .send()
+            .unwrap()
+    };
+
+    // Miss, fetched from upstream and stored locally
+    let res = download(backup_id);
+    assert_eq!(res.status().as_u16(), 200);
+    assert_eq!(res.bytes().unwrap().as_ref(), b"migrota");
+    let local_file = server.backup_dir.path().join(backup_id);
+    assert_eq!(std::fs::read(&local_file).unwrap(), b"migrota");
+
+    // Hit
+    assert_eq!(download(backup_id).status().as_u16(), 200);
+
+    // Missing everywhere
+    assert_eq!(download(missing_id).status().as_u16(), 404);
+
+    let metrics = Client::new()
+        .get(format!("{}/metrics", server.base_url))
+        .send()
+        .unwrap()
+        .text()
+        .unwrap();
+    assert!(metrics.contains("\nsekursranko_migration_hits_total 1\n"));
+    assert!(metrics.contains("\nsekursranko_migration_misses_total 2\n"));
+    assert!(metrics.contains("\nsekursranko_migration_migrated_total 1\n"));
+
+    // Deletion is forwarded, so the backup is not migrated again
+    let res = Client::new()
+        .delete(format!("{}/backups/{}", server.base_url, backup_id))
+        .header(header::USER_AGENT, "Threema")
+        .send()
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 204);
+    assert!(!local_file.exists());
+    assert!(!upstream.backup_dir.path().join(backup_id).exists());
+    assert_eq!(download(backup_id).status().as_u16(), 404);
+}
+
+/// Create an auth config with users that have the password "secret".
+fn auth_config(dir: &TempDir, users: &[&str]) -> AuthConfig {
+    let htpasswd_file = dir.path().join("htpasswd");
+    let hash = "$2b$04$okK9S1/hF7QJO1ReoRi4puM/bvcq882iNaLpVZk5hzl9GYT/w.HgW";
+    let lines: String = users
+        .iter()
+        .map(|user| format!("{}:{}\n", user, hash))
+        .collect();
+    std::fs::write(&htpasswd_file, lines).unwrap();
+    AuthConfig {
+        htpasswd_file,
+        realm: None,
+        reload_interval_secs: None,
+        quota: QuotaConfig::default(),
+        user_quotas: HashMap::new(),
+    }
+}
+
+/// The Safe API requires credentials if Basic auth is enabled.
+#[test]
+fn basic_auth() {
+    let auth_dir = tempfile::tempdir().unwrap();
+    let auth = AuthConfig {
+        realm: Some("Private Safe".into()),
+        ..auth_config(&auth_dir, &["alice"])
+    };
+    let TestServer { base_url, .. } = TestServer::with_config(
+        ListenerConfig {
+            routes: Some(vec![
+                RouteGroup::Info,
+                RouteGroup::Safe,
+                RouteGroup::Metrics,
+            ]),
+            ..Default::default()
+        },
+        move |config| {
+            config.auth = Some(auth);
+        },
+    );
+    let get_config = |credentials: Option<(&str, &str)>| {
+        let mut builder = Client::new()
+            .get(format!("{}/config", base_url))
+            .header(header::USER_AGENT, "Threema")
+            .header(header::ACCEPT, "application/json");
+        if let Some((user, password)) = credentials {
+            builder = builder.basic_auth(user, Some(password));
+        }
+        builder.send().unwrap()
+    };
+
+    let res = get_config(None);
+    assert_eq!(res.status().as_u16(), 401);
+    assert_eq!(
+        res.headers()[header::WWW_AUTHENTICATE],
+        "Basic realm=\"Private Safe\", charset=\"UTF-8\""
+    );
+    assert_eq!(
+        res.text().unwrap(),
+        error_json("unauthorized", "Authentication required")
+    );
+    assert_eq!(get_config(Some(("alice", "wrong"))).status().as_u16(), 401);
+    assert_eq!(get_config(Some(("bob", "secret"))).status().as_u16(), 401);
+    assert_eq!(get_config(Some(("alice", "secret"))).status().as_u16(), 200);
+
+    // The index and metrics don't require credentials
+    let res = Client::new()
+        .get(&base_url)
+        .header(header::USER_AGENT, "Threema")
+        .send()
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let metrics = Client::new()
+        .get(format!("{}/metrics", base_url))
+        .send()
+        .unwrap()
+        .text()
+        .unwrap();
+    assert!(metrics.contains("\nsekursranko_auth_failed_total 3\n"));
+}
+
+/// Backups are owned by the user that created them, and uploads are
+/// limited by the user's quota.
+#[test]
+fn backup_ownership() {
+    let auth_dir = tempfile::tempdir().unwrap();
+    let mut auth = auth_config(&auth_dir, &["alice", "bob"]);
+    auth.quota.max_bytes = Some(100);
+    auth.user_quotas.insert(
+        "bob".into(),
+        QuotaConfig {
+            max_backups: Some(1),
+            max_bytes: Some(1000),
+        },
+    );
+    let server = TestServer::with_config(ListenerConfig::default(), move |config| {
+        config.auth = Some(auth);
+    });
+    let backup_id = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+    let other_id = "fedcba9876543210fedcba9876543210fedcba9876543210fedcba9876543210";
+    let request = |method: Method, user: &str, backup_id: &str, body: &[u8]| {
+        Client::new()
+            .request(method, format!("{}/backups/{}", server.base_url, backup_id))
+            .basic_auth(user, Some("secret"))
+            .header(header::USER_AGENT, "Threema")
+            .header(header::ACCEPT, "application/octet-stream")
+            .header(header::CONTENT_TYPE, "application/octet-stream")
+            .body(body.to_vec())
+            .send()
+            .unwrap()
+            .status()
+            .as_u16()
+    };
+
+    assert_eq!(request(Method::PUT, "alice", backup_id, b"alice"), 201);
+    let owner_file = server.backup_dir.path().join(".owners").join(backup_id);
+    assert_eq!(std::fs::read_to_string(&owner_file).unwrap(), "alice");
+
+    // Other users don't see the backup
+    assert_eq!(request(Method::GET, "bob", backup_id, b""), 404);
+    assert_eq!(request(Method::PUT, "bob", backup_id, b"bob"), 404);
+    assert_eq!(request(Method::DELETE, "bob", backup_id, b""), 404);
+    assert_eq!(request(Method::GET, "alice", backup_id, b""), 200);
+
+    // Quotas
+    assert_eq!(request(Method::PUT, "alice", backup_id, &[0; 100]), 204);
+    assert_eq!(request(Method::PUT, "alice", other_id, b"x"), 403);
+    assert_eq!(request(Method::PUT, "bob", other_id, &[0; 200]), 201);
+    let third_id = "00000000000000000000000000000000000000000000000000000000000000ff";
+    assert_eq!(request(Method::PUT, "bob", third_id, b"x"), 403);
+
+    assert_eq!(request(Method::DELETE, "alice", backup_id, b""), 204);
+    assert!(!owner_file.exists());
+}
+
+/// The client sends the required headers and returns typed errors.
+#[test]
+fn client() {
+    let auth_dir = tempfile::tempdir().unwrap();
+    let server = TestServer::with_config(ListenerConfig::default(), |config| {
+        config.max_backup_bytes = 16;
+        config.auth = Some(auth_config(&auth_dir, &["alice"]));
+    });
+    let backup_id = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let client = SafeClient::new(&server.base_url)
+            .unwrap()
+            .with_basic_auth("alice", "secret");
+        let config = client.config().await.unwrap();
+        assert_eq!(config.max_backup_bytes, 16);
+        assert_eq!(config.retention_days, 180);
+
+        // Upload, update, download and delete
+        assert!(!client
+            .put_backup(backup_id, b"sekur".to_vec())
+            .await
+            .unwrap());
+        assert!(client
+            .put_backup(backup_id, b"sekura".to_vec())
+            .await
+            .unwrap());
+        let info = client.head_backup(backup_id).await.unwrap();
+        assert_eq!(info.size, 6);
+        let stored_at = info.stored_at.unwrap();
+        assert_eq!(
+            info.expires_at,
+            Some(stored_at + std::time::Duration::from_secs(180 * 24 * 60 * 60))
+        );
+        assert_eq!(client.get_backup(backup_id).await.unwrap(), b"sekura");
+        client.delete_backup(backup_id).await.unwrap();
+
+        // Errors
+        let not_found = |result| matches!(result, Err(ClientError::Api(ApiError::NotFound)));
+        assert!(not_found(client.head_backup(backup_id).await.map(drop)));
+        assert!(not_found(client.get_backup(backup_id).await.map(drop)));
+        assert!(not_found(client.delete_backup(backup_id).await));
+        assert!(matches!(
+            client.put_backup(backup_id, vec![0; 17]).await,
+            Err(ClientError::Api(ApiError::BackupTooLarge))
+        ));
+        assert!(matches!(
+            client.get_backup("abcd").await,
+            Err(ClientError::Api(ApiError::InvalidBackupId))
+        ));
+        let anonymous = SafeClient::new(&server.base_url).unwrap();
+        assert!(matches!(
+            anonymous.config().await,
+            Err(ClientError::Api(ApiError::Unauthorized))
+        ));
+        let curl = anonymous.with_user_agent("curl/7.0");
+        assert!(matches!(
+            curl.config().await,
+            Err(ClientError::Api(ApiError::InvalidUserAgent))
+        ));
+    });
+}
+
+/// Tenants are selected by host and path prefix, and have their own
+/// backups and limits.
+#[test]
+fn tenants() {
+    let acme_dir = tempfile::tempdir().unwrap();
+    let globex_dir = tempfile::tempdir().unwrap();
+    let tenants = vec![
+        TenantConfig {
+            name: "acme".into(),
+            hosts: vec!["safe.acme.example".into()],
+            path_prefix: None,
+            backup_dir: acme_dir.path().to_path_buf(),
+            max_backup_bytes: Some(1000),
+            retention_days: None,
+            user_agent: None,
+            auth: None,
+        },
+        TenantConfig {
+            name: "globex".into(),
+            hosts: vec![],
+            path_prefix: Some("/globex".into()),
+            backup_dir: globex_dir.path().to_path_buf(),
+            max_backup_bytes: None,
+            retention_days: Some(30),
+            user_agent: None,
+            auth: None,
+        },
+    ];
+    let server = TestServer::with_config(ListenerConfig::default(), move |config| {
+        config.tenants = tenants;
+    });
+    let get_config = |host: &str, path: &str| {
+        Client::new()
+            .get(format!("{}{}", server.base_url, path))
+            .header(header::HOST, host)
+            .header(header::USER_AGENT, "Threema")
+            .header(header::ACCEPT, "application/json")
+            .send()
+            .unwrap()
+            .text()
+            .unwrap()
+    };
+    assert_eq!(
+        get_config("safe.acme.example:443", "/config"),
+        "{\"maxBackupBytes\":1000,\"retentionDays\":180}"
+    );
+    assert_eq!(
+        get_config("localhost", "/globex/config"),
+        "{\"maxBackupBytes\":524288,\"retentionDays\":30}"
+    );
+    assert_eq!(
+        get_config("localhost", "/config"),
+        "{\"maxBackupBytes\":524288,\"retentionDays\":180}"
+    );
+
+    // Backups are stored in the tenant's backup directory
+    let backup_id = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+    let res = Client::new()
+        .put(format!("{}/globex/backups/{}", server.base_url, backup_id))
+        .header(header::USER_AGENT, "Threema")
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .body(b"globex".to_vec())
+        .send()
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 201);
+    assert!(globex_dir.path().join(backup_id).exists());
+    assert!(!server.backup_dir.path().join(backup_id).exists());
+    let res = Client::new()
+        .get(format!("{}/backups/{}", server.base_url, backup_id))
+        .header(header::USER_AGENT, "Threema")
+        .header(header::ACCEPT, "application/octet-stream")
+        .send()
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 404);
+}
+
+/// A peer can be reconciled from scratch.
+#[test]
+fn replication_resync() {
+    let peer = TestServer::new();
+    let backup_dir = tempfile::tempdir().unwrap();
+    let queue_dir = tempfile::tempdir().unwrap();
+    let backup_id = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+    std::fs::write(backup_dir.path().join(backup_id), b"malnova").unwrap();
+    std::fs::write(backup_dir.path().join("not-a-backup"), b"").unwrap();
+    let config = ServerConfig {
+        backup_dir: backup_dir.path().to_path_buf(),
+        replication: Some(ReplicationConfig {
+            queue_dir: queue_dir.path().to_path_buf(),
+            max_backoff_secs: None,
+            peers: vec![PeerConfig {
+                name: "standby".into(),
+                url: peer.base_url.clone(),
+                user_agent: None,
+            }],
+        }),
+        ..Default::default()
+    };
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let stats = rt
+        .block_on(sekursranko::admin::resync(&config, "standby"))
+        .unwrap();
+    assert_eq!(stats.pushed, 1);
+    assert_eq!(stats.failed, 0);
+    assert_eq!(
+        std::fs::read(peer.backup_dir.path().join(backup_id)).unwrap(),
+        b"malnova"
+    );
+    assert!(rt
+        .block_on(sekursranko::admin::resync(&config, "unknown"))
+        .is_err());
+}
+
+/// Backups are encrypted at rest and decrypted on download.
+#[test]
+fn encryption_at_rest() {
+    let key_dir = tempfile::tempdir().unwrap();
+    let key_file = key_dir.path().join("keys");
+    std::fs::write(
+        &key_file,
+        "k1 000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f\n",
+    )
+    .unwrap();
+    let TestServer {
+        base_url,
+        backup_dir,
+        ..
+    } = TestServer::with_config(ListenerConfig::default(), move |config| {
+        config.encryption = Some(EncryptionConfig {
+            key_file,
+            key_id: "k1".into(),
+        });
+    });
+
+    let backup_id = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+    let res = upload_backup(&base_url, backup_id, b"sekurkopio".to_vec());
+    assert_eq!(res.status().as_u16(), 201);
+
+    // Stored file is encrypted
+    let stored = std::fs::read(backup_dir.path().join(backup_id)).unwrap();
+    assert!(stored.starts_with(b"SKRE"));
+    assert!(!stored.windows(10).any(|w| w == b"sekurkopio"));
+
+    // Download returns the plaintext
+    for method in &[Method::GET, Method::HEAD] {
+        let res = Client::new()
+            .request(
+                method.clone(),
+                format!("{}/backups/{}", base_url, backup_id),
+            )
+            .header(header::USER_AGENT, "Threema")
+            .header(header::ACCEPT, "application/octet-stream")
+            .send()
+            .unwrap();
+        assert_eq!(res.status().as_u16(), 200);
+        assert_eq!(res.headers()[header::CONTENT_LENGTH], "10");
+        if *method == Method::GET {
+            assert_eq!(res.bytes().unwrap().as_ref(), b"sekurkopio");
+        }
+    }
+}
+
+/// Prior versions are retained, but only the latest version is served.
+#[test]
+fn retained_versions() {
+    let server = TestServer::with_config(ListenerConfig::default(), |config| {
+        config.retained_versions = 3;
+    });
+    let backup_id = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+    assert_eq!(
+        upload_backup(&server.base_url, backup_id, b"good".to_vec())
+            .status()
+            .as_u16(),
+        201
+    );
+    assert_eq!(
+        upload_backup(&server.base_url, backup_id, b"broken".to_vec())
+            .status()
+            .as_u16(),
+        204
+    );
+    let res = Client::new()
+        .get(format!("{}/backups/{}", server.base_url, backup_id))
+        .header(header::USER_AGENT, "Threema")
+        .header(header::ACCEPT, "application/octet-stream")
+        .send()
+        .unwrap();
+    assert_eq!(res.bytes().unwrap().as_ref(), b"broken");
+
+    // Restore the previous version
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let versions = rt
+        .block_on(sekursranko::admin::list_versions(&server.config, backup_id))
+        .unwrap();
+    assert_eq!(versions.len(), 1);
+    assert_eq!(versions[0].size, 4);
+    rt.block_on(sekursranko::admin::promote_version(
+        &server.config,
+        backup_id,
+        &versions[0].id,
+    ))
+    .unwrap();
+    let res = Client::new()
+        .get(format!("{}/backups/{}", server.base_url, backup_id))
+        .header(header::USER_AGENT, "Threema")
+        .header(header::ACCEPT, "application/octet-stream")
+        .send()
+        .unwrap();
+    assert_eq!(res.bytes().unwrap().as_ref(), b"good");
+}
+
+/// Deleted backups are moved to the trash and can be restored.
+#[test]
+fn soft_delete() {
+    let server = TestServer::with_config(ListenerConfig::default(), |config| {
+        config.delete_grace_days = Some(7);
+    });
+    let backup_id = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+    let backup_url = format!("{}/backups/{}", server.base_url, backup_id);
+    let download = || {
+        Client::new()
+            .get(&backup_url)
+            .header(header::USER_AGENT, "Threema")
+            .header(header::ACCEPT, "application/octet-stream")
+            .send()
+            .unwrap()
+    };
+    let delete = || {
+        Client::new()
+            .delete(&backup_url)
+            .header(header::USER_AGENT, "Threema")
+            .send()
+            .unwrap()
+    };
+    upload_backup(&server.base_url, backup_id, b"sekurkopio".to_vec());
+    assert_eq!(delete().status().as_u16(), 204);
+
+    // The API treats trashed backups as absent
+    assert_eq!(download().status().as_u16(), 404);
+    assert_eq!(delete().status().as_u16(), 404);
+    let res = upload_backup(&server.base_url, backup_id, b"fresh".to_vec());
+    assert_eq!(res.status().as_u16(), 201);
+    assert_eq!(delete().status().as_u16(), 204);
+
+    // Restore the most recently deleted backup
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(sekursranko::admin::restore(&server.config, backup_id))
+        .unwrap();
+    assert_eq!(download().bytes().unwrap().as_ref(), b"fresh");
+}
+
+/// Backups can be exported and imported into a differently configured
+/// server, keeping the time they were stored.
+#[test]
+fn export_import() {
+    let source = TestServer::new();
+    let backup_id = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+    let res = upload_backup(&source.base_url, backup_id, b"eksportita".to_vec());
+    assert_eq!(res.status().as_u16(), 201);
+    let stored_at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    File::options()
+        .write(true)
+        .open(source.backup_dir.path().join(backup_id))
+        .unwrap()
+        .set_modified(stored_at)
+        .unwrap();
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let mut archive = Vec::new();
+    let stats = rt
+        .block_on(sekursranko::admin::export(&source.config, &mut archive))
+        .unwrap();
+    assert_eq!(stats.exported, 1);
+
+    let key_dir = tempfile::tempdir().unwrap();
+    let key_file = key_dir.path().join("keys");
+    std::fs::write(
+        &key_file,
+        "k1 000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f\n",
+    )
+    .unwrap();
+    let target = TestServer::with_config(ListenerConfig::default(), move |config| {
+        config.encryption = Some(EncryptionConfig {
+            key_file,
+            key_id: "k1".into(),
+        });
+    });
+    let stats = rt
+        .block_on(sekursranko::admin::import(
+            &target.config,
+            &archive[..],
+            false,
+        ))
+        .unwrap();
+    assert_eq!(stats.imported, 1);
+    assert_eq!(stats.failed, 0);
+
+    // Imported backup is encrypted and keeps its retention clock
+    let path = target.backup_dir.path().join(backup_id);
+    assert!(std::fs::read(&path).unwrap().starts_with(b"SKRE"));
+    assert_eq!(
+        std::fs::metadata(&path).unwrap().modified().unwrap(),
+        stored_at
+    );
+    let res = Client::new()
+        .get(format!("{}/backups/{}", target.base_url, backup_id))
+        .header(header::USER_AGENT, "Threema")
+        .header(header::ACCEPT, "application/octet-stream")
+        .send()
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    assert_eq!(res.bytes().unwrap().as_ref(), b"eksportita");
+
+    // Existing backups are skipped
+    let stats = rt
+        .block_on(sekursranko::admin::import(
+            &target.config,
+            &archive[..],
+            false,
+        ))
+        .unwrap();
+    assert_eq!(stats.imported, 0);
+    assert_eq!(stats.skipped, 1);
+}
+
+/// Start an HTTP server that forwards the signature header and body of every
+/// request to the returned channel.
+fn webhook_receiver() -> (String, std::sync::mpsc::Receiver<(String, String)>) {
+    use hyper::service::{make_service_fn, service_fn};
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let (port_tx, port_rx) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let make_service = make_service_fn(move |_| {
+                let tx = tx.clone();
+                async move {
+                    Ok::<_, hyper::Error>(service_fn(move |req: hyper::Request<hyper::Body>| {
+                        let tx = tx.clone();
+                        async move {
+                            let signature = req.headers()["x-sekursranko-signature"]
+                                .to_str()
+                                .unwrap()
+                                .to_string();
+                            let body = hyper::body::to_bytes(req.into_body()).await?;
+                            tx.send((signature, String::from_utf8(body.to_vec()).unwrap()))
+                                .unwrap();
+                            Ok::<_, hyper::Error>(hyper::Response::new(hyper::Body::empty()))
+                        }
+                    }))
+                }
+            });
+            let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+            port_tx.send(server.local_addr().port()).unwrap();
+            server.await.unwrap();
+        });
+    });
+    let port = port_rx.recv().unwrap();
+    (format!("http://127.0.0.1:{}/hook", port), rx)
+}
+
+/// Lifecycle events are posted to webhooks with a signature.
+#[test]
+fn webhooks() {
+    let (url, received) = webhook_receiver();
+    let key_dir = tempfile::tempdir().unwrap();
+    let hash_key_file = key_dir.path().join("hash-key");
+    let secret_file = key_dir.path().join("secret");
+    std::fs::write(&hash_key_file, "webhook hash key 1234\n").unwrap();
+    std::fs::write(&secret_file, "webhook signing secret\n").unwrap();
+    let server = TestServer::with_config(ListenerConfig::default(), move |config| {
+        config.webhooks = Some(WebhookConfig {
+            hash_key_file,
+            queue_size: None,
+            max_attempts: None,
+            timeout_secs: None,
+            endpoints: vec![WebhookEndpoint {
+                name: "ops".into(),
+                url,
+                secret_file,
+                events: Some(vec!["created".into(), "deleted".into()]),
+            }],
+        });
+    });
+    let backup_id = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+    let timeout = std::time::Duration::from_secs(10);
+
+    // Upload (created, then updated which is not subscribed)
+    assert_eq!(
+        upload_backup(&server.base_url, backup_id, b"v1".to_vec())
+            .status()
+            .as_u16(),
+        201
+    );
+    assert_eq!(
+        upload_backup(&server.base_url, backup_id, b"v22".to_vec())
+            .status()
+            .as_u16(),
+        204
+    );
+    let (signature, body) = received.recv_timeout(timeout).unwrap();
+    let payload: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(payload["event"], "created");
+    assert_eq!(payload["size"], 2);
+    assert_eq!(payload["source"], "api");
+    assert!(!body.contains(backup_id));
+    let mut mac = Hmac::<Sha256>::new_from_slice(b"webhook signing secret").unwrap();
+    mac.update(body.as_bytes());
+    let expected = hex::encode(mac.finalize().into_bytes());
+    assert_eq!(signature, format!("sha256={}", expected));
+
+    // Delete
+    let res = Client::new()
+        .delete(format!("{}/backups/{}", server.base_url, backup_id))
+        .header(header::USER_AGENT, "Threema")
+        .send()
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 204);
+    let (_, body) = received.recv_timeout(timeout).unwrap();
+    let payload: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(payload["event"], "deleted");
+    assert!(received.try_recv().is_err());
+}
+
+/// Events of admin commands are delivered before the command completes,
+/// although the CLI doesn't run delivery workers.
+#[test]
+fn webhooks_admin_command() {
+    let (url, received) = webhook_receiver();
+    let dir = tempfile::tempdir().unwrap();
+    let hash_key_file = dir.path().join("hash-key");
+    let secret_file = dir.path().join("secret");
+    std::fs::write(&hash_key_file, "webhook hash key 1234\n").unwrap();
+    std::fs::write(&secret_file, "webhook signing secret\n").unwrap();
+    let backup_dir = dir.path().join("backups");
+    std::fs::create_dir(&backup_dir).unwrap();
+    let config = ServerConfig {
+        backup_dir: backup_dir.clone(),
+        retention_days: 180,
+        webhooks: Some(WebhookConfig {
+            hash_key_file,
+            queue_size: None,
+            max_attempts: None,
+            timeout_secs: None,
+            endpoints: vec![WebhookEndpoint {
+                name: "ops".into(),
+                url,
+                secret_file,
+                events: None,
+            }],
+        }),
+        ..Default::default()
+    };
+    let backup_id = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+    let file = File::create(backup_dir.join(backup_id)).unwrap();
+    file.set_modified(UNIX_EPOCH + Duration::from_secs(1_600_000_000))
+        .unwrap();
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let stats = rt.block_on(sekursranko::admin::sweep(&config)).unwrap();
+    assert_eq!(stats.expired, 1);
+    let (_, body) = received.try_recv().unwrap();
+    let payload: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(payload["event"], "expired");
+    assert_eq!(payload["source"], "sweep");
+}
+
+/// Lifecycle events and rejected changes are written to the audit log.
+#[test]
+fn audit_log() {
+    let log_dir = tempfile::tempdir().unwrap();
+    let log_path = log_dir.path().join("audit.log");
+    let config_log_path = log_path.clone();
+    let server = TestServer::with_config(ListenerConfig::default(), move |config| {
+        config.max_backup_bytes = 10;
+        config.audit_log = Some(AuditLogConfig {
+            path: config_log_path,
+            hash_key_file: None,
+            max_bytes: None,
+            max_files: None,
+        });
+    });
+    let backup_id = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+    upload_backup(&server.base_url, backup_id, b"sekurkopio".to_vec());
+    upload_backup(&server.base_url, backup_id, b"too large backup".to_vec());
+    Client::new()
+        .delete(format!("{}/backups/{}", server.base_url, backup_id))
+        .header(header::USER_AGENT, "Threema")
+        .send()
+        .unwrap();
+
+    let contents = std::fs::read_to_string(&log_path).unwrap();
+    assert!(!contents.contains("sekurkopio"));
+    let lines: Vec<serde_json::Value> = contents
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let events: Vec<_> = lines.iter().map(|l| l["event"].as_str().unwrap()).collect();
+    assert_eq!(events, vec!["created", "rejected", "deleted"]);
+    assert_eq!(lines[0]["size"], 10);
+    assert_eq!(lines[0]["source"], "api");
+    assert_eq!(lines[1]["reason"], "backup_too_large");
+    assert_eq!(lines[1]["size"], 16);
+    assert_eq!(lines[2]["backup_id"], backup_id);
+}
+
+/// The server can be embedded in a tokio application and shut down
+/// gracefully.
+#[tokio::test]
+async fn embedded_server() {
+    let backup_dir = tempfile::tempdir().unwrap();
+    let config = ServerConfig {
+        max_backup_bytes: 524_288,
+        retention_days: 180,
+        backup_dir: backup_dir.path().to_path_buf(),
+        ..Default::default()
+    };
+    let storage = Arc::new(Storage::from_config(&config).unwrap());
+    let tcp_listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let server = ServerBuilder::new(config)
+        .tcp_listener(tcp_listener)
+        .storage(storage.clone())
+        .start()
+        .unwrap();
+    let url = format!(
+        "http://{}/backups/0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef",
+        server.local_addr().unwrap()
+    );
+
+    let client = reqwest::Client::new();
+    let res = client
+        .put(&url)
+        .header(header::USER_AGENT, "Threema")
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .body("enigita")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 201);
+    assert_eq!(
+        storage
+            .read("0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef")
+            .await
+            .unwrap(),
+        b"enigita"
+    );
+
+    server.shutdown_trigger().shutdown();
+    tokio::time::timeout(Duration::from_secs(5), server)
+        .await
+        .expect("Server did not shut down")
+        .unwrap();
+    assert!(client
+        .get(&url)
+        .header(header::USER_AGENT, "Threema")
+        .send()
+        .await
+        .is_err());
+}
+
+/// A graceful shutdown completes the running retention sweep and delivers
+/// its webhook events.
+#[tokio::test]
+async fn embedded_server_shutdown_delivers_events() {
+    let (url, received) = webhook_receiver();
+    let dir = tempfile::tempdir().unwrap();
+    let hash_key_file = dir.path().join("hash-key");
+    let secret_file = dir.path().join("secret");
+    std::fs::write(&hash_key_file, "webhook hash key 1234\n").unwrap();
+    std::fs::write(&secret_file, "webhook signing secret\n").unwrap();
+    let backup_dir = dir.path().join("backups");
+    std::fs::create_dir(&backup_dir).unwrap();
+    let backup_id = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+    let file = File::create(backup_dir.join(backup_id)).unwrap();
+    file.set_modified(UNIX_EPOCH + Duration::from_secs(1_600_000_000))
+        .unwrap();
+    let config = ServerConfig {
+        backup_dir,
+        retention_days: 180,
+        sweep_interval_secs: Some(3600),
+        webhooks: Some(WebhookConfig {
+            hash_key_file,
+            queue_size: None,
+            max_attempts: None,
+            timeout_secs: None,
+            endpoints: vec![WebhookEndpoint {
+                name: "ops".into(),
+                url,
+                secret_file,
+                events: None,
+            }],
+        }),
+        ..Default::default()
+    };
+    let server = ServerBuilder::new(config)
+        .tcp_listener(std::net::TcpListener::bind("127.0.0.1:0").unwrap())
+        .start()
+        .unwrap();
+
+    server.shutdown();
+    tokio::time::timeout(Duration::from_secs(10), server)
+        .await
+        .expect("Server did not shut down")
+        .unwrap();
+    let (_, body) = received.try_recv().unwrap();
+    let payload: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(payload["event"], "expired");
+    assert_eq!(payload["source"], "sweep");
+}
+
+/// Shutting down an embedded server also closes its TLS listeners.
+#[cfg(feature = "tls")]
+#[tokio::test]
+async fn embedded_tls_server_shutdown() {
+    let backup_dir = tempfile::tempdir().unwrap();
+    let fixtures = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let config = ServerConfig {
+        max_backup_bytes: 524_288,
+        retention_days: 180,
+        backup_dir: backup_dir.path().to_path_buf(),
+        listeners: vec![ListenerConfig {
+            listen_on: "127.0.0.1:0".into(),
+            tls: Some(sekursranko::TlsConfig {
+                cert_file: fixtures.join("cert.pem"),
+                key_file: fixtures.join("key.pem"),
+                client_ca_file: None,
+                client_crl_file: None,
+                client_identity: None,
+                quota: Default::default(),
+                user_quotas: Default::default(),
+            }),
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    let server = ServerBuilder::new(config).start().unwrap();
+    let addr = server.local_addr().unwrap();
+
+    // A client that never completes the handshake doesn't delay the shutdown
+    let _stalled = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let client = reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap();
+    let res = client
+        .get(format!("https://{}/", addr))
+        .header(header::USER_AGENT, "Threema")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+
+    server.shutdown_trigger().shutdown();
+    tokio::time::timeout(Duration::from_secs(5), server)
+        .await
+        .expect("Server did not shut down")
+        .unwrap();
+    std::net::TcpListener::bind(addr).expect("TLS listener was not closed");
+}